@@ -0,0 +1,262 @@
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use crate::auth::AuthError;
+use crate::dynamo::{CancellationReason, DynamoError};
+use crate::dynamo_query_helpers::{InvalidPageToken, QueryError};
+use crate::user::UserStoreError;
+use crate::validation::{FieldError, ValidationErrors};
+
+/// Error returned by every handler.
+///
+/// Rendered as an RFC 7807 `application/problem+json` body with a stable `code`
+/// clients can match on, e.g.
+/// `{"type":"about:blank","title":"Not Found","status":404,"detail":"No item found","code":"not_found"}`
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{detail}")]
+    BadRequest { code: &'static str, detail: String },
+    #[error("{detail}")]
+    Forbidden { code: &'static str, detail: String },
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    /// A payload that failed `validation::Validate`, with an error per field
+    #[error("{0}")]
+    Invalid(#[from] ValidationErrors),
+    #[error("{detail}")]
+    Conflict { code: &'static str, detail: String },
+    #[error("{detail}")]
+    TooManyRequests { code: &'static str, detail: String },
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+    #[error("{0}")]
+    Dynamo(#[from] DynamoError),
+    /// Some writes of a batch failed, the others stay done
+    #[error("{source}")]
+    PartialBatch { failed: Vec<serde_json::Value>, source: DynamoError },
+    /// Anything else. Logged, but not shown to the client.
+    #[error("{0}")]
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::BadRequest { code, detail: detail.into() }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        ApiError::NotFound(detail.into())
+    }
+
+    /// A batch of which the writes with keys `failed` were not applied
+    pub fn partial_batch<K: Serialize>(failed: &[K], error: DynamoError) -> Self {
+        let failed = failed.iter().filter_map(|key| serde_json::to_value(key).ok()).collect();
+        ApiError::PartialBatch { failed, source: error }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.problem().0
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.problem().1
+    }
+
+    fn problem(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::BadRequest { code, .. } => (StatusCode::BAD_REQUEST, code),
+            ApiError::Forbidden { code, .. } => (StatusCode::FORBIDDEN, code),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::UnsupportedMediaType(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type"),
+            ApiError::Invalid(_) => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            ApiError::Conflict { code, .. } => (StatusCode::CONFLICT, code),
+            ApiError::TooManyRequests { code, .. } => (StatusCode::TOO_MANY_REQUESTS, code),
+            ApiError::Auth(e) => (e.status_code(), e.code()),
+            ApiError::Dynamo(e) | ApiError::PartialBatch { source: e, .. } => dynamo_problem(e),
+            ApiError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        }
+    }
+
+    /// What the client is told. Server side failures only get a generic message.
+    fn detail(&self) -> String {
+        match self {
+            ApiError::Internal(_) => "An internal error occurred".to_string(),
+            ApiError::Dynamo(_) | ApiError::PartialBatch { .. } if self.status_code().is_server_error() => {
+                "The database is unavailable, try again later".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Status and code of a DynamoDB error
+fn dynamo_problem(error: &DynamoError) -> (StatusCode, &'static str) {
+    match error {
+        DynamoError::ConditionalCheckFailed { .. } => (StatusCode::CONFLICT, "conditional_check_failed"),
+        DynamoError::TransactionCancelled { .. } => (StatusCode::CONFLICT, "transaction_cancelled"),
+        DynamoError::TransactionConflict { .. } => (StatusCode::CONFLICT, "transaction_conflict"),
+        DynamoError::TransactionInProgress { .. } => (StatusCode::CONFLICT, "transaction_in_progress"),
+        DynamoError::IdempotencyMismatch { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "idempotency_key_mismatch"),
+        DynamoError::ResourceInUse { .. } => (StatusCode::CONFLICT, "resource_in_use"),
+        DynamoError::AlreadyExists(_) => (StatusCode::CONFLICT, "already_exists"),
+        DynamoError::VersionMismatch(_) => (StatusCode::PRECONDITION_FAILED, "precondition_failed"),
+        DynamoError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+        DynamoError::ResourceNotFound { .. } => (StatusCode::NOT_FOUND, "resource_not_found"),
+        DynamoError::Throttled { .. } | DynamoError::Unprocessed(_) => (StatusCode::TOO_MANY_REQUESTS, "throttled"),
+        DynamoError::Validation { .. } => (StatusCode::BAD_REQUEST, "validation_error"),
+        DynamoError::Unavailable { .. }
+        | DynamoError::Dispatch { .. }
+        | DynamoError::TableNotReady(_) => (StatusCode::SERVICE_UNAVAILABLE, "database_unavailable"),
+        DynamoError::Timeout { .. } => (StatusCode::GATEWAY_TIMEOUT, "database_timeout"),
+        // Our credentials or IAM policy, not something the caller can fix
+        DynamoError::AccessDenied { .. }
+        | DynamoError::Serialization(_)
+        | DynamoError::Versioned(_)
+        | DynamoError::Build(_)
+        | DynamoError::Service { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+    }
+}
+
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    /// Why each item of a cancelled transaction failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasons: Option<Vec<CancellationReason>>,
+    /// The invalid fields of a `validation_failed`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FieldError>>,
+    /// The keys of the writes of a partly written batch that were not applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed: Option<Vec<serde_json::Value>>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code) = self.problem();
+        if status.is_server_error() {
+            let request_id = match &self {
+                ApiError::Dynamo(e) | ApiError::PartialBatch { source: e, .. } => e.request_id(),
+                _ => None,
+            };
+            tracing::error!(code, request_id, "{:?}", self);
+        }
+
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.detail(),
+            code,
+            reasons: match &self {
+                ApiError::Dynamo(DynamoError::TransactionCancelled { reasons, .. }) => Some(reasons.clone()),
+                _ => None,
+            },
+            errors: match &self {
+                ApiError::Invalid(errors) => Some(errors.errors().to_vec()),
+                _ => None,
+            },
+            failed: match &self {
+                ApiError::PartialBatch { failed, .. } => Some(failed.clone()),
+                _ => None,
+            },
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        if let ApiError::Auth(e) = &self {
+            if let Some(value) = e.www_authenticate().and_then(|c| HeaderValue::from_str(&c).ok()) {
+                headers.insert(header::WWW_AUTHENTICATE, value);
+            }
+        }
+        response
+    }
+}
+
+impl From<aws_sdk_dynamodb::Error> for ApiError {
+    fn from(error: aws_sdk_dynamodb::Error) -> Self {
+        ApiError::Dynamo(error.into())
+    }
+}
+
+/// Keeps the DynamoDB errors that helpers returned through `anyhow`
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<DynamoError>() {
+            Ok(e) => return e.into(),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<aws_sdk_dynamodb::Error>() {
+            Ok(e) => return e.into(),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<modyne::Error>() {
+            Ok(e) => return e.into(),
+            Err(error) => error,
+        };
+        match error.downcast::<InvalidPageToken>() {
+            Ok(e) => e.into(),
+            Err(error) => ApiError::Internal(error),
+        }
+    }
+}
+
+impl From<InvalidPageToken> for ApiError {
+    fn from(error: InvalidPageToken) -> Self {
+        ApiError::bad_request("invalid_page_token", error.to_string())
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(error: QueryError) -> Self {
+        match error {
+            QueryError::Dynamo(e) => e.into(),
+            QueryError::PageToken(e) => e.into(),
+            QueryError::MissingPartition => ApiError::Internal(error.into()),
+        }
+    }
+}
+
+impl From<modyne::Error> for ApiError {
+    fn from(error: modyne::Error) -> Self {
+        if error.is_conditional_check_failed_exception() {
+            ApiError::Conflict {
+                code: "conditional_check_failed",
+                detail: "The conditional request failed".to_string(),
+            }
+        } else if error.is_provisioned_throughput_exceeded_exception() || error.is_request_limit_exceeded() {
+            ApiError::TooManyRequests {
+                code: "throttled",
+                detail: "Too many requests, try again later".to_string(),
+            }
+        } else {
+            ApiError::Internal(error.into())
+        }
+    }
+}
+
+impl From<UserStoreError> for ApiError {
+    fn from(error: UserStoreError) -> Self {
+        match error {
+            UserStoreError::EmailTaken => ApiError::Conflict {
+                code: "email_taken",
+                detail: error.to_string(),
+            },
+            UserStoreError::Dynamo(e) => e.into(),
+            other => ApiError::Internal(other.into()),
+        }
+    }
+}
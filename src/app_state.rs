@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::Client;
+use crate::config::Config;
+use crate::dynamo_query_helpers::{PageTokens, Transaction};
+use crate::jwk::JwkAuth;
+use crate::modyne::App;
+use crate::repository::{DynamoEntity, Repository};
+use crate::signing_keys::{SigningKeyError, SigningKeys};
+
+/// State shared by every handler through `State<AppState>`.
+///
+/// Built once in `main` so the DynamoDB client (and its connection pool)
+/// is reused across Lambda invocations instead of being rebuilt per request.
+#[derive(Clone)]
+pub struct AppState {
+    pub client: Client,
+    pub config: Arc<Config>,
+    /// Keys for the self-issued JWTs, loaded once at startup
+    pub signing_keys: Arc<SigningKeys>,
+    /// Seals and opens the `app-token` of paginated queries
+    pub page_tokens: Arc<PageTokens>,
+    jwk_auth: JwkAuth,
+}
+
+impl AppState {
+    pub fn new(client: Client, config: Config) -> Result<Self, SigningKeyError> {
+        let signing_keys = SigningKeys::from_config(&config.jwt)?;
+        Ok(Self {
+            client,
+            page_tokens: Arc::new(PageTokens::new(&config.pagination.secret, config.pagination.token_minutes)),
+            jwk_auth: JwkAuth::new(config.jwk_issuers()),
+            config: Arc::new(config),
+            signing_keys: Arc::new(signing_keys),
+        })
+    }
+
+    /// Loads the AWS configuration from the environment and builds the state
+    pub async fn from_config(config: Config) -> Result<Self, SigningKeyError> {
+        let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let state = Self::new(Client::new(&aws_config), config)?;
+        state.jwk_auth.start_key_update();
+        Ok(state)
+    }
+
+    /// Replaces the key cache of the Firebase / OIDC issuers, for tests
+    #[cfg(test)]
+    pub fn with_jwk_auth(mut self, jwk_auth: JwkAuth) -> Self {
+        self.jwk_auth = jwk_auth;
+        self
+    }
+
+    /// The process-wide key cache of the Firebase / OIDC issuers
+    pub fn jwk_auth(&self) -> &JwkAuth {
+        &self.jwk_auth
+    }
+
+    /// Modyne table handle for the session store
+    pub fn session_app(&self) -> App {
+        App::new_with_table(self.client.clone(), &self.config.tables.sessions)
+    }
+
+    /// Typed access to the table of `T`
+    pub fn repository<T: DynamoEntity>(&self) -> Repository<T> {
+        Repository::new(self.client.clone(), &self.config.tables)
+    }
+
+    /// A transaction across the configured tables
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(&self.config.tables)
+    }
+}
@@ -4,35 +4,54 @@
 use axum::{
     body::Body,
     response::IntoResponse,
-    extract::{Request, Json},
+    extract::{Request, Json, State},
     http,
     http::{Response, StatusCode},
     middleware::Next,
 };
 use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use jsonwebtoken::{decode, decode_header, encode, TokenData, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use crate::{jwk};
+use crate::api_error::ApiError;
+use crate::app_state::AppState;
+use crate::authorization::AuthClaims;
+use crate::config::JwtConfig;
+use crate::jwk::KeyResponse;
+use crate::signing_keys::SigningKeys;
+use crate::refresh_token::{issue_refresh_token, revoke_refresh_token, rotate_refresh_token, IssuedRefreshToken, RefreshTokenError};
+use crate::user::{find_user_by_email, UserRecord, UserStatus};
 // use crate::dynamo::Paginator;
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum AuthError {
     #[error("Empty header is not allowed")]
     EmptyHeaderError ,
     #[error("Please add the JWT token to the header")]
     MissingAuthHeaderError,
+    #[error("Authorization header must be \"Bearer <token>\"")]
+    MalformedAuthSchemeError,
     #[error("Error decoding JWT")]
     TokenDecodeError,
     #[error("Unauthorized user")]
     UnauthorizedUserError,
     #[error("Couldn't find user by email")]
     NoUserError,
+    #[error("Could not look up user")]
+    UserLookupError,
     #[error("Could not obtain token key")]
     NoTokenKeyError,
+    #[error("Token was issued by an unknown issuer")]
+    UnknownIssuerError,
+    #[error("Token signing keys are unavailable, try again later")]
+    KeysUnavailableError,
     #[error("{0}")]
     JWTVerificationError (#[from] VerificationError),
+    #[error("{0}")]
+    RefreshTokenError (#[from] RefreshTokenError),
 
     // Creating JWT
     #[error("BcryptError")]
@@ -49,17 +68,130 @@ pub enum VerificationError {
     InvalidSignature,
     #[error("Unknown key algorithm")]
     UnknownKeyAlgorithm,
+    #[error("Token algorithm does not match its key")]
+    AlgorithmMismatch,
+    #[error("Token signing key is invalid")]
+    InvalidKey,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token is not valid yet")]
+    NotYetValid,
+    #[error("Token was issued for another audience")]
+    WrongAudience,
+    #[error("Token was issued by an unexpected issuer")]
+    WrongIssuer,
+    #[error("Token is missing the {0} claim")]
+    MissingClaim(String),
+    #[error("Token is malformed")]
+    Malformed,
+}
+
+impl From<jsonwebtoken::errors::Error> for VerificationError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+
+        match error.into_kind() {
+            ErrorKind::InvalidSignature => VerificationError::InvalidSignature,
+            ErrorKind::ExpiredSignature => VerificationError::Expired,
+            ErrorKind::ImmatureSignature => VerificationError::NotYetValid,
+            ErrorKind::InvalidAudience => VerificationError::WrongAudience,
+            ErrorKind::InvalidIssuer => VerificationError::WrongIssuer,
+            ErrorKind::MissingRequiredClaim(claim) => VerificationError::MissingClaim(claim),
+            ErrorKind::InvalidAlgorithm | ErrorKind::MissingAlgorithm => VerificationError::AlgorithmMismatch,
+            ErrorKind::InvalidAlgorithmName => VerificationError::UnknownKeyAlgorithm,
+            ErrorKind::InvalidEcdsaKey | ErrorKind::InvalidRsaKey(_) | ErrorKind::InvalidKeyFormat => {
+                VerificationError::InvalidKey
+            }
+            _ => VerificationError::Malformed,
+        }
+    }
+}
+
+impl AuthError {
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::EmptyHeaderError | AuthError::MalformedAuthSchemeError => StatusCode::BAD_REQUEST,
+            AuthError::KeysUnavailableError => StatusCode::SERVICE_UNAVAILABLE,
+            // The user store failing isn't the caller's fault
+            AuthError::UserLookupError
+            | AuthError::BCryptError
+            | AuthError::GenerateJWTError
+            | AuthError::RefreshTokenError(RefreshTokenError::Store(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Bearer challenge as in RFC 6750 section 3, e.g.
+    /// `Bearer error="invalid_token", error_description="Token has expired"`
+    pub(crate) fn www_authenticate(&self) -> Option<String> {
+        let error = match self {
+            AuthError::EmptyHeaderError | AuthError::MalformedAuthSchemeError => "invalid_request",
+            AuthError::TokenDecodeError
+            | AuthError::NoTokenKeyError
+            | AuthError::UnknownIssuerError
+            | AuthError::JWTVerificationError(_) => "invalid_token",
+            // No token was presented, so the challenge has no error code
+            AuthError::MissingAuthHeaderError => return Some("Bearer".to_string()),
+            _ if self.status_code() == StatusCode::UNAUTHORIZED => return Some("Bearer".to_string()),
+            _ => return None,
+        };
+        let description = self.to_string().replace(['"', '\\'], "'");
+        Some(format!("Bearer error=\"{error}\", error_description=\"{description}\""))
+    }
+
+    /// Stable code of the problem+json body, see `ApiError`
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            AuthError::EmptyHeaderError | AuthError::MalformedAuthSchemeError => "invalid_request",
+            AuthError::MissingAuthHeaderError => "missing_token",
+            AuthError::TokenDecodeError | AuthError::NoTokenKeyError => "invalid_token",
+            AuthError::UnauthorizedUserError => "unauthorized",
+            AuthError::NoUserError => "unknown_user",
+            AuthError::PasswordError => "invalid_credentials",
+            AuthError::UnknownIssuerError => "unknown_issuer",
+            AuthError::KeysUnavailableError => "keys_unavailable",
+            AuthError::JWTVerificationError(e) => match e {
+                VerificationError::Expired => "token_expired",
+                VerificationError::NotYetValid => "token_not_yet_valid",
+                VerificationError::WrongAudience => "wrong_audience",
+                VerificationError::WrongIssuer => "wrong_issuer",
+                _ => "invalid_token",
+            },
+            AuthError::RefreshTokenError(e) => match e {
+                RefreshTokenError::Invalid => "invalid_refresh_token",
+                RefreshTokenError::Expired => "refresh_token_expired",
+                RefreshTokenError::Revoked => "refresh_token_revoked",
+                RefreshTokenError::Reused => "refresh_token_reused",
+                RefreshTokenError::Store(_) => "internal_error",
+            },
+            AuthError::UserLookupError | AuthError::BCryptError | AuthError::GenerateJWTError => {
+                "internal_error"
+            }
+        }
+    }
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> axum::http::Response<Body> {
+        ApiError::Auth(self).into_response()
+    }
+}
+
+/// Takes the token out of an `Authorization: Bearer <token>` header
+fn bearer_token(req: &Request) -> Result<&str, AuthError> {
+    let auth_header = match req.headers().get(http::header::AUTHORIZATION) {
+        Some(header) => header.to_str()
+            .map_err(|_| AuthError::EmptyHeaderError)?,
+        None => return Err(AuthError::MissingAuthHeaderError),
+    };
 
-        let result = self.to_string();
-        let body = Json(json!({
-            "error": result,
-        }));
+    let mut header = auth_header.split_whitespace();
 
-        (StatusCode::UNAUTHORIZED, body).into_response()
+    // Splitting 'Bearer' from token
+    match (header.next(), header.next(), header.next()) {
+        (Some(scheme), Some(token), None) if scheme.eq_ignore_ascii_case("Bearer") => Ok(token),
+        (None, _, _) => Err(AuthError::EmptyHeaderError),
+        _ => Err(AuthError::MalformedAuthSchemeError),
     }
 }
 
@@ -68,9 +200,16 @@ impl IntoResponse for AuthError {
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
+    pub sub: String,
+    pub iss: String,
+    pub aud: String,
     pub exp: usize,
     pub iat: usize,
     pub email: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
 }
 
 
@@ -83,35 +222,51 @@ pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
     Ok(hash)
 }
 
-pub fn encode_jwt(email: String) -> Result<String, StatusCode> {
-    let jwt_token: String = "randomstring".to_string();
-
+pub fn encode_jwt(user: &CurrentUser, keys: &SigningKeys, config: &JwtConfig) -> Result<String, StatusCode> {
     let now = Utc::now();
-    let expire: chrono::TimeDelta = Duration::hours(24);
+    let expire: chrono::TimeDelta = Duration::minutes(config.access_token_minutes);
     let exp: usize = (now + expire).timestamp() as usize;
     let iat: usize = now.timestamp() as usize;
 
-    let claim = Claims { iat, exp, email };
-    let secret = jwt_token.clone();
+    let claim = Claims {
+        sub: user.email.clone(),
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
+        iat,
+        exp,
+        email: user.email.clone(),
+        roles: user.roles.clone(),
+        permissions: user.permissions.clone(),
+    };
 
-    encode(
-        &Header::default(),
-        &claim,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
+    encode(&keys.header(), &claim, keys.encoding_key())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-pub fn decode_jwt(jwt: String) -> Result<TokenData<Claims>, StatusCode> {
-    let secret = "randomstring".to_string();
+/// Verifies a self-issued token with the key named by the `kid` in its header
+pub fn decode_jwt(jwt: &str, keys: &SigningKeys, config: &JwtConfig) -> Result<TokenData<Claims>, AuthError> {
+    let header = decode_header(jwt).map_err(|_| AuthError::TokenDecodeError)?;
+    let (algorithm, key) = keys
+        .decoding_key(header.kid.as_deref())
+        .ok_or(AuthError::NoTokenKeyError)?;
+
+    // Only accept the algorithm the key was configured with
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+    validation.validate_nbf = true;
+
+    let token_data = decode(jwt, key, &validation).map_err(VerificationError::from)?;
+    Ok(token_data)
+}
 
-    let result: Result<TokenData<Claims>, StatusCode> = decode(
-        &jwt,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
+/// Publishes the public keys of the self-issued tokens, e.g.
+/// curl http://localhost:9003/.well-known/jwks.json
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(http::header::CACHE_CONTROL, "public, max-age=3600")],
+        Json(KeyResponse { keys: state.signing_keys.jwks().to_vec() }),
     )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
-    result
 }
 
 #[derive(Clone)]
@@ -119,7 +274,22 @@ pub struct CurrentUser {
     pub email: String,
     pub first_name: String,
     pub last_name: String,
-    pub password_hash: String
+    pub password_hash: String,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl From<UserRecord> for CurrentUser {
+    fn from(user: UserRecord) -> Self {
+        CurrentUser {
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            password_hash: user.password_hash,
+            roles: user.roles,
+            permissions: user.permissions,
+        }
+    }
 }
 
 /// The authorize middleware is getting the current user from
@@ -128,40 +298,31 @@ pub struct CurrentUser {
 ///
 ///  check out docs:  https://docs.rs/axum/latest/axum/middleware/index.html#passing-state-from-middleware-to-handlers
 ///
-pub async fn authorize(mut req: Request, next: Next) -> Result<Response<Body>, AuthError> {
-    let auth_header = req.headers_mut().get(http::header::AUTHORIZATION);
-
-    let auth_header = match auth_header {
-        Some(header) => header.to_str()
-            .map_err(|_| AuthError::EmptyHeaderError)?,
-        None => Err(AuthError::MissingAuthHeaderError)?,
-    };
-
-    let mut header = auth_header.split_whitespace();
-
-    // Splitting 'Bearer' from token
-    let (bearer, token) = (header.next(), header.next());
-
+pub async fn authorize(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response<Body>, AuthError> {
+    let token = bearer_token(&req)?;
 
     // this uses the homegrown JWT decoder
-    let token_data = match decode_jwt(token.unwrap().to_string()) {
-        Ok(data) => data,
-        Err(_) => return Err(AuthError::TokenDecodeError),
-    };
+    let token_data = decode_jwt(token, &state.signing_keys, &state.config.jwt)?;
 
     // Fetch the user details from the database
-    let current_user = match retrieve_user_by_email(&token_data.claims.email) {
+    let current_user = match retrieve_user_by_email(&state, &token_data.claims.email).await? {
         Some(user) => user,
         None => return Err(AuthError::NoUserError),
     };
 
+    // Roles and permissions come from the token, so changes apply once it is refreshed
+    req.extensions_mut().insert(AuthClaims::from(&token_data.claims));
     req.extensions_mut().insert(current_user);
     Ok(next.run(req).await)
 }
 
 
 
-/// No Longer Used
+// No Longer Used
 // pub async fn get_paginator_token(mut req: Request, next: Next) -> Result<Response<Body>, AuthError> {
 //     let token = match req.headers()
 //         .get("app_token") {
@@ -183,24 +344,20 @@ pub async fn authorize(mut req: Request, next: Next) -> Result<Response<Body>, A
 ///
 ///  check out docs:  https://docs.rs/axum/latest/axum/middleware/index.html#passing-state-from-middleware-to-handlers
 ///
-pub async fn authorize_firebase(mut req: Request, next: Next) -> Result<Response<Body>, AuthError> {
-    let auth_header = req.headers_mut().get(http::header::AUTHORIZATION);
-
-    let auth_header = match auth_header {
-        Some(header) => header.to_str()
-            .map_err(|_|
-                AuthError::EmptyHeaderError
-            )?,
-        None => Err(AuthError::MissingAuthHeaderError)?,
-    };
-
-    let mut header = auth_header.split_whitespace();
-
-    // Splitting 'Bearer' from token
-    let (bearer, token) = (header.next(), header.next());
-
-    let firebase_token_data = jwk::JwkAuth::new()
-        .verify_firebase_jwt(&token.unwrap().to_string())?;
+///  Despite the name, tokens of any issuer in `Config::jwk_issuers` are accepted,
+///  e.g. Cognito or Auth0 next to Firebase.
+///
+pub async fn authorize_firebase(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response<Body>, AuthError> {
+    let token = bearer_token(&req)?;
+
+    let firebase_token_data = state
+        .jwk_auth()
+        .verify_jwt(token)
+        .await?;
 
     // let current_user: CurrentUser = CurrentUser {
     //     email: firebase_token_data.claims.sub,
@@ -220,6 +377,7 @@ pub async fn authorize_firebase(mut req: Request, next: Next) -> Result<Response
     //     }),
     // };
 
+    req.extensions_mut().insert(AuthClaims::from(&firebase_token_data.claims));
     req.extensions_mut().insert(firebase_token_data);
     // req.extensions_mut().insert(current_user);
     Ok(next.run(req).await)
@@ -231,12 +389,34 @@ pub struct SignInData {
     pub password: String,
 }
 
+/// Returned by /signin and /token/refresh
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    /// Lifetime of the access token in seconds
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+impl TokenResponse {
+    fn new(access_token: String, refresh_token: IssuedRefreshToken, config: &JwtConfig) -> Self {
+        TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: config.access_token_minutes * 60,
+            refresh_token: refresh_token.token,
+        }
+    }
+}
+
 pub async fn sign_in(
+    State(state): State<AppState>,
     Json(user_data): Json<SignInData>,
-) -> Result<Json<String>, AuthError> {
+) -> Result<Json<TokenResponse>, AuthError> {
 
     // 1. Retrieve user from the database
-    let user = match retrieve_user_by_email(&user_data.email) {
+    let user = match retrieve_user_by_email(&state, &user_data.email).await? {
         Some(user) => user,
         // None => return Err(StatusCode::UNAUTHORIZED), // User not found
         None => return Err(AuthError::NoUserError), // User not found
@@ -250,23 +430,68 @@ pub async fn sign_in(
         return Err(AuthError::PasswordError); // Wrong password
     }
 
-    // 3. Generate JWT
-    let token = encode_jwt(user.email)
+    // 3. Generate JWT and start a refresh token family
+    let refresh_token =
+        issue_refresh_token(&state.session_app(), &user.email, &state.config.jwt).await?;
+    let token = encode_jwt(&user, &state.signing_keys, &state.config.jwt)
         .map_err(|_| AuthError::GenerateJWTError)?;
 
-    println!("Token: {}", token);
+    // Never the tokens themselves, logs end up in CloudWatch
+    tracing::debug!("issued an access and a refresh token");
+
+    // 4. Return the tokens
+    Ok(Json(TokenResponse::new(token, refresh_token, &state.config.jwt)))
+}
 
-    // 4. Return the token
-    Ok(Json(token))
+#[derive(Deserialize)]
+pub struct RefreshTokenData {
+    pub refresh_token: String,
 }
 
-fn retrieve_user_by_email(email: &str) -> Option<CurrentUser> {
-    let current_user: CurrentUser = CurrentUser {
-        email: "myemail@gmail.com".to_string(),
-        first_name: "Eze".to_string(),
-        last_name: "Sunday".to_string(),
-        // the plain password hashed to this is "okon" without the quotes.
-        password_hash: "$2b$12$Gwf0uvxH3L7JLfo0CC/NCOoijK2vQ/wbgP.LeNup8vj6gg31IiFkm".to_string()
+/// Exchanges a refresh token for a new access token and a new refresh token.
+/// The presented refresh token can't be used again.
+pub async fn refresh_token(
+    State(state): State<AppState>,
+    Json(token_data): Json<RefreshTokenData>,
+) -> Result<Json<TokenResponse>, AuthError> {
+    let refresh_token =
+        rotate_refresh_token(&state.session_app(), &token_data.refresh_token, &state.config.jwt).await?;
+
+    // The user may have been disabled since signing in
+    let user = match retrieve_user_by_email(&state, refresh_token.username.as_str()).await? {
+        Some(user) => user,
+        None => return Err(AuthError::NoUserError),
     };
-    Some(current_user)
+
+    let token = encode_jwt(&user, &state.signing_keys, &state.config.jwt)
+        .map_err(|_| AuthError::GenerateJWTError)?;
+
+    Ok(Json(TokenResponse::new(token, refresh_token, &state.config.jwt)))
+}
+
+/// Revokes the presented refresh token together with every token rotated from the same sign in.
+/// Access tokens already issued stay valid until they expire.
+pub async fn sign_out(
+    State(state): State<AppState>,
+    Json(token_data): Json<RefreshTokenData>,
+) -> Result<StatusCode, AuthError> {
+    match revoke_refresh_token(&state.session_app(), &token_data.refresh_token).await {
+        // Signing out with an unknown token has nothing left to revoke
+        Ok(()) | Err(RefreshTokenError::Invalid) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Looks the user up in the users table. Disabled users are treated as unknown.
+async fn retrieve_user_by_email(state: &AppState, email: &str) -> Result<Option<CurrentUser>, AuthError> {
+    let user = find_user_by_email(&state.client, &state.config.tables.users, email)
+        .await
+        .map_err(|e| {
+            tracing::error!("user lookup failed: {}", e);
+            AuthError::UserLookupError
+        })?;
+
+    Ok(user
+        .filter(|user| user.status == UserStatus::Active)
+        .map(CurrentUser::from))
 }
@@ -0,0 +1,190 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tower::{Layer, Service};
+use crate::api_error::ApiError;
+use crate::auth::{AuthError, Claims};
+use crate::jwk::OidcClaims;
+
+/// Who the caller is and what they may do, independent of the token that proved it.
+///
+/// Inserted as a request extension by `auth::authorize` and `auth::authorize_firebase`,
+/// and checked by the `RequireRole` and `RequireScope` layers.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuthClaims {
+    pub subject: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    /// Permissions and OAuth scopes, e.g. "orders:write"
+    pub permissions: Vec<String>,
+}
+
+impl AuthClaims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+impl From<&Claims> for AuthClaims {
+    fn from(claims: &Claims) -> Self {
+        AuthClaims {
+            subject: claims.sub.clone(),
+            email: Some(claims.email.clone()),
+            roles: claims.roles.clone(),
+            permissions: claims.permissions.clone(),
+        }
+    }
+}
+
+/// Firebase custom claims are set with the Admin SDK, e.g.
+/// `setCustomUserClaims(uid, { roles: ["admin"], permissions: ["orders:write"] })`.
+/// A single `role`, Cognito's `cognito:groups` and a space separated OAuth `scope`
+/// claim are accepted as well.
+impl From<&OidcClaims> for AuthClaims {
+    fn from(claims: &OidcClaims) -> Self {
+        let custom = &claims.custom_claims;
+        AuthClaims {
+            subject: claims.sub.clone(),
+            email: claims.email.clone(),
+            roles: [
+                string_list(custom, "roles"),
+                string_list(custom, "role"),
+                string_list(custom, "cognito:groups"),
+            ]
+            .concat(),
+            permissions: [string_list(custom, "permissions"), string_list(custom, "scope")].concat(),
+        }
+    }
+}
+
+/// Reads a claim holding either an array of strings or a space separated string
+fn string_list(claims: &Map<String, Value>, name: &str) -> Vec<String> {
+    match claims.get(name) {
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        Some(Value::String(items)) => items.split_whitespace().map(str::to_string).collect(),
+        _ => vec![],
+    }
+}
+
+/// Lets a handler take the claims of an authorized request as an argument
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthClaims {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthClaims>()
+            .cloned()
+            .ok_or(AuthError::UnauthorizedUserError)
+    }
+}
+
+/// Rejects requests whose claims lack a role, e.g.
+/// ```ignore
+/// .route(
+///     "/delete_user_table_entity/:user_id/:order_id",
+///     delete(handler)
+///         .layer(RequireRole("admin"))
+///         .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
+/// )
+/// ```
+/// The authorize middleware has to be added after, so it runs first.
+#[derive(Clone, Copy, Debug)]
+pub struct RequireRole(pub &'static str);
+
+impl RequireRole {
+    /// The same 403 as the layer, for handlers that only need the role for some requests
+    pub fn check(self, claims: &AuthClaims) -> Result<(), ApiError> {
+        Requirement::Role(self.0).check(claims)
+    }
+}
+
+/// Rejects requests whose claims lack a permission or scope, see `RequireRole`
+#[derive(Clone, Copy, Debug)]
+pub struct RequireScope(pub &'static str);
+
+#[derive(Clone, Copy, Debug)]
+enum Requirement {
+    Role(&'static str),
+    Scope(&'static str),
+}
+
+impl Requirement {
+    /// 403 naming what was missing, with the code `insufficient_role` or `insufficient_scope`
+    fn check(self, claims: &AuthClaims) -> Result<(), ApiError> {
+        let (allowed, code, kind, required) = match self {
+            Requirement::Role(role) => (claims.has_role(role), "insufficient_role", "role", role),
+            Requirement::Scope(scope) => (claims.has_permission(scope), "insufficient_scope", "scope", scope),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden { code, detail: format!("Requires {kind} {required}") })
+        }
+    }
+}
+
+impl<S> Layer<S> for RequireRole {
+    type Service = RequireService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireService { inner, requirement: Requirement::Role(self.0) }
+    }
+}
+
+impl<S> Layer<S> for RequireScope {
+    type Service = RequireService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireService { inner, requirement: Requirement::Scope(self.0) }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequireService<S> {
+    inner: S,
+    requirement: Requirement,
+}
+
+impl<S> Service<Request> for RequireService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let checked = match req.extensions().get::<AuthClaims>() {
+            Some(claims) => self.requirement.check(claims).map_err(IntoResponse::into_response),
+            // No authorize middleware ran before this layer
+            None => Err(AuthError::UnauthorizedUserError.into_response()),
+        };
+
+        match checked {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(rejection) => Box::pin(async move { Ok(rejection) }),
+        }
+    }
+}
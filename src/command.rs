@@ -0,0 +1,102 @@
+//! Commands the binary runs instead of serving the API, e.g.
+//! `cargo run -- provision --dry-run` or `cargo run -- seed --clear`
+
+use aws_sdk_dynamodb::Client;
+use lambda_http::Error;
+use crate::config::TableNames;
+use crate::provision::ProvisionCommand;
+use crate::seed::SeedCommand;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Creates or updates the tables, see `provision`
+    Provision(ProvisionCommand),
+    /// Loads the sample items of a NoSQL Workbench model, see `seed`
+    Seed(SeedCommand),
+}
+
+impl Command {
+    /// `Some` when the first argument names a command
+    pub fn from_args() -> Result<Option<Self>, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Self::parse(&args)
+    }
+
+    fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let Some((name, args)) = args.split_first() else {
+            return Ok(None);
+        };
+        match name.as_str() {
+            "provision" => ProvisionCommand::parse(args).map(|command| Some(Command::Provision(command))),
+            "seed" => SeedCommand::parse(args).map(|command| Some(Command::Seed(command))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Commands only need the table names of the config, not the JWT or JWK settings
+    pub async fn run(self, client: Client, tables: &TableNames) -> Result<(), Error> {
+        match self {
+            Command::Provision(command) => command.run(client, tables).await?,
+            Command::Seed(command) => command.run(client, tables).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Splits the options of `command` into flags and their values. Flags of
+/// `with_value` take one, as `--flag value` or `--flag=value`.
+pub fn options(command: &str, args: &[String], switches: &[&str], with_value: &[&str]) -> Result<Vec<(String, Option<String>)>, String> {
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let value = if with_value.contains(&flag) {
+            let value = inline_value.or_else(|| args.next().cloned());
+            Some(value.ok_or_else(|| format!("{command} {flag} needs a value"))?)
+        } else if switches.contains(&flag) && inline_value.is_none() {
+            None
+        } else {
+            return Err(format!("unknown {command} option {arg:?}"));
+        };
+        options.push((flag.to_string(), value));
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, String> {
+        Command::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn commands_read_their_options() {
+        assert_eq!(parse(&["--listen", "127.0.0.1:8080"]), Ok(None));
+        assert_eq!(
+            parse(&["provision", "--model=AccountUser.json", "--table", "UserTable", "--dry-run"]),
+            Ok(Some(Command::Provision(ProvisionCommand {
+                model: Some(PathBuf::from("AccountUser.json")),
+                tables: vec!["UserTable".to_string()],
+                dry_run: true,
+            })))
+        );
+        assert_eq!(
+            parse(&["seed", "--clear", "--into", "UserTable-dev"]),
+            Ok(Some(Command::Seed(SeedCommand {
+                model: PathBuf::from("AccountUser.json"),
+                table: "UserTable".to_string(),
+                into: Some("UserTable-dev".to_string()),
+                clear: true,
+            })))
+        );
+        assert!(parse(&["provision", "--table"]).is_err());
+        assert!(parse(&["seed", "--clear=yes"]).is_err());
+        assert!(parse(&["seed", "--force"]).is_err());
+    }
+}
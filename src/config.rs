@@ -0,0 +1,343 @@
+use std::env;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::jwk::JwkConfiguration;
+
+/// Environment variable pointing to an optional TOML or JSON config file
+const CONFIG_FILE_ENV: &str = "APP_CONFIG_FILE";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read config file {path:?}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("could not parse config file {path:?}: {message}")]
+    Parse { path: PathBuf, message: String },
+    #[error("unsupported config file format {0:?}, expected .toml or .json")]
+    UnsupportedFormat(PathBuf),
+    #[error("invalid value for {name}: {message}")]
+    Invalid { name: &'static str, message: String },
+    #[error("missing required setting {0}")]
+    Missing(&'static str),
+}
+
+/// Application configuration, built once at startup.
+///
+/// Values are read from an optional config file named by `APP_CONFIG_FILE`
+/// and then overridden by environment variables, e.g.
+/// ```toml
+/// [tables]
+/// user_table = "UserTable"
+///
+/// [jwt]
+/// secret = "..."
+///
+/// [pagination]
+/// secret = "at least 32 characters"
+///
+/// [jwk]
+/// jwk_url = "https://www.googleapis.com/service_accounts/v1/jwk/securetoken@system.gserviceaccount.com"
+/// audience = "FIREBASE_PROJECT_ID"
+/// issuer = "https://securetoken.google.com/FIREBASE_PROJECT_ID"
+///
+/// # More issuers, with keys found through OIDC discovery
+/// [[issuers]]
+/// audience = "COGNITO_APP_CLIENT_ID"
+/// issuer = "https://cognito-idp.us-east-1.amazonaws.com/USER_POOL_ID"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub tables: TableNames,
+    pub jwt: JwtConfig,
+    /// Issuer configured through the `JWK_*` environment variables, usually Firebase
+    pub jwk: JwkConfiguration,
+    /// Further trusted issuers
+    pub issuers: Vec<JwkConfiguration>,
+    pub pagination: PaginationConfig,
+}
+
+/// Names of the DynamoDB tables used by the handlers
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TableNames {
+    /// Table behind the `Item` handlers, keyed by `username`
+    pub items: String,
+    /// Table loaded from AccountUser.json, keyed by `UserId` / `OrderId`
+    pub user_table: String,
+    /// Table used by the modyne `Session` entity
+    pub sessions: String,
+    /// Registered users of the `/signin` flow, keyed by `user_id` with an `email_index` GSI
+    pub users: String,
+}
+
+impl Default for TableNames {
+    fn default() -> Self {
+        Self {
+            items: "lambda_dynamo_2".to_string(),
+            user_table: "UserTable".to_string(),
+            sessions: "SessionStore".to_string(),
+            users: "Users".to_string(),
+        }
+    }
+}
+
+/// Settings for the self-issued JWTs of the `/signin` flow
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    /// Shared HS256 secret, required when no `signing_keys` are configured
+    pub secret: String,
+    /// `iss` claim of issued tokens
+    pub issuer: String,
+    /// `aud` claim of issued tokens
+    pub audience: String,
+    /// Asymmetric keys, see `signing_keys::SigningKeys`
+    pub signing_keys: Vec<SigningKeyConfig>,
+    /// `kid` of the signing key used for new tokens
+    pub active_kid: String,
+    /// Lifetime of an access token
+    pub access_token_minutes: i64,
+    /// Lifetime of a refresh token. Each refresh issues a new token with a fresh lifetime.
+    pub refresh_token_days: i64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            issuer: "cargo-lambda-axum".to_string(),
+            audience: "cargo-lambda-axum".to_string(),
+            signing_keys: vec![],
+            active_kid: String::new(),
+            access_token_minutes: 15,
+            refresh_token_days: 30,
+        }
+    }
+}
+
+/// Settings of the page tokens returned in `app-token`, see `dynamo_query_helpers::PageTokens`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PaginationConfig {
+    /// Key material for encrypting page tokens, at least 32 characters.
+    /// Changing it invalidates the tokens handed out so far.
+    pub secret: String,
+    /// How long a page token can be used
+    pub token_minutes: i64,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            token_minutes: 60,
+        }
+    }
+}
+
+/// A PEM encoded signing key, e.g.
+/// ```toml
+/// [[jwt.signing_keys]]
+/// kid = "2024-09"
+/// algorithm = "ES256"
+/// public_key_path = "keys/2024-09.pub.pem"
+/// private_key_path = "keys/2024-09.pem"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningKeyConfig {
+    pub kid: String,
+    /// RS256, RS384, RS512, PS256, PS384, PS512, ES256, ES384 or EdDSA
+    pub algorithm: String,
+    /// `SubjectPublicKeyInfo` PEM
+    pub public_key_path: PathBuf,
+    /// PKCS#8 PEM (or PKCS#1 for RSA). Only needed for the active key.
+    #[serde(default)]
+    pub private_key_path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Loads the config file (if any), applies environment overrides and validates the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let config = Self::read()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like `load` without the validation, for commands that only need some
+    /// settings, e.g. the table names of `provision`
+    pub fn read() -> Result<Self, ConfigError> {
+        let mut config = match env::var(CONFIG_FILE_ENV) {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Reads a config file, choosing the format from its extension
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+            _ => Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+        }
+    }
+
+    /// Environment variables take precedence over the config file
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_from_env(&mut self.tables.items, "TABLE_ITEMS");
+        override_from_env(&mut self.tables.user_table, "TABLE_USER_TABLE");
+        override_from_env(&mut self.tables.sessions, "TABLE_SESSIONS");
+        override_from_env(&mut self.tables.users, "TABLE_USERS");
+
+        override_from_env(&mut self.jwt.secret, "JWT_SECRET");
+        override_from_env(&mut self.jwt.issuer, "JWT_ISSUER");
+        override_from_env(&mut self.jwt.audience, "JWT_AUDIENCE");
+        override_from_env(&mut self.jwt.active_kid, "JWT_ACTIVE_KID");
+        override_number_from_env(&mut self.jwt.access_token_minutes, "JWT_ACCESS_TOKEN_MINUTES")?;
+        override_number_from_env(&mut self.jwt.refresh_token_days, "JWT_REFRESH_TOKEN_DAYS")?;
+
+        override_from_env(&mut self.pagination.secret, "PAGINATION_SECRET");
+        override_number_from_env(&mut self.pagination.token_minutes, "PAGINATION_TOKEN_MINUTES")?;
+
+        override_from_env(&mut self.jwk.jwk_url, "JWK_URL");
+        override_from_env(&mut self.jwk.audience, "JWK_AUDIENCE");
+        override_from_env(&mut self.jwk.issuer, "JWK_ISSUER");
+        Ok(())
+    }
+
+    /// Fails fast on settings that would otherwise only break at request time
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        require(&self.tables.items, "tables.items / TABLE_ITEMS")?;
+        require(&self.tables.user_table, "tables.user_table / TABLE_USER_TABLE")?;
+        require(&self.tables.sessions, "tables.sessions / TABLE_SESSIONS")?;
+        require(&self.tables.users, "tables.users / TABLE_USERS")?;
+        require(&self.jwt.issuer, "jwt.issuer / JWT_ISSUER")?;
+        require(&self.jwt.audience, "jwt.audience / JWT_AUDIENCE")?;
+        self.validate_signing_keys()?;
+        self.validate_issuers()?;
+
+        require_positive(self.jwt.access_token_minutes, "jwt.access_token_minutes / JWT_ACCESS_TOKEN_MINUTES")?;
+        require_positive(self.jwt.refresh_token_days, "jwt.refresh_token_days / JWT_REFRESH_TOKEN_DAYS")?;
+
+        require(&self.pagination.secret, "pagination.secret / PAGINATION_SECRET")?;
+        if self.pagination.secret.len() < 32 {
+            return Err(ConfigError::Invalid {
+                name: "pagination.secret / PAGINATION_SECRET",
+                message: "must be at least 32 characters".to_string(),
+            });
+        }
+        require_positive(self.pagination.token_minutes, "pagination.token_minutes / PAGINATION_TOKEN_MINUTES")?;
+        Ok(())
+    }
+
+    /// Every issuer whose tokens `auth::authorize_firebase` accepts
+    pub fn jwk_issuers(&self) -> Vec<JwkConfiguration> {
+        let jwk = &self.jwk;
+        let jwk_is_set = [&jwk.jwk_url, &jwk.audience, &jwk.issuer]
+            .iter()
+            .any(|value| !value.is_empty());
+
+        jwk_is_set
+            .then(|| jwk.clone())
+            .into_iter()
+            .chain(self.issuers.iter().cloned())
+            .collect()
+    }
+
+    fn validate_issuers(&self) -> Result<(), ConfigError> {
+        let issuers = self.jwk_issuers();
+        if issuers.is_empty() {
+            return Err(ConfigError::Missing("jwk.issuer / JWK_ISSUER or issuers"));
+        }
+
+        let mut seen = HashSet::new();
+        for issuer in &issuers {
+            require(&issuer.issuer, "jwk.issuer / JWK_ISSUER / issuers.issuer")?;
+            require(&issuer.audience, "jwk.audience / JWK_AUDIENCE / issuers.audience")?;
+            if !seen.insert(issuer.issuer.as_str()) {
+                return Err(ConfigError::Invalid {
+                    name: "issuers",
+                    message: format!("duplicate issuer {:?}", issuer.issuer),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_signing_keys(&self) -> Result<(), ConfigError> {
+        let jwt = &self.jwt;
+        if jwt.signing_keys.is_empty() {
+            return require(&jwt.secret, "jwt.secret / JWT_SECRET");
+        }
+
+        let mut kids = HashSet::new();
+        for key in &jwt.signing_keys {
+            require(&key.kid, "jwt.signing_keys.kid")?;
+            if !kids.insert(key.kid.as_str()) {
+                return Err(ConfigError::Invalid {
+                    name: "jwt.signing_keys",
+                    message: format!("duplicate kid {:?}", key.kid),
+                });
+            }
+        }
+
+        require(&jwt.active_kid, "jwt.active_kid / JWT_ACTIVE_KID")?;
+        match jwt.signing_keys.iter().find(|key| key.kid == jwt.active_kid) {
+            Some(key) if key.private_key_path.is_some() => Ok(()),
+            Some(_) => Err(ConfigError::Invalid {
+                name: "jwt.active_kid / JWT_ACTIVE_KID",
+                message: format!("signing key {:?} has no private_key_path", jwt.active_kid),
+            }),
+            None => Err(ConfigError::Invalid {
+                name: "jwt.active_kid / JWT_ACTIVE_KID",
+                message: format!("no signing key with kid {:?}", jwt.active_kid),
+            }),
+        }
+    }
+}
+
+fn override_from_env(target: &mut String, name: &str) {
+    if let Ok(value) = env::var(name) {
+        *target = value;
+    }
+}
+
+fn override_number_from_env(target: &mut i64, name: &'static str) -> Result<(), ConfigError> {
+    if let Ok(value) = env::var(name) {
+        *target = value.parse().map_err(|_| ConfigError::Invalid {
+            name,
+            message: format!("{value:?} is not a whole number"),
+        })?;
+    }
+    Ok(())
+}
+
+fn require_positive(value: i64, name: &'static str) -> Result<(), ConfigError> {
+    if value > 0 {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            name,
+            message: "must be greater than zero".to_string(),
+        })
+    }
+}
+
+fn require(value: &str, name: &'static str) -> Result<(), ConfigError> {
+    if value.trim().is_empty() {
+        Err(ConfigError::Missing(name))
+    } else {
+        Ok(())
+    }
+}
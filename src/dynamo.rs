@@ -24,23 +24,246 @@ use serde::{Deserialize, Serialize};
 use serde_dynamo::to_attribute_value;
 use serde_json::json;
 use crate::auth::{AuthError, CurrentUser, VerificationError};
-use crate::dynamo::DynamoError::DynError;
+use aws_sdk_dynamodb::error::{BuildError, ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::RequestId;
 use crate::dynamo_query_helpers::*;
 use crate::item::*;
 use crate::{ UserResponse};
 use crate::user_table::{query_by_date_range_serde_dynamo, query_by_sorted_dates_serde_dynamo, UpdateUserTable, UserTable};
 
+/// The SDK error a `DynamoError` was classified from
+pub type SdkSource = Box<aws_sdk_dynamodb::Error>;
+
+/// Why a DynamoDB call failed.
+///
+/// Classified once from the SDK error, which stays available as the `source`
+/// together with the request id AWS support asks for.
 #[derive(Debug, thiserror::Error)]
 pub enum DynamoError {
-    #[error("Error in Dynamo")]
-    DynError ,
-    #[error("Error in Dynamo: {exp:?}")]
-    DynErrorExp {exp: String},
-    // #[error("Error in Dynamo: {0}")]
-    // DynErrorFrom {#[from] aws_smithy_runtime_api::client::result::SdkError},
+    #[error("DynamoDB throttled the request")]
+    Throttled { source: SdkSource, request_id: Option<String> },
+    #[error("The conditional request failed")]
+    ConditionalCheckFailed { source: SdkSource, request_id: Option<String> },
+    /// `reasons` has one entry per item of the transaction, in order
+    #[error("Transaction cancelled: {}", CancellationReason::summary(.reasons))]
+    TransactionCancelled {
+        reasons: Vec<CancellationReason>,
+        source: SdkSource,
+        request_id: Option<String>,
+    },
+    #[error("Transaction conflicts with another transaction in progress")]
+    TransactionConflict { source: SdkSource, request_id: Option<String> },
+    /// A transaction with the same idempotency token is still running
+    #[error("A transaction with this idempotency token is in progress")]
+    TransactionInProgress { source: SdkSource, request_id: Option<String> },
+    /// The idempotency token was used before for a different transaction
+    #[error("The idempotency token was used for a different request")]
+    IdempotencyMismatch { source: SdkSource, request_id: Option<String> },
+    /// A read found no item with the key
+    #[error("{0} not found")]
+    NotFound(String),
+    /// A batch still had unprocessed items after its retries, DynamoDB is over capacity
+    #[error("{0} items were not processed, try again later")]
+    Unprocessed(usize),
+    /// A create found an item with the key
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    /// A write expected another version of the item
+    #[error("{0} has been modified since it was read")]
+    VersionMismatch(String),
+    /// An unconditional put of a versioned entity, which would reset its version
+    #[error("{0} is versioned, write it with a create or an update")]
+    Versioned(String),
+    #[error("Table or index not found")]
+    ResourceNotFound { source: SdkSource, request_id: Option<String> },
+    #[error("Table or index already exists or is being changed")]
+    ResourceInUse { source: SdkSource, request_id: Option<String> },
+    #[error("DynamoDB rejected the request: {}", .source.message().unwrap_or("validation failed"))]
+    Validation { source: SdkSource, request_id: Option<String> },
+    #[error("Access to DynamoDB was denied")]
+    AccessDenied { source: SdkSource, request_id: Option<String> },
+    #[error("DynamoDB is unavailable")]
+    Unavailable { source: SdkSource, request_id: Option<String> },
+    /// The request never got a response, e.g. a DNS or connection failure
+    #[error("Could not reach DynamoDB")]
+    Dispatch { source: SdkSource, request_id: Option<String> },
+    #[error("DynamoDB request timed out")]
+    Timeout { source: SdkSource, request_id: Option<String> },
+    #[error("Could not convert the item: {0}")]
+    Serialization(#[from] serde_dynamo::Error),
+    #[error("table was not ready after several attempts: {0}")]
+    TableNotReady(String),
+    #[error("problem building schema key or element: {0}")]
+    Build(#[from] BuildError),
+    #[error("Error in Dynamo: {source}")]
+    Service { source: SdkSource, request_id: Option<String> },
+}
+
+/// Why one item of a cancelled transaction failed. `code` is "None" for items that didn't.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CancellationReason {
+    pub code: String,
+    pub message: Option<String>,
+    /// What the item was, e.g. "Update UserTable", when the transaction was
+    /// sent through `dynamo_query_helpers::Transaction`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+}
+
+impl CancellationReason {
+    pub fn failed(&self) -> bool {
+        self.code != "None"
+    }
+
+    /// "ConditionalCheckFailed at item 1, ..."
+    fn summary(reasons: &[CancellationReason]) -> String {
+        reasons
+            .iter()
+            .enumerate()
+            .filter(|(_, reason)| reason.failed())
+            .map(|(index, reason)| format!("{} at item {}", reason.code, index))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl From<&aws_sdk_dynamodb::types::CancellationReason> for CancellationReason {
+    fn from(reason: &aws_sdk_dynamodb::types::CancellationReason) -> Self {
+        CancellationReason {
+            code: reason.code().unwrap_or("None").to_string(),
+            message: reason.message().map(str::to_string),
+            operation: None,
+        }
+    }
+}
+
+impl DynamoError {
+    pub fn table_not_ready(table_name: impl Into<String>) -> Self {
+        DynamoError::TableNotReady(table_name.into())
+    }
+
+    pub fn not_found(what: impl Into<String>) -> Self {
+        DynamoError::NotFound(what.into())
+    }
 
+    /// The request id of the failed call, if it got a response
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            DynamoError::Throttled { request_id, .. }
+            | DynamoError::ConditionalCheckFailed { request_id, .. }
+            | DynamoError::TransactionCancelled { request_id, .. }
+            | DynamoError::TransactionConflict { request_id, .. }
+            | DynamoError::TransactionInProgress { request_id, .. }
+            | DynamoError::IdempotencyMismatch { request_id, .. }
+            | DynamoError::ResourceNotFound { request_id, .. }
+            | DynamoError::ResourceInUse { request_id, .. }
+            | DynamoError::Validation { request_id, .. }
+            | DynamoError::AccessDenied { request_id, .. }
+            | DynamoError::Unavailable { request_id, .. }
+            | DynamoError::Dispatch { request_id, .. }
+            | DynamoError::Timeout { request_id, .. }
+            | DynamoError::Service { request_id, .. } => request_id.as_deref(),
+            DynamoError::NotFound(_)
+            | DynamoError::Unprocessed(_)
+            | DynamoError::AlreadyExists(_)
+            | DynamoError::VersionMismatch(_)
+            | DynamoError::Versioned(_)
+            | DynamoError::Serialization(_)
+            | DynamoError::TableNotReady(_)
+            | DynamoError::Build(_) => None,
+        }
+    }
+
+    /// The item as it was when a condition failed, if the request asked for it
+    /// with `ReturnValuesOnConditionCheckFailure::AllOld`
+    pub fn condition_failed_item(&self) -> Option<&HashMap<String, AttributeValue>> {
+        match self {
+            DynamoError::ConditionalCheckFailed { source, .. } => match &**source {
+                aws_sdk_dynamodb::Error::ConditionalCheckFailedException(e) => e.item(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// A condition failed, on its own or as part of a transaction
+    pub fn is_conditional_check_failed(&self) -> bool {
+        match self {
+            DynamoError::ConditionalCheckFailed { .. } => true,
+            DynamoError::TransactionCancelled { reasons, .. } => {
+                reasons.iter().any(|reason| reason.code == "ConditionalCheckFailed")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<aws_sdk_dynamodb::Error> for DynamoError {
+    fn from(error: aws_sdk_dynamodb::Error) -> Self {
+        use aws_sdk_dynamodb::Error as E;
+
+        let request_id = error.request_id().map(str::to_string);
+        let source = Box::new(error);
+        match &*source {
+            E::ProvisionedThroughputExceededException(_)
+            | E::ThrottlingException(_)
+            | E::RequestLimitExceeded(_)
+            | E::LimitExceededException(_) => DynamoError::Throttled { source, request_id },
+            E::ConditionalCheckFailedException(_) => DynamoError::ConditionalCheckFailed { source, request_id },
+            E::TransactionCanceledException(e) => {
+                let reasons = e.cancellation_reasons().iter().map(Into::into).collect();
+                DynamoError::TransactionCancelled { reasons, source, request_id }
+            }
+            E::TransactionConflictException(_) => DynamoError::TransactionConflict { source, request_id },
+            E::TransactionInProgressException(_) => DynamoError::TransactionInProgress { source, request_id },
+            E::IdempotentParameterMismatchException(_) => DynamoError::IdempotencyMismatch { source, request_id },
+            E::ResourceNotFoundException(_) | E::TableNotFoundException(_) | E::IndexNotFoundException(_) => {
+                DynamoError::ResourceNotFound { source, request_id }
+            }
+            E::ResourceInUseException(_) | E::TableAlreadyExistsException(_) => {
+                DynamoError::ResourceInUse { source, request_id }
+            }
+            E::InternalServerError(_) => DynamoError::Unavailable { source, request_id },
+            // Not modelled by the SDK, so only known by their code
+            other => match other.code() {
+                Some("ThrottlingException") => DynamoError::Throttled { source, request_id },
+                Some("ValidationException") => DynamoError::Validation { source, request_id },
+                Some(
+                    "AccessDeniedException"
+                    | "UnrecognizedClientException"
+                    | "MissingAuthenticationTokenException"
+                    | "InvalidSignatureException"
+                    | "ExpiredTokenException",
+                ) => DynamoError::AccessDenied { source, request_id },
+                Some("ServiceUnavailable") => DynamoError::Unavailable { source, request_id },
+                _ => DynamoError::Service { source, request_id },
+            },
+        }
+    }
+}
+
+/// Failures without a response are told apart here, the unified SDK error loses them
+impl<E, R> From<SdkError<E, R>> for DynamoError
+where
+    aws_sdk_dynamodb::Error: From<SdkError<E, R>>,
+{
+    fn from(error: SdkError<E, R>) -> Self {
+        let timeout = match &error {
+            SdkError::TimeoutError(_) => true,
+            SdkError::DispatchFailure(failure) => failure.is_timeout(),
+            _ => return aws_sdk_dynamodb::Error::from(error).into(),
+        };
+        let source = Box::new(aws_sdk_dynamodb::Error::from(error));
+        if timeout {
+            DynamoError::Timeout { source, request_id: None }
+        } else {
+            DynamoError::Dispatch { source, request_id: None }
+        }
+    }
 }
 
+/// Body of a successful write, `{"result":"success","message":"created item"}`.
+/// Failures are returned as `ApiError`.
 // #[derive(Serialize, Deserialize)]
 pub struct StatResp {
     pub result: String,
@@ -0,0 +1,1580 @@
+//! An in-memory DynamoDB for offline tests.
+//!
+//! `FakeDynamo::client` returns a real `aws_sdk_dynamodb::Client` whose HTTP
+//! client answers the DynamoDB JSON protocol from memory, so `Repository`,
+//! `PagedQuery`, `Transaction` and the modyne `App` run unchanged against it.
+//!
+//! Covered: `GetItem`, `PutItem`, `UpdateItem`, `DeleteItem`, `Query`, `Scan`,
+//! `BatchGetItem`, `BatchWriteItem`, `TransactWriteItems`, table management
+//! and TTL. Condition, key condition, filter, update and projection
+//! expressions are evaluated on top level attributes; document paths
+//! (`a.b`, `a[0]`) are not supported.
+//!
+//! ```ignore
+//! let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+//! let client = fake.client();
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_dynamodb::Client;
+use aws_smithy_http_client::test_util::infallible_client_fn;
+use aws_smithy_types::body::SdkBody;
+use axum::http;
+use serde_json::{json, Map, Value};
+use crate::config::TableNames;
+
+/// An item in the wire format, e.g. `{"UserId": {"S": "u#user1"}}`
+pub type Item = Map<String, Value>;
+
+/// The hash and optional range attribute of a table or index
+#[derive(Clone, Debug)]
+pub struct KeySchema {
+    pub hash: String,
+    pub range: Option<String>,
+}
+
+impl KeySchema {
+    pub fn new(hash: &str, range: Option<&str>) -> Self {
+        KeySchema { hash: hash.to_string(), range: range.map(str::to_string) }
+    }
+
+    fn attributes(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.hash).chain(self.range.as_ref())
+    }
+
+    /// The key of `item`, `None` if it lacks a key attribute
+    fn key_of(&self, item: &Item) -> Option<Item> {
+        self.attributes()
+            .map(|name| item.get(name).map(|value| (name.clone(), value.clone())))
+            .collect()
+    }
+
+    fn from_wire(key_schema: &Value) -> Result<Self, Fault> {
+        let attribute = |key_type: &str| {
+            key_schema.as_array().into_iter().flatten().find(|element| element["KeyType"] == key_type).and_then(|element| element["AttributeName"].as_str())
+        };
+        let hash = attribute("HASH").ok_or_else(|| Fault::validation("KeySchema needs a HASH key"))?;
+        Ok(KeySchema::new(hash, attribute("RANGE")))
+    }
+
+    fn to_wire(&self) -> Value {
+        let mut schema = vec![json!({"AttributeName": self.hash, "KeyType": "HASH"})];
+        if let Some(range) = &self.range {
+            schema.push(json!({"AttributeName": range, "KeyType": "RANGE"}));
+        }
+        Value::Array(schema)
+    }
+}
+
+/// A table to create up front, e.g. `TableSpec::new("Items", "username", None)`
+#[derive(Clone, Debug)]
+pub struct TableSpec {
+    pub name: String,
+    pub key: KeySchema,
+    pub indexes: Vec<(String, KeySchema)>,
+    pub ttl_attribute: Option<String>,
+}
+
+impl TableSpec {
+    pub fn new(name: &str, hash: &str, range: Option<&str>) -> Self {
+        TableSpec { name: name.to_string(), key: KeySchema::new(hash, range), indexes: Vec::new(), ttl_attribute: None }
+    }
+
+    /// A global secondary index projecting all attributes
+    pub fn index(mut self, name: &str, hash: &str, range: Option<&str>) -> Self {
+        self.indexes.push((name.to_string(), KeySchema::new(hash, range)));
+        self
+    }
+
+    /// Enables TTL on `attribute`
+    pub fn ttl(mut self, attribute: &str) -> Self {
+        self.ttl_attribute = Some(attribute.to_string());
+        self
+    }
+
+    /// `UserTable` of AccountUser.json with its `gsi1` index
+    pub fn user_table() -> Self {
+        TableSpec::new(&TableNames::default().user_table, "UserId", Some("OrderId")).index("gsi1", "gsi_pk", Some("date_ordered"))
+    }
+
+    /// The modyne sessions table with `UserIndex` and TTL on `ttl`
+    pub fn sessions() -> Self {
+        TableSpec::new(&TableNames::default().sessions, "session_token", None).index("UserIndex", "username", None).ttl("ttl")
+    }
+
+    /// The users of `/signin` with their `email_index`
+    pub fn users() -> Self {
+        TableSpec::new(&TableNames::default().users, "user_id", None).index(crate::user::EMAIL_INDEX, "email", None)
+    }
+
+    /// The `Item` table, keyed by `username`
+    pub fn items() -> Self {
+        TableSpec::new(&TableNames::default().items, "username", None)
+    }
+}
+
+struct Table {
+    spec: TableSpec,
+    /// Types of the key attributes, "S" when not given
+    attribute_types: HashMap<String, String>,
+    billing_mode: String,
+    /// Read and write capacity units of the table and its indexes
+    throughput: Option<Value>,
+    stream: Option<Value>,
+    /// `DescribeTable` calls left that report the table as `status`, see `FakeDynamo::slow_changes`
+    pending: usize,
+    status: &'static str,
+    items: Vec<Item>,
+}
+
+impl Table {
+    fn new(spec: TableSpec) -> Self {
+        Table {
+            spec,
+            attribute_types: HashMap::new(),
+            billing_mode: "PAY_PER_REQUEST".to_string(),
+            throughput: None,
+            stream: None,
+            pending: 0,
+            status: "ACTIVE",
+            items: Vec::new(),
+        }
+    }
+
+    fn define_attributes(&mut self, request: &Value) {
+        for definition in request["AttributeDefinitions"].as_array().into_iter().flatten() {
+            if let (Some(name), Some(kind)) = (definition["AttributeName"].as_str(), definition["AttributeType"].as_str()) {
+                self.attribute_types.insert(name.to_string(), kind.to_string());
+            }
+        }
+    }
+
+    /// Reports the table as `status` for the next `describes` `DescribeTable` calls
+    fn change(&mut self, status: &'static str, describes: usize) {
+        self.pending = describes;
+        self.status = if describes == 0 { "ACTIVE" } else { status };
+    }
+
+    fn schema(&self, index: Option<&str>) -> Result<&KeySchema, Fault> {
+        match index {
+            None => Ok(&self.spec.key),
+            Some(index) => self.spec.indexes.iter().find(|(name, _)| name == index).map(|(_, schema)| schema).ok_or_else(|| {
+                Fault::validation(format!("The table does not have the specified index: {index}"))
+            }),
+        }
+    }
+
+    /// The key attributes of `key`, checked against the table's key schema
+    fn key(&self, key: &Value) -> Result<Item, Fault> {
+        let key = key.as_object().ok_or_else(|| Fault::validation("Key is required"))?;
+        match self.spec.key.key_of(key) {
+            Some(found) if found.len() == key.len() => Ok(found),
+            _ => Err(Fault::validation("The provided key element does not match the schema")),
+        }
+    }
+
+    fn position(&self, key: &Item) -> Option<usize> {
+        self.items.iter().position(|item| key.iter().all(|(name, value)| item.get(name) == Some(value)))
+    }
+
+    fn describe(&self) -> Value {
+        let mut definitions: Vec<&String> = self.spec.key.attributes().chain(self.spec.indexes.iter().flat_map(|(_, schema)| schema.attributes())).collect();
+        definitions.sort();
+        definitions.dedup();
+
+        let attribute_type = |name: &String| self.attribute_types.get(name).map_or("S", String::as_str);
+        let throughput = self.throughput.clone().unwrap_or_else(|| json!({"ReadCapacityUnits": 0, "WriteCapacityUnits": 0}));
+        let mut description = json!({
+            "TableName": self.spec.name,
+            "TableStatus": self.status,
+            "TableArn": format!("arn:aws:dynamodb:us-east-1:000000000000:table/{}", self.spec.name),
+            "KeySchema": self.spec.key.to_wire(),
+            "AttributeDefinitions": definitions.iter().map(|name| json!({"AttributeName": name, "AttributeType": attribute_type(name)})).collect::<Vec<_>>(),
+            "ItemCount": self.items.len(),
+            "BillingModeSummary": {"BillingMode": self.billing_mode},
+            "ProvisionedThroughput": throughput,
+        });
+        if !self.spec.indexes.is_empty() {
+            description["GlobalSecondaryIndexes"] = self
+                .spec
+                .indexes
+                .iter()
+                .map(|(name, schema)| {
+                    json!({
+                        "IndexName": name,
+                        "KeySchema": schema.to_wire(),
+                        "Projection": {"ProjectionType": "ALL"},
+                        "IndexStatus": "ACTIVE",
+                        "ProvisionedThroughput": throughput,
+                    })
+                })
+                .collect();
+        }
+        if let Some(stream) = &self.stream {
+            description["StreamSpecification"] = stream.clone();
+        }
+        description
+    }
+}
+
+#[derive(Default)]
+struct State {
+    tables: HashMap<String, Table>,
+    /// Batch calls left that process nothing, see `FakeDynamo::leave_unprocessed`
+    unprocessed: usize,
+    now: Option<i64>,
+    /// How long table changes take, see `FakeDynamo::slow_changes`
+    change_describes: usize,
+    requests: Vec<(String, Value)>,
+}
+
+/// An in-memory DynamoDB, cloning shares the tables
+#[derive(Clone, Default)]
+pub struct FakeDynamo {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeDynamo {
+    pub fn new() -> Self {
+        FakeDynamo::default()
+    }
+
+    pub fn with_table(self, spec: TableSpec) -> Self {
+        self.create_table(spec);
+        self
+    }
+
+    pub fn create_table(&self, spec: TableSpec) {
+        let mut state = self.state.lock().unwrap();
+        state.tables.insert(spec.name.clone(), Table::new(spec));
+    }
+
+    /// A client talking to this fake, without retries
+    pub fn client(&self) -> Client {
+        let state = self.state.clone();
+        let http_client = infallible_client_fn(move |request| handle(&state, request));
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::for_tests())
+            .retry_config(RetryConfig::disabled())
+            .http_client(http_client)
+            .build();
+        Client::from_conf(config)
+    }
+
+    /// Every item of `table` in the wire format, in insertion order
+    pub fn items(&self, table: &str) -> Vec<Item> {
+        let state = self.state.lock().unwrap();
+        state.tables.get(table).map(|table| table.items.clone()).unwrap_or_default()
+    }
+
+    /// Stores `item` as is, e.g. `json!({"username": {"S": "user1"}})`
+    pub fn insert(&self, table: &str, item: Value) {
+        let mut state = self.state.lock().unwrap();
+        let table = state.tables.get_mut(table).expect("no such table");
+        let item = item.as_object().expect("an item is a JSON object").clone();
+        let key = table.spec.key.key_of(&item).expect("the item lacks a key attribute");
+        match table.position(&key) {
+            Some(position) => table.items[position] = item,
+            None => table.items.push(item),
+        }
+    }
+
+    /// Fixes the clock used by `expire`, in seconds since the epoch
+    pub fn set_now(&self, now: i64) {
+        self.state.lock().unwrap().now = Some(now);
+    }
+
+    /// Deletes every item whose TTL attribute is in the past, like the TTL
+    /// sweeper does. Until then expired items are still read, as in DynamoDB.
+    /// Returns how many items were deleted.
+    pub fn expire(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let now = state.now.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
+        let mut expired = 0;
+        for table in state.tables.values_mut() {
+            let Some(ttl) = table.spec.ttl_attribute.clone() else { continue };
+            let before = table.items.len();
+            table.items.retain(|item| {
+                let expires = item.get(&ttl).and_then(|value| value["N"].as_str()).and_then(|n| n.parse::<f64>().ok());
+                !matches!(expires, Some(expires) if expires < now as f64)
+            });
+            expired += before - table.items.len();
+        }
+        expired
+    }
+
+    /// The next `calls` batch calls return all their requests unprocessed
+    /// Tables created or updated from now on report `CREATING` or `UPDATING`
+    /// for `describes` `DescribeTable` calls before they are `ACTIVE`
+    pub fn slow_changes(&self, describes: usize) {
+        self.state.lock().unwrap().change_describes = describes;
+    }
+
+    pub fn leave_unprocessed(&self, calls: usize) {
+        self.state.lock().unwrap().unprocessed = calls;
+    }
+
+    /// Every request received so far as (operation, JSON body)
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+/// A DynamoDB error response
+#[derive(Debug)]
+struct Fault {
+    status: u16,
+    kind: &'static str,
+    message: String,
+    extra: Item,
+}
+
+impl Fault {
+    fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Fault { status: 400, kind, message: message.into(), extra: Item::new() }
+    }
+
+    fn validation(message: impl Into<String>) -> Self {
+        Fault::new("ValidationException", message)
+    }
+
+    fn not_found() -> Self {
+        Fault::new("ResourceNotFoundException", "Requested resource not found")
+    }
+
+    fn condition_failed(old: Option<&Item>, request: &Value) -> Self {
+        let mut fault = Fault::new("ConditionalCheckFailedException", "The conditional request failed");
+        if let (Some(old), "ALL_OLD") = (old, request["ReturnValuesOnConditionCheckFailure"].as_str().unwrap_or_default()) {
+            fault.extra.insert("Item".to_string(), Value::Object(old.clone()));
+        }
+        fault
+    }
+
+    fn is_condition_failed(&self) -> bool {
+        self.kind == "ConditionalCheckFailedException"
+    }
+}
+
+fn handle(state: &Mutex<State>, request: http::Request<SdkBody>) -> http::Response<SdkBody> {
+    let operation = request
+        .headers()
+        .get("x-amz-target")
+        .and_then(|target| target.to_str().ok())
+        .and_then(|target| target.strip_prefix("DynamoDB_20120810."))
+        .unwrap_or_default()
+        .to_string();
+    let body: Value = request.body().bytes().and_then(|bytes| serde_json::from_slice(bytes).ok()).unwrap_or_else(|| json!({}));
+
+    let mut state = state.lock().unwrap();
+    state.requests.push((operation.clone(), body.clone()));
+
+    let result = match operation.as_str() {
+        "GetItem" => state.get_item(&body),
+        "PutItem" => state.put_item(&body),
+        "UpdateItem" => state.update_item(&body),
+        "DeleteItem" => state.delete_item(&body),
+        "Query" => state.query(&body),
+        "Scan" => state.scan(&body),
+        "BatchGetItem" => state.batch_get_item(&body),
+        "BatchWriteItem" => state.batch_write_item(&body),
+        "TransactWriteItems" => state.transact_write_items(&body),
+        "CreateTable" => state.create_table(&body),
+        "DescribeTable" => state.describe_table(&body),
+        "UpdateTable" => state.update_table(&body),
+        "DeleteTable" => state.delete_table(&body),
+        "ListTables" => Ok(state.list_tables()),
+        "UpdateTimeToLive" => state.update_time_to_live(&body),
+        "DescribeTimeToLive" => state.describe_time_to_live(&body),
+        _ => Err(Fault::new("UnknownOperationException", format!("{operation} is not supported by the fake"))),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(fault) => {
+            let mut body = fault.extra;
+            body.insert("__type".to_string(), json!(format!("com.amazonaws.dynamodb.v20120810#{}", fault.kind)));
+            body.insert("message".to_string(), json!(fault.message));
+            (fault.status, Value::Object(body))
+        }
+    };
+    http::Response::builder()
+        .status(status)
+        .header("content-type", "application/x-amz-json-1.0")
+        .body(SdkBody::from(body.to_string()))
+        .unwrap()
+}
+
+impl State {
+    fn table(&self, request: &Value) -> Result<&Table, Fault> {
+        request["TableName"].as_str().and_then(|name| self.tables.get(name)).ok_or_else(Fault::not_found)
+    }
+
+    fn table_mut(&mut self, request: &Value) -> Result<&mut Table, Fault> {
+        request["TableName"].as_str().and_then(|name| self.tables.get_mut(name)).ok_or_else(Fault::not_found)
+    }
+
+    fn get_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table(request)?;
+        let key = table.key(&request["Key"])?;
+        let projection = Projection::parse(request)?;
+        Ok(match table.position(&key) {
+            Some(position) => json!({"Item": projection.apply(&table.items[position])}),
+            None => json!({}),
+        })
+    }
+
+    fn put_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table_mut(request)?;
+        let item = request["Item"].as_object().cloned().ok_or_else(|| Fault::validation("Item is required"))?;
+        let key = table.spec.key.key_of(&item).ok_or_else(|| Fault::validation("One or more parameter values were invalid: Missing the key"))?;
+        let position = table.position(&key);
+        let old = position.map(|position| table.items[position].clone());
+        check_condition(request, old.as_ref())?;
+
+        match position {
+            Some(position) => table.items[position] = item,
+            None => table.items.push(item),
+        }
+        Ok(returned(request, old.as_ref(), None))
+    }
+
+    fn update_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table_mut(request)?;
+        let key = table.key(&request["Key"])?;
+        let position = table.position(&key);
+        let old = position.map(|position| table.items[position].clone());
+        check_condition(request, old.as_ref())?;
+
+        let mut new = old.clone().unwrap_or_else(|| key.clone());
+        let mut updated = Vec::new();
+        if let Some(expression) = request["UpdateExpression"].as_str() {
+            let names = Names::of(request);
+            let values = Values::of(request);
+            let before = new.clone();
+            for action in Parser::new(expression, &names)?.update()? {
+                if key.contains_key(action.attribute()) {
+                    return Err(Fault::validation(format!(
+                        "Cannot update attribute {}. This attribute is part of the key",
+                        action.attribute()
+                    )));
+                }
+                updated.push(action.attribute().to_string());
+                action.apply(&mut new, &before, &values)?;
+            }
+        }
+
+        match position {
+            Some(position) => table.items[position] = new.clone(),
+            None => table.items.push(new.clone()),
+        }
+        let updated_only = |item: &Item| -> Item { item.iter().filter(|(name, _)| updated.contains(name)).map(|(name, value)| (name.clone(), value.clone())).collect() };
+        Ok(match request["ReturnValues"].as_str().unwrap_or("NONE") {
+            "UPDATED_NEW" => json!({"Attributes": updated_only(&new)}),
+            "UPDATED_OLD" => json!({"Attributes": old.as_ref().map(updated_only).unwrap_or_default()}),
+            _ => returned(request, old.as_ref(), Some(&new)),
+        })
+    }
+
+    fn delete_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table_mut(request)?;
+        let key = table.key(&request["Key"])?;
+        let position = table.position(&key);
+        let old = position.map(|position| table.items[position].clone());
+        check_condition(request, old.as_ref())?;
+
+        if let Some(position) = position {
+            table.items.remove(position);
+        }
+        Ok(returned(request, old.as_ref(), None))
+    }
+
+    fn condition_check(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table(request)?;
+        let key = table.key(&request["Key"])?;
+        let old = table.position(&key).map(|position| &table.items[position]);
+        check_condition(request, old)?;
+        Ok(json!({}))
+    }
+
+    fn query(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table(request)?;
+        let schema = table.schema(request["IndexName"].as_str())?;
+        let names = Names::of(request);
+        let key_condition = request["KeyConditionExpression"]
+            .as_str()
+            .ok_or_else(|| Fault::validation("KeyConditionExpression is required"))?;
+        let key_condition = Parser::new(key_condition, &names)?.condition()?;
+        let values = Values::of(request);
+
+        let mut candidates = Vec::new();
+        for item in &table.items {
+            if schema.key_of(item).is_some() && key_condition.evaluate(item, &values)? {
+                candidates.push(item);
+            }
+        }
+        let forward = request["ScanIndexForward"].as_bool().unwrap_or(true);
+        page(table, schema, candidates, forward, request)
+    }
+
+    fn scan(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table(request)?;
+        let schema = table.schema(request["IndexName"].as_str())?;
+        let candidates = table.items.iter().filter(|item| schema.key_of(item).is_some()).collect();
+        page(table, schema, candidates, true, request)
+    }
+
+    fn batch_get_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let requests = request["RequestItems"].as_object().cloned().unwrap_or_default();
+        if self.unprocessed > 0 {
+            self.unprocessed -= 1;
+            return Ok(json!({"Responses": {}, "UnprocessedKeys": requests}));
+        }
+
+        let mut responses = Map::new();
+        for (name, keys_and_attributes) in &requests {
+            let table = self.tables.get(name).ok_or_else(Fault::not_found)?;
+            let projection = Projection::parse(keys_and_attributes)?;
+            let keys = keys_and_attributes["Keys"].as_array().into_iter().flatten().map(|key| table.key(key));
+            let keys = distinct_keys(keys.collect::<Result<_, _>>()?)?;
+            let mut found = Vec::new();
+            for key in keys {
+                if let Some(position) = table.position(&key) {
+                    found.push(Value::Object(projection.apply(&table.items[position])));
+                }
+            }
+            responses.insert(name.clone(), Value::Array(found));
+        }
+        Ok(json!({"Responses": responses, "UnprocessedKeys": {}}))
+    }
+
+    fn batch_write_item(&mut self, request: &Value) -> Result<Value, Fault> {
+        let requests = request["RequestItems"].as_object().cloned().unwrap_or_default();
+        if self.unprocessed > 0 {
+            self.unprocessed -= 1;
+            return Ok(json!({"UnprocessedItems": requests}));
+        }
+
+        for (name, writes) in &requests {
+            let table = self.tables.get(name).ok_or_else(Fault::not_found)?;
+            let keys = writes.as_array().into_iter().flatten().map(|write| match write.get("PutRequest") {
+                Some(put) => table.spec.key.key_of(put["Item"].as_object().unwrap_or(&Map::new())).ok_or_else(|| Fault::validation("Item is missing a key attribute")),
+                None => table.key(&write["DeleteRequest"]["Key"]),
+            });
+            distinct_keys(keys.collect::<Result<_, _>>()?)?;
+        }
+
+        for (name, writes) in &requests {
+            for write in writes.as_array().into_iter().flatten() {
+                if let Some(put) = write.get("PutRequest") {
+                    self.put_item(&json!({"TableName": name, "Item": put["Item"]}))?;
+                } else if let Some(delete) = write.get("DeleteRequest") {
+                    self.delete_item(&json!({"TableName": name, "Key": delete["Key"]}))?;
+                }
+            }
+        }
+        Ok(json!({"UnprocessedItems": {}}))
+    }
+
+    /// Applies every action to a copy of the tables, which replaces them only
+    /// if all conditions held
+    fn transact_write_items(&mut self, request: &Value) -> Result<Value, Fault> {
+        let actions = request["TransactItems"].as_array().cloned().unwrap_or_default();
+        if actions.is_empty() || actions.len() > 100 {
+            return Err(Fault::validation("TransactItems must have between 1 and 100 items"));
+        }
+
+        let mut scratch = State { tables: HashMap::new(), ..State::default() };
+        for (name, table) in &self.tables {
+            let mut copy = Table::new(table.spec.clone());
+            copy.items = table.items.clone();
+            scratch.tables.insert(name.clone(), copy);
+        }
+
+        let mut reasons = Vec::new();
+        for action in &actions {
+            let result = if let Some(put) = action.get("Put") {
+                scratch.put_item(put)
+            } else if let Some(update) = action.get("Update") {
+                scratch.update_item(update)
+            } else if let Some(delete) = action.get("Delete") {
+                scratch.delete_item(delete)
+            } else if let Some(check) = action.get("ConditionCheck") {
+                scratch.condition_check(check)
+            } else {
+                Err(Fault::validation("A TransactItem needs one of Put, Update, Delete or ConditionCheck"))
+            };
+            match result {
+                Ok(_) => reasons.push(json!({"Code": "None"})),
+                Err(fault) if fault.is_condition_failed() => {
+                    let mut reason = json!({"Code": "ConditionalCheckFailed", "Message": fault.message});
+                    if let Some(item) = fault.extra.get("Item") {
+                        reason["Item"] = item.clone();
+                    }
+                    reasons.push(reason);
+                }
+                Err(fault) => return Err(fault),
+            }
+        }
+
+        if reasons.iter().any(|reason| reason["Code"] != "None") {
+            let codes: Vec<&str> = reasons.iter().map(|reason| reason["Code"].as_str().unwrap_or_default()).collect();
+            let mut fault = Fault::new(
+                "TransactionCanceledException",
+                format!("Transaction cancelled, please refer cancellation reasons for specific reasons [{}]", codes.join(", ")),
+            );
+            fault.extra.insert("CancellationReasons".to_string(), Value::Array(reasons));
+            return Err(fault);
+        }
+
+        for (name, scratch_table) in scratch.tables {
+            if let Some(table) = self.tables.get_mut(&name) {
+                table.items = scratch_table.items;
+            }
+        }
+        Ok(json!({}))
+    }
+
+    fn create_table(&mut self, request: &Value) -> Result<Value, Fault> {
+        let name = request["TableName"].as_str().ok_or_else(|| Fault::validation("TableName is required"))?;
+        if self.tables.contains_key(name) {
+            return Err(Fault::new("ResourceInUseException", format!("Table already exists: {name}")));
+        }
+
+        let mut spec = TableSpec { name: name.to_string(), key: KeySchema::from_wire(&request["KeySchema"])?, indexes: Vec::new(), ttl_attribute: None };
+        let indexes = request["GlobalSecondaryIndexes"].as_array().into_iter().chain(request["LocalSecondaryIndexes"].as_array()).flatten();
+        for index in indexes {
+            let index_name = index["IndexName"].as_str().ok_or_else(|| Fault::validation("IndexName is required"))?;
+            spec.indexes.push((index_name.to_string(), KeySchema::from_wire(&index["KeySchema"])?));
+        }
+
+        let mut table = Table::new(spec);
+        table.define_attributes(request);
+        table.billing_mode = request["BillingMode"].as_str().unwrap_or("PROVISIONED").to_string();
+        table.throughput = request.get("ProvisionedThroughput").cloned();
+        table.stream = request.get("StreamSpecification").cloned();
+        table.change("CREATING", self.change_describes);
+        let description = table.describe();
+        self.tables.insert(name.to_string(), table);
+        Ok(json!({"TableDescription": description}))
+    }
+
+    fn describe_table(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table_mut(request)?;
+        let description = table.describe();
+        if table.pending > 0 {
+            table.pending -= 1;
+            if table.pending == 0 {
+                table.status = "ACTIVE";
+            }
+        }
+        Ok(json!({"Table": description}))
+    }
+
+    /// Billing mode, throughput, streams and one index created or deleted per call, as in DynamoDB
+    fn update_table(&mut self, request: &Value) -> Result<Value, Fault> {
+        let change_describes = self.change_describes;
+        let table = self.table_mut(request)?;
+        if table.status != "ACTIVE" {
+            return Err(Fault::new("ResourceInUseException", format!("Table is being changed: {}", table.spec.name)));
+        }
+
+        let updates = request["GlobalSecondaryIndexUpdates"].as_array().cloned().unwrap_or_default();
+        let creates_or_deletes = updates.iter().filter(|update| update.get("Create").is_some() || update.get("Delete").is_some()).count();
+        if creates_or_deletes > 1 {
+            return Err(Fault::new("LimitExceededException", "Only 1 online index can be created or deleted simultaneously per table"));
+        }
+        for update in &updates {
+            if let Some(create) = update.get("Create") {
+                let name = create["IndexName"].as_str().ok_or_else(|| Fault::validation("IndexName is required"))?;
+                if table.spec.indexes.iter().any(|(index, _)| index == name) {
+                    return Err(Fault::validation(format!("Attempting to create an index which already exists: {name}")));
+                }
+                table.spec.indexes.push((name.to_string(), KeySchema::from_wire(&create["KeySchema"])?));
+            } else if let Some(delete) = update.get("Delete") {
+                let name = delete["IndexName"].as_str().unwrap_or_default();
+                table.spec.indexes.retain(|(index, _)| index != name);
+            }
+        }
+
+        table.define_attributes(request);
+        if let Some(billing_mode) = request["BillingMode"].as_str() {
+            table.billing_mode = billing_mode.to_string();
+            if billing_mode == "PAY_PER_REQUEST" {
+                table.throughput = None;
+            }
+        }
+        if let Some(throughput) = request.get("ProvisionedThroughput") {
+            table.throughput = Some(throughput.clone());
+        }
+        if let Some(stream) = request.get("StreamSpecification") {
+            table.stream = Some(stream.clone()).filter(|stream| stream["StreamEnabled"] == true);
+        }
+        table.change("UPDATING", change_describes);
+        Ok(json!({"TableDescription": table.describe()}))
+    }
+
+    fn delete_table(&mut self, request: &Value) -> Result<Value, Fault> {
+        let description = self.table(request)?.describe();
+        self.tables.remove(request["TableName"].as_str().unwrap_or_default());
+        Ok(json!({"TableDescription": description}))
+    }
+
+    fn list_tables(&self) -> Value {
+        let mut names: Vec<&String> = self.tables.keys().collect();
+        names.sort();
+        json!({"TableNames": names})
+    }
+
+    fn update_time_to_live(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table_mut(request)?;
+        let specification = &request["TimeToLiveSpecification"];
+        table.spec.ttl_attribute = match specification["Enabled"].as_bool() {
+            Some(true) => specification["AttributeName"].as_str().map(str::to_string),
+            _ => None,
+        };
+        Ok(json!({"TimeToLiveSpecification": specification}))
+    }
+
+    fn describe_time_to_live(&mut self, request: &Value) -> Result<Value, Fault> {
+        let table = self.table(request)?;
+        Ok(match &table.spec.ttl_attribute {
+            Some(attribute) => json!({"TimeToLiveDescription": {"TimeToLiveStatus": "ENABLED", "AttributeName": attribute}}),
+            None => json!({"TimeToLiveDescription": {"TimeToLiveStatus": "DISABLED"}}),
+        })
+    }
+}
+
+/// Fails with `ConditionalCheckFailedException` unless the request's
+/// `ConditionExpression` holds for the stored item
+/// DynamoDB rejects a batch that reads or writes one key twice
+fn distinct_keys(keys: Vec<Item>) -> Result<Vec<Item>, Fault> {
+    for (n, key) in keys.iter().enumerate() {
+        if keys[..n].contains(key) {
+            return Err(Fault::validation("Provided list of item keys contains duplicates"));
+        }
+    }
+    Ok(keys)
+}
+
+fn check_condition(request: &Value, old: Option<&Item>) -> Result<(), Fault> {
+    let Some(expression) = request["ConditionExpression"].as_str() else {
+        return Ok(());
+    };
+    let names = Names::of(request);
+    let condition = Parser::new(expression, &names)?.condition()?;
+    let empty = Item::new();
+    if condition.evaluate(old.unwrap_or(&empty), &Values::of(request))? {
+        Ok(())
+    } else {
+        Err(Fault::condition_failed(old, request))
+    }
+}
+
+/// The `Attributes` of a write response for its `ReturnValues`
+fn returned(request: &Value, old: Option<&Item>, new: Option<&Item>) -> Value {
+    let attributes = match request["ReturnValues"].as_str().unwrap_or("NONE") {
+        "ALL_OLD" => old,
+        "ALL_NEW" => new,
+        _ => None,
+    };
+    match attributes {
+        Some(attributes) => json!({"Attributes": attributes}),
+        None => json!({}),
+    }
+}
+
+/// One page of a query or scan: `Limit` counts items read before the
+/// filter, and the page continues after `ExclusiveStartKey`
+fn page(table: &Table, schema: &KeySchema, mut candidates: Vec<&Item>, forward: bool, request: &Value) -> Result<Value, Fault> {
+    let order = |item: &Item| -> Vec<Option<Value>> {
+        let attributes = schema.range.iter().chain(std::iter::once(&table.spec.key.hash)).chain(table.spec.key.range.iter());
+        attributes.map(|name| item.get(name).cloned()).collect()
+    };
+    candidates.sort_by(|a, b| compare_keys(&order(a), &order(b)));
+    if !forward {
+        candidates.reverse();
+    }
+
+    if let Some(start) = request["ExclusiveStartKey"].as_object() {
+        let start = order(start);
+        candidates.retain(|item| {
+            let ordering = compare_keys(&order(item), &start);
+            if forward { ordering == Ordering::Greater } else { ordering == Ordering::Less }
+        });
+    }
+
+    let limit = request["Limit"].as_u64().map(|limit| limit as usize).unwrap_or(usize::MAX);
+    let more = candidates.len() > limit;
+    candidates.truncate(limit);
+
+    let names = Names::of(request);
+    let values = Values::of(request);
+    let filter = request["FilterExpression"].as_str().map(|filter| Parser::new(filter, &names)?.condition()).transpose()?;
+    let projection = Projection::parse(request)?;
+
+    let mut items = Vec::new();
+    for item in &candidates {
+        if filter.as_ref().map(|filter| filter.evaluate(item, &values)).transpose()?.unwrap_or(true) {
+            items.push(Value::Object(projection.apply(item)));
+        }
+    }
+
+    let mut response = json!({"Count": items.len(), "ScannedCount": candidates.len()});
+    if request["Select"] != "COUNT" {
+        response["Items"] = Value::Array(items);
+    }
+    if let (true, Some(last)) = (more, candidates.last()) {
+        let mut key = table.spec.key.key_of(last).unwrap_or_default();
+        key.extend(schema.key_of(last).unwrap_or_default());
+        response["LastEvaluatedKey"] = Value::Object(key);
+    }
+    Ok(response)
+}
+
+fn compare_keys(a: &[Option<Value>], b: &[Option<Value>]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| match (a, b) {
+            (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+            _ => Ordering::Equal,
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Orders two scalars of the same type, `None` for anything else
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (number(a), number(b)) {
+        return a.partial_cmp(&b);
+    }
+    ["S", "B"].iter().find_map(|kind| match (a.get(kind), b.get(kind)) {
+        (Some(Value::String(a)), Some(Value::String(b))) => Some(a.cmp(b)),
+        _ => None,
+    })
+}
+
+fn number(value: &Value) -> Option<f64> {
+    value.get("N")?.as_str()?.parse().ok()
+}
+
+/// Renders a number the way DynamoDB would, integers without a fraction
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        n.to_string()
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    compare(a, b).map(Ordering::is_eq).unwrap_or(a == b)
+}
+
+/// `ExpressionAttributeNames` of a request
+struct Names(Map<String, Value>);
+
+impl Names {
+    fn of(request: &Value) -> Self {
+        Names(request["ExpressionAttributeNames"].as_object().cloned().unwrap_or_default())
+    }
+
+    fn resolve(&self, token: &str) -> Result<String, Fault> {
+        if token.starts_with('#') {
+            self.0
+                .get(token)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| Fault::validation(format!("An expression attribute name used in the document path is not defined; attribute name: {token}")))
+        } else {
+            Ok(token.to_string())
+        }
+    }
+}
+
+/// `ExpressionAttributeValues` of a request
+struct Values(Map<String, Value>);
+
+impl Values {
+    fn of(request: &Value) -> Self {
+        Values(request["ExpressionAttributeValues"].as_object().cloned().unwrap_or_default())
+    }
+
+    fn get(&self, token: &str) -> Result<&Value, Fault> {
+        self.0
+            .get(token)
+            .ok_or_else(|| Fault::validation(format!("An expression attribute value used in expression is not defined; attribute value: {token}")))
+    }
+}
+
+/// `ProjectionExpression` of a request, all attributes when absent
+struct Projection(Option<Vec<String>>);
+
+impl Projection {
+    fn parse(request: &Value) -> Result<Self, Fault> {
+        let Some(expression) = request["ProjectionExpression"].as_str() else {
+            return Ok(Projection(None));
+        };
+        let names = Names::of(request);
+        let attributes = expression.split(',').map(|token| names.resolve(token.trim())).collect::<Result<_, _>>()?;
+        Ok(Projection(Some(attributes)))
+    }
+
+    fn apply(&self, item: &Item) -> Item {
+        match &self.0 {
+            None => item.clone(),
+            Some(attributes) => item.iter().filter(|(name, _)| attributes.contains(name)).map(|(name, value)| (name.clone(), value.clone())).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// An attribute name, either plain or a resolved `#name`
+    Name(String),
+    /// A `:value` placeholder
+    Value(String),
+    /// A keyword or function name, e.g. `AND` or `begins_with`
+    Word(String),
+    Comparator(&'static str),
+    Plus,
+    Minus,
+    Open,
+    Close,
+    Comma,
+}
+
+const KEYWORDS: &[&str] = &["AND", "OR", "NOT", "BETWEEN", "IN", "SET", "REMOVE", "ADD", "DELETE"];
+const FUNCTIONS: &[&str] = &["attribute_exists", "attribute_not_exists", "begins_with", "contains", "if_not_exists", "list_append", "size"];
+
+fn tokenize(expression: &str, names: &Names) -> Result<Vec<Token>, Fault> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let word = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '#' || c == ':' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            word
+        };
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | '+' | '-' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    ',' => Token::Comma,
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    _ => Token::Comparator("="),
+                });
+            }
+            '<' | '>' => {
+                chars.next();
+                let comparator = match (c, chars.peek()) {
+                    ('<', Some('=')) => "<=",
+                    ('<', Some('>')) => "<>",
+                    ('>', Some('=')) => ">=",
+                    ('<', _) => "<",
+                    _ => ">",
+                };
+                if comparator.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Comparator(comparator));
+            }
+            '#' | ':' => {
+                let token = word(&mut chars);
+                tokens.push(if c == ':' { Token::Value(token) } else { Token::Name(names.resolve(&token)?) });
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let token = word(&mut chars);
+                let keyword = KEYWORDS.iter().find(|keyword| keyword.eq_ignore_ascii_case(&token));
+                tokens.push(match keyword {
+                    Some(keyword) => Token::Word(keyword.to_string()),
+                    None if FUNCTIONS.contains(&token.as_str()) => Token::Word(token),
+                    None => Token::Name(token),
+                });
+            }
+            _ => return Err(Fault::validation(format!("Invalid expression: unexpected {c:?} in {expression}"))),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Operand {
+    Path(String),
+    Value(String),
+    Size(String),
+    IfNotExists(String, Box<Operand>),
+    ListAppend(Box<Operand>, Box<Operand>),
+    Plus(Box<Operand>, Box<Operand>),
+    Minus(Box<Operand>, Box<Operand>),
+}
+
+impl Operand {
+    fn evaluate(&self, item: &Item, values: &Values) -> Result<Option<Value>, Fault> {
+        Ok(match self {
+            Operand::Path(name) => item.get(name).cloned(),
+            Operand::Value(token) => Some(values.get(token)?.clone()),
+            Operand::Size(name) => item.get(name).map(|value| {
+                let size = match value.as_object().and_then(|value| value.iter().next()) {
+                    Some((_, Value::String(s))) => s.len(),
+                    Some((_, Value::Array(elements))) => elements.len(),
+                    Some((_, Value::Object(entries))) => entries.len(),
+                    _ => 0,
+                };
+                json!({"N": size.to_string()})
+            }),
+            Operand::IfNotExists(name, default) => match item.get(name) {
+                Some(value) => Some(value.clone()),
+                None => default.evaluate(item, values)?,
+            },
+            Operand::ListAppend(a, b) => {
+                let list = |operand: &Operand| -> Result<Vec<Value>, Fault> {
+                    let value = operand.evaluate(item, values)?;
+                    value.and_then(|value| value["L"].as_array().cloned()).ok_or_else(|| Fault::validation("list_append needs two lists"))
+                };
+                let mut appended = list(a)?;
+                appended.extend(list(b)?);
+                Some(json!({"L": appended}))
+            }
+            Operand::Plus(a, b) | Operand::Minus(a, b) => {
+                let operand = |operand: &Operand| -> Result<f64, Fault> {
+                    operand
+                        .evaluate(item, values)?
+                        .as_ref()
+                        .and_then(number)
+                        .ok_or_else(|| Fault::validation("An operand in the update expression has an incorrect data type"))
+                };
+                let (a, b) = (operand(a)?, operand(b)?);
+                let n = if matches!(self, Operand::Plus(..)) { a + b } else { a - b };
+                Some(json!({"N": format_number(n)}))
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    Compare(Operand, &'static str, Operand),
+    Between(Operand, Operand, Operand),
+    In(Operand, Vec<Operand>),
+    Exists(String),
+    NotExists(String),
+    BeginsWith(Operand, Operand),
+    Contains(Operand, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    fn evaluate(&self, item: &Item, values: &Values) -> Result<bool, Fault> {
+        Ok(match self {
+            Condition::Compare(a, comparator, b) => {
+                let (a, b) = (a.evaluate(item, values)?, b.evaluate(item, values)?);
+                let (Some(a), Some(b)) = (a, b) else {
+                    return Ok(*comparator == "<>");
+                };
+                match *comparator {
+                    "=" => equal(&a, &b),
+                    "<>" => !equal(&a, &b),
+                    comparator => match compare(&a, &b) {
+                        Some(ordering) => match comparator {
+                            "<" => ordering.is_lt(),
+                            "<=" => ordering.is_le(),
+                            ">" => ordering.is_gt(),
+                            _ => ordering.is_ge(),
+                        },
+                        None => false,
+                    },
+                }
+            }
+            Condition::Between(value, low, high) => {
+                match (value.evaluate(item, values)?, low.evaluate(item, values)?, high.evaluate(item, values)?) {
+                    (Some(value), Some(low), Some(high)) => {
+                        matches!(compare(&value, &low), Some(ordering) if ordering.is_ge()) && matches!(compare(&value, &high), Some(ordering) if ordering.is_le())
+                    }
+                    _ => false,
+                }
+            }
+            Condition::In(value, candidates) => match value.evaluate(item, values)? {
+                Some(value) => {
+                    let mut found = false;
+                    for candidate in candidates {
+                        found |= candidate.evaluate(item, values)?.is_some_and(|candidate| equal(&value, &candidate));
+                    }
+                    found
+                }
+                None => false,
+            },
+            Condition::Exists(name) => item.contains_key(name),
+            Condition::NotExists(name) => !item.contains_key(name),
+            Condition::BeginsWith(value, prefix) => match (value.evaluate(item, values)?, prefix.evaluate(item, values)?) {
+                (Some(value), Some(prefix)) => match (value["S"].as_str(), prefix["S"].as_str()) {
+                    (Some(value), Some(prefix)) => value.starts_with(prefix),
+                    _ => false,
+                },
+                _ => false,
+            },
+            Condition::Contains(value, element) => match (value.evaluate(item, values)?, element.evaluate(item, values)?) {
+                (Some(value), Some(element)) => match (value.as_object().and_then(|value| value.iter().next()), element["S"].as_str()) {
+                    (Some((kind, Value::String(s))), Some(needle)) if kind == "S" => s.contains(needle),
+                    (Some((_, Value::Array(elements))), _) => {
+                        let scalar = element.as_object().and_then(|element| element.values().next());
+                        elements.iter().any(|candidate| equal(candidate, &element) || Some(candidate) == scalar)
+                    }
+                    _ => false,
+                },
+                _ => false,
+            },
+            Condition::And(a, b) => a.evaluate(item, values)? && b.evaluate(item, values)?,
+            Condition::Or(a, b) => a.evaluate(item, values)? || b.evaluate(item, values)?,
+            Condition::Not(condition) => !condition.evaluate(item, values)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Action {
+    Set(String, Operand),
+    Remove(String),
+    Add(String, Operand),
+    Delete(String, Operand),
+}
+
+impl Action {
+    fn attribute(&self) -> &str {
+        match self {
+            Action::Set(name, _) | Action::Remove(name) | Action::Add(name, _) | Action::Delete(name, _) => name,
+        }
+    }
+
+    /// Applies the action to `item`, operands read the item as it was before the update
+    fn apply(self, item: &mut Item, before: &Item, values: &Values) -> Result<(), Fault> {
+        let missing = || Fault::validation("The provided expression refers to an attribute that does not exist in the item");
+        match self {
+            Action::Set(name, value) => {
+                let value = value.evaluate(before, values)?.ok_or_else(missing)?;
+                item.insert(name, value);
+            }
+            Action::Remove(name) => {
+                item.remove(&name);
+            }
+            Action::Add(name, value) => {
+                let value = value.evaluate(before, values)?.ok_or_else(missing)?;
+                let added = match item.get(&name) {
+                    None => value,
+                    Some(current) => match (number(current), number(&value)) {
+                        (Some(a), Some(b)) => json!({"N": format_number(a + b)}),
+                        _ => {
+                            let (kind, mut elements, added) = same_sets(current, &value)?;
+                            elements.extend(added.into_iter().filter(|element| !elements.contains(element)).collect::<Vec<_>>());
+                            json!({ kind: elements })
+                        }
+                    },
+                };
+                item.insert(name, added);
+            }
+            Action::Delete(name, value) => {
+                let value = value.evaluate(before, values)?.ok_or_else(missing)?;
+                if let Some(current) = item.get(&name) {
+                    let (kind, mut elements, deleted) = same_sets(current, &value)?;
+                    elements.retain(|element| !deleted.contains(element));
+                    if elements.is_empty() {
+                        item.remove(&name);
+                    } else {
+                        item.insert(name, json!({ kind: elements }));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The set type and elements of two sets of the same type
+fn same_sets(a: &Value, b: &Value) -> Result<(&'static str, Vec<Value>, Vec<Value>), Fault> {
+    for kind in ["SS", "NS", "BS"] {
+        if let (Some(Value::Array(a)), Some(Value::Array(b))) = (a.get(kind), b.get(kind)) {
+            return Ok((kind, a.clone(), b.clone()));
+        }
+    }
+    Err(Fault::validation("An operand in the update expression has an incorrect data type"))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    expression: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(expression: &'a str, names: &Names) -> Result<Self, Fault> {
+        Ok(Parser { tokens: tokenize(expression, names)?, position: 0, expression })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn invalid(&self) -> Fault {
+        Fault::validation(format!("Invalid expression: syntax error near token {} of {}", self.position + 1, self.expression))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), Fault> {
+        if self.next() == Some(token) { Ok(()) } else { Err(self.invalid()) }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Word(word)) if word == keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn end(&self) -> Result<(), Fault> {
+        if self.position == self.tokens.len() { Ok(()) } else { Err(self.invalid()) }
+    }
+
+    fn condition(mut self) -> Result<Condition, Fault> {
+        let condition = self.or()?;
+        self.end()?;
+        Ok(condition)
+    }
+
+    fn or(&mut self) -> Result<Condition, Fault> {
+        let mut condition = self.and()?;
+        while self.keyword("OR") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, Fault> {
+        let mut condition = self.not()?;
+        while self.keyword("AND") {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, Fault> {
+        if self.keyword("NOT") {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Condition, Fault> {
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            let condition = self.or()?;
+            self.expect(Token::Close)?;
+            return Ok(condition);
+        }
+
+        if let Some(Token::Word(function)) = self.peek().cloned() {
+            let condition = match function.as_str() {
+                "attribute_exists" | "attribute_not_exists" | "begins_with" | "contains" => {
+                    self.next();
+                    self.expect(Token::Open)?;
+                    let first = self.operand()?;
+                    let condition = match (function.as_str(), first) {
+                        ("attribute_exists", Operand::Path(name)) => Condition::Exists(name),
+                        ("attribute_not_exists", Operand::Path(name)) => Condition::NotExists(name),
+                        ("begins_with", first) => {
+                            self.expect(Token::Comma)?;
+                            Condition::BeginsWith(first, self.operand()?)
+                        }
+                        ("contains", first) => {
+                            self.expect(Token::Comma)?;
+                            Condition::Contains(first, self.operand()?)
+                        }
+                        _ => return Err(self.invalid()),
+                    };
+                    self.expect(Token::Close)?;
+                    Some(condition)
+                }
+                _ => None,
+            };
+            if let Some(condition) = condition {
+                return Ok(condition);
+            }
+        }
+
+        let left = self.operand()?;
+        match self.next() {
+            Some(Token::Comparator(comparator)) => Ok(Condition::Compare(left, comparator, self.operand()?)),
+            Some(Token::Word(word)) if word == "BETWEEN" => {
+                let low = self.operand()?;
+                if !self.keyword("AND") {
+                    return Err(self.invalid());
+                }
+                Ok(Condition::Between(left, low, self.operand()?))
+            }
+            Some(Token::Word(word)) if word == "IN" => {
+                self.expect(Token::Open)?;
+                let mut candidates = vec![self.operand()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.next();
+                    candidates.push(self.operand()?);
+                }
+                self.expect(Token::Close)?;
+                Ok(Condition::In(left, candidates))
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn path(&mut self) -> Result<String, Fault> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, Fault> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(Operand::Path(name)),
+            Some(Token::Value(token)) => Ok(Operand::Value(token)),
+            Some(Token::Word(function)) if function == "size" => {
+                self.expect(Token::Open)?;
+                let name = self.path()?;
+                self.expect(Token::Close)?;
+                Ok(Operand::Size(name))
+            }
+            Some(Token::Word(function)) if function == "if_not_exists" => {
+                self.expect(Token::Open)?;
+                let name = self.path()?;
+                self.expect(Token::Comma)?;
+                let default = self.operand()?;
+                self.expect(Token::Close)?;
+                Ok(Operand::IfNotExists(name, Box::new(default)))
+            }
+            Some(Token::Word(function)) if function == "list_append" => {
+                self.expect(Token::Open)?;
+                let a = self.operand()?;
+                self.expect(Token::Comma)?;
+                let b = self.operand()?;
+                self.expect(Token::Close)?;
+                Ok(Operand::ListAppend(Box::new(a), Box::new(b)))
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    /// `SET a = b [+|- c], ...`, `REMOVE a, ...`, `ADD a :v, ...` and
+    /// `DELETE a :v, ...` clauses in any order
+    fn update(mut self) -> Result<Vec<Action>, Fault> {
+        let mut actions = Vec::new();
+        while let Some(token) = self.next() {
+            let Token::Word(clause) = token else {
+                return Err(self.invalid());
+            };
+            loop {
+                let name = self.path()?;
+                actions.push(match clause.as_str() {
+                    "SET" => {
+                        self.expect(Token::Comparator("="))?;
+                        let value = self.operand()?;
+                        let value = match self.peek() {
+                            Some(Token::Plus) => {
+                                self.next();
+                                Operand::Plus(Box::new(value), Box::new(self.operand()?))
+                            }
+                            Some(Token::Minus) => {
+                                self.next();
+                                Operand::Minus(Box::new(value), Box::new(self.operand()?))
+                            }
+                            _ => value,
+                        };
+                        Action::Set(name, value)
+                    }
+                    "REMOVE" => Action::Remove(name),
+                    "ADD" => Action::Add(name, self.operand()?),
+                    "DELETE" => Action::Delete(name, self.operand()?),
+                    _ => return Err(self.invalid()),
+                });
+                if self.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                self.next();
+            }
+        }
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue, ReturnValuesOnConditionCheckFailure};
+    use super::*;
+
+    fn s(value: &str) -> AttributeValue {
+        AttributeValue::S(value.to_string())
+    }
+
+    fn n(value: i64) -> AttributeValue {
+        AttributeValue::N(value.to_string())
+    }
+
+    fn order(fake: &FakeDynamo, user: &str, date: &str) {
+        fake.insert(
+            "UserTable",
+            json!({"UserId": {"S": user}, "OrderId": {"S": format!("o#{date}")}, "gsi_pk": {"N": "1"}, "date_ordered": {"S": date}}),
+        );
+    }
+
+    #[tokio::test]
+    async fn query_pages_through_gsi1() {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        for (user, date) in [("u#a", "2024-01-03"), ("u#b", "2024-01-01"), ("u#a", "2024-01-04"), ("u#c", "2024-01-02")] {
+            order(&fake, user, date);
+        }
+        // Not in gsi1
+        fake.insert("UserTable", json!({"UserId": {"S": "u#d"}, "OrderId": {"S": "o#x"}}));
+        let client = fake.client();
+
+        let mut dates = Vec::new();
+        let mut start = None;
+        loop {
+            let page = client
+                .query()
+                .table_name("UserTable")
+                .index_name("gsi1")
+                .key_condition_expression("#pk = :pk AND #sk BETWEEN :from AND :to")
+                .expression_attribute_names("#pk", "gsi_pk")
+                .expression_attribute_names("#sk", "date_ordered")
+                .expression_attribute_values(":pk", n(1))
+                .expression_attribute_values(":from", s("2024-01-02"))
+                .expression_attribute_values(":to", s("2024-01-04"))
+                .scan_index_forward(false)
+                .limit(2)
+                .set_exclusive_start_key(start)
+                .send()
+                .await
+                .unwrap();
+            dates.extend(page.items().iter().map(|item| item["date_ordered"].as_s().unwrap().clone()));
+            start = page.last_evaluated_key;
+            if let Some(key) = &start {
+                assert_eq!(key.len(), 4, "table and index key attributes");
+            } else {
+                break;
+            }
+        }
+        assert_eq!(dates, ["2024-01-04", "2024-01-03", "2024-01-02"]);
+    }
+
+    #[tokio::test]
+    async fn update_applies_set_remove_add_under_a_condition() {
+        let fake = FakeDynamo::new().with_table(TableSpec::items());
+        let client = fake.client();
+        client.put_item().table_name("lambda_dynamo_2").item("username", s("user1")).item("age", s("30")).item("visits", n(1)).send().await.unwrap();
+
+        let updated = client
+            .update_item()
+            .table_name("lambda_dynamo_2")
+            .key("username", s("user1"))
+            .update_expression("SET #first = :first, #total = #visits + :one REMOVE age ADD #visits :one")
+            .condition_expression("attribute_exists(username) AND NOT #visits > :one")
+            .expression_attribute_names("#first", "first_name")
+            .expression_attribute_names("#visits", "visits")
+            .expression_attribute_names("#total", "total")
+            .expression_attribute_values(":first", s("Ada"))
+            .expression_attribute_values(":one", n(1))
+            .return_values(ReturnValue::AllNew)
+            .send()
+            .await
+            .unwrap();
+        let attributes = updated.attributes.unwrap();
+        assert_eq!(attributes["first_name"], s("Ada"));
+        assert_eq!(attributes["visits"], n(2));
+        assert_eq!(attributes["total"], n(2));
+        assert!(!attributes.contains_key("age"));
+
+        // visits is now 2
+        let err = client
+            .update_item()
+            .table_name("lambda_dynamo_2")
+            .key("username", s("user1"))
+            .update_expression("ADD visits :one")
+            .condition_expression("visits <= :one")
+            .expression_attribute_values(":one", n(1))
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .send()
+            .await
+            .unwrap_err()
+            .into_service_error();
+        let aws_sdk_dynamodb::operation::update_item::UpdateItemError::ConditionalCheckFailedException(e) = err else {
+            panic!("expected a conditional check failure, got {err:?}");
+        };
+        assert_eq!(e.item().unwrap()["visits"], n(2));
+    }
+
+    #[tokio::test]
+    async fn expire_deletes_items_past_their_ttl() {
+        let fake = FakeDynamo::new().with_table(TableSpec::sessions());
+        fake.set_now(1_000);
+        fake.insert("SessionStore", json!({"session_token": {"S": "old"}, "ttl": {"N": "999"}}));
+        fake.insert("SessionStore", json!({"session_token": {"S": "new"}, "ttl": {"N": "1001"}}));
+        fake.insert("SessionStore", json!({"session_token": {"S": "forever"}}));
+
+        // Expired items are read until the sweeper deletes them
+        let client = fake.client();
+        let old = client.get_item().table_name("SessionStore").key("session_token", s("old")).send().await.unwrap();
+        assert!(old.item.is_some());
+
+        assert_eq!(fake.expire(), 1);
+        let scan = client.scan().table_name("SessionStore").send().await.unwrap();
+        assert_eq!(scan.count, 2);
+
+        let ttl = client.describe_time_to_live().table_name("SessionStore").send().await.unwrap();
+        assert_eq!(ttl.time_to_live_description.unwrap().attribute_name(), Some("ttl"));
+    }
+
+    #[tokio::test]
+    async fn batch_write_can_leave_items_unprocessed() {
+        let fake = FakeDynamo::new().with_table(TableSpec::items());
+        let client = fake.client();
+        let put = |username: &str| {
+            let put = aws_sdk_dynamodb::types::PutRequest::builder().item("username", s(username)).build().unwrap();
+            aws_sdk_dynamodb::types::WriteRequest::builder().put_request(put).build()
+        };
+
+        fake.leave_unprocessed(1);
+        let output = client.batch_write_item().request_items("lambda_dynamo_2", vec![put("a"), put("b")]).send().await.unwrap();
+        let unprocessed = output.unprocessed_items.unwrap();
+        assert_eq!(unprocessed["lambda_dynamo_2"].len(), 2);
+        assert!(fake.items("lambda_dynamo_2").is_empty());
+
+        let output = client.batch_write_item().set_request_items(Some(unprocessed)).send().await.unwrap();
+        assert!(output.unprocessed_items.unwrap().is_empty());
+        assert_eq!(fake.items("lambda_dynamo_2").len(), 2);
+    }
+}
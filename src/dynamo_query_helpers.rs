@@ -2,6 +2,7 @@
 #![allow(unused)]
 
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
 use aws_sdk_dynamodb::{Client, Error};
 use aws_sdk_dynamodb::error::SdkError;
@@ -12,139 +13,574 @@ use base64::Engine;
 use serde::{de, Deserialize, Serialize};
 use serde_dynamo::aws_sdk_dynamodb_1::{from_items, to_item};
 use serde_json::json;
-use base64::{decode};
 use serde_json::from_str;
 use anyhow::{anyhow, Context, Result};
 
-
-use base64::{encode};
+use crate::config::TableNames;
 use crate::dynamo::DynamoError;
-use crate::dynamo::DynamoError::DynError;
+use crate::repository::{create_item, unversioned, Condition, DynamoEntity, Update, UpdateRequest};
 
 
+/// A pagination token the client sent back could not be used
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidPageToken {
+    /// Not a token we issued, tampered with, or issued for another query
+    #[error("Invalid pagination token")]
+    Invalid,
+    #[error("Pagination token expired")]
+    Expired,
+}
 
-fn handle_error<E>(e: E) -> DynamoError
-where
-    E: std::fmt::Display,
-{
-    DynamoError::DynErrorExp {exp: e.to_string()}
+/// Version byte of the current token format
+const PAGE_TOKEN_VERSION: u8 = 1;
+
+/// Seals the last evaluated key of a query into the opaque `app-token` the client
+/// sends back for the next page, and opens it again.
+///
+/// Tokens are AES-256-GCM encrypted with a key derived from `pagination.secret`,
+/// so clients can neither read nor edit the key. Each token carries a format
+/// version and an expiry, and is bound to the query it came from: the query
+/// description (table, index, sort direction, filters) is authenticated along
+/// with it, so a token only opens for the same query.
+///
+/// Token layout, URL-safe base64 without padding: version (1 byte), nonce (12 bytes),
+/// ciphertext of `{"exp":..,"key":..}` and tag.
+#[derive(Clone)]
+pub struct PageTokens {
+    key: ring::aead::LessSafeKey,
+    lifetime: chrono::Duration,
 }
 
-/// Coverts a Base64 key sent via the REST query into a
-/// type and then into a HashMap<String, AttributeValue>
-/// readable by DynamoDB
-pub fn get_last_evaluated_key<T>(paginator_token: &str)
-    -> Result<HashMap<String, AttributeValue>, anyhow::Error>
-where
-    T: serde::de::DeserializeOwned + Serialize
-{
-    let json_key = decode_base64_to_json(paginator_token)?;
-    // Get UserTableKey
-    let last_key_struct_from_json =
-        serde_json::from_str::<T>(json_key.as_str())?;
-    // Convert key to the Dynamo HashTable<String, AttributeValue> format
-    let last_evaluated_key =
-        serde_dynamo::to_item(last_key_struct_from_json)?;
-    Ok(last_evaluated_key)
+#[derive(Serialize, serde::Deserialize)]
+struct PageTokenPayload<T> {
+    /// Unix time after which the token is rejected
+    exp: i64,
+    key: T,
 }
 
-/// Creates a Base64 version of the last evaluated key from dynamo HashMap<String, AttributeValue>
-pub fn generate_evaluated_key_base64<T> (last_evaluated_key: HashMap<String, AttributeValue>)
-                                                -> Result<String, anyhow::Error>
-   where T: Serialize + serde::de::DeserializeOwned
-{
-    let last_table_key: T = serde_dynamo::aws_sdk_dynamodb_1::from_item(last_evaluated_key)?;
-    let last_evaluated_key_json = json!(last_table_key).to_string();
-    let last_evaluated_key_base64 = encode(&last_evaluated_key_json);
-    Ok(last_evaluated_key_base64)
+impl PageTokens {
+    pub fn new(secret: &str, lifetime_minutes: i64) -> Self {
+        use sha2::Digest;
+        let key_bytes = sha2::Sha256::new()
+            .chain_update(b"cargo-lambda-axum page token\0")
+            .chain_update(secret.as_bytes())
+            .finalize();
+        let key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, &key_bytes)
+            .expect("a SHA-256 digest is a valid AES-256 key");
+        PageTokens {
+            key: ring::aead::LessSafeKey::new(key),
+            lifetime: chrono::Duration::minutes(lifetime_minutes),
+        }
+    }
+
+    /// Seals a last evaluated key, read as `T` to only keep the key attributes
+    pub fn encode<T>(&self, query: &str, last_evaluated_key: HashMap<String, AttributeValue>) -> Result<String, DynamoError>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let key: T = serde_dynamo::aws_sdk_dynamodb_1::from_item(last_evaluated_key)?;
+        let payload = PageTokenPayload { exp: (chrono::Utc::now() + self.lifetime).timestamp(), key };
+        let mut sealed = serde_json::to_vec(&payload).expect("a DynamoDB key serializes to JSON");
+
+        let mut nonce = [0u8; ring::aead::NONCE_LEN];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut nonce)
+            .expect("the system random number generator is available");
+        self.key
+            .seal_in_place_append_tag(ring::aead::Nonce::assume_unique_for_key(nonce), aad(query), &mut sealed)
+            .expect("a page token is far below the AES-GCM size limit");
+
+        let token = [&[PAGE_TOKEN_VERSION][..], &nonce, &sealed].concat();
+        Ok(base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(token))
+    }
+
+    /// Opens a token issued by `encode` for the same `query` and returns the
+    /// key to start the query from
+    pub fn decode<T>(&self, query: &str, token: &str) -> Result<HashMap<String, AttributeValue>, InvalidPageToken>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let token = base64::prelude::BASE64_URL_SAFE_NO_PAD
+            .decode(token)
+            .map_err(|_| InvalidPageToken::Invalid)?;
+        let (version, rest) = token.split_first().ok_or(InvalidPageToken::Invalid)?;
+        if *version != PAGE_TOKEN_VERSION || rest.len() < ring::aead::NONCE_LEN {
+            return Err(InvalidPageToken::Invalid);
+        }
+
+        let (nonce, sealed) = rest.split_at(ring::aead::NONCE_LEN);
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| InvalidPageToken::Invalid)?;
+        let mut sealed = sealed.to_vec();
+        let payload = self
+            .key
+            .open_in_place(nonce, aad(query), &mut sealed)
+            .map_err(|_| InvalidPageToken::Invalid)?;
+        let payload: PageTokenPayload<T> = serde_json::from_slice(payload).map_err(|_| InvalidPageToken::Invalid)?;
+
+        if payload.exp < chrono::Utc::now().timestamp() {
+            return Err(InvalidPageToken::Expired);
+        }
+        serde_dynamo::aws_sdk_dynamodb_1::to_item(payload.key).map_err(|_| InvalidPageToken::Invalid)
+    }
 }
 
-// Only used in UserTable
-pub async fn create_entity_serde<T: serde::Serialize>(client: &Client, entity: T, table: &String)
-                                                      -> Result<(), anyhow::Error> {
-    // Create dynamoDb HashMap<String, AttributeValue> entity
-    let entity = serde_dynamo::aws_sdk_dynamodb_1::to_item(entity)?;
-
-    // Write to dynamo
-    client
-        .put_item()
-        .table_name(table)
-        .set_item(Some(entity))
-        .send()
-        .await
-        .map_err(|e| {
-            e.into_service_error()
-        })?;
-
-    Ok(())
+/// The token format version and the query are authenticated, not encrypted
+fn aad(query: &str) -> ring::aead::Aad<Vec<u8>> {
+    ring::aead::Aad::from([&[PAGE_TOKEN_VERSION][..], query.as_bytes()].concat())
 }
 
+/// One page of query results, with the token of the next page if there is one
+#[derive(Clone, Debug)]
+pub struct PaginatedOutput<T> {
+    pub key: Option<String>,
+    pub output: T,
+}
 
-/// DynamoDB - Delete Item by Key
-///
-///
-pub async fn delete_by_key_attribute_value_serde(
-    client: &Client,
-    table_name: &str,
-    // attribute_name: &str,
-    // attribute_value_str: &str,
-    key: HashMap<String, AttributeValue>
-) -> Result<(), DynamoError> {
-
-    let result =
-        client
-            .delete_item()
-            .table_name(table_name)
-            .set_key(Some(key))
-            .send()
-            .await
-            .map_err(|e| DynamoError::DynErrorExp {exp: e.into_service_error().to_string()})?
-        ;
-    Ok(())
+/// Why a `PagedQuery` failed
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error(transparent)]
+    Dynamo(#[from] DynamoError),
+    #[error(transparent)]
+    PageToken(#[from] InvalidPageToken),
+    /// `PagedQuery::partition` was not called
+    #[error("A query needs a partition key")]
+    MissingPartition,
 }
 
+/// A condition on the sort key of a `PagedQuery`
+#[derive(Clone, Debug)]
+pub enum SortCondition {
+    Eq(AttributeValue),
+    Lt(AttributeValue),
+    Le(AttributeValue),
+    Gt(AttributeValue),
+    Ge(AttributeValue),
+    /// Inclusive on both ends
+    Between(AttributeValue, AttributeValue),
+    BeginsWith(String),
+}
 
-pub fn decode_base64_to_json(token: &str) -> Result<String, base64::DecodeError> {
-    // Decode the base64 token
-    let decoded_bytes = base64::prelude::BASE64_URL_SAFE.decode(token)?;
-    // Convert Vec<u8> to String
-    let json_string = String::from_utf8(decoded_bytes)
-        .map_err(|err| base64::DecodeError::InvalidLength)?;
-    Ok(json_string.to_string())
+impl SortCondition {
+    /// The key condition on `#sk`, with its values
+    fn expression(&self) -> (String, Vec<AttributeValue>) {
+        let comparison = |operator: &str, value: &AttributeValue| (format!("#sk {operator} :sk0"), vec![value.clone()]);
+        match self {
+            SortCondition::Eq(value) => comparison("=", value),
+            SortCondition::Lt(value) => comparison("<", value),
+            SortCondition::Le(value) => comparison("<=", value),
+            SortCondition::Gt(value) => comparison(">", value),
+            SortCondition::Ge(value) => comparison(">=", value),
+            SortCondition::Between(from, to) => ("#sk BETWEEN :sk0 AND :sk1".to_string(), vec![from.clone(), to.clone()]),
+            SortCondition::BeginsWith(prefix) => {
+                ("begins_with(#sk, :sk0)".to_string(), vec![AttributeValue::S(prefix.clone())])
+            }
+        }
+    }
 }
 
-/// DynamoDB - Query Item by Key
-///
-/// Rust Generics with Deserialize
-///     https://stackoverflow.com/questions/54851996/rust-and-serde-deserializing-using-generics
-///
-pub async fn query_items_key_attribute_value_serde<T>(
-    client: &Client,
-    table_name: &str,
-    key: HashMap<String, AttributeValue>
-) -> Result<Option<T>, anyhow::Error>
+/// One page of a DynamoDB Query of `T`, resumed from and returning opaque page
+/// tokens. `K` has the attributes of the last evaluated key: the table key,
+/// plus the index key when querying an index. E.g.
+/// ```ignore
+/// PagedQuery::<UserTable, UserTableKey>::new(client, page_tokens, "UserTable")
+///     .index("gsi1")
+///     .partition("gsi_pk", AttributeValue::N("1".to_string()))
+///     .sort("date_ordered", SortCondition::BeginsWith("2024-10".to_string()))
+///     .reverse()
+///     .set_limit(page_size)
+///     .set_page_token(token)
+///     .send()
+///     .await?
+/// ```
+/// The key condition uses the placeholders `#pk`, `#sk`, `:pk`, `:sk0` and `:sk1`,
+/// and the projection `#p0`, `#p1`, ..., which a filter must not reuse.
+/// Page tokens are bound to everything but the limit, so the page size can
+/// change from page to page.
+pub struct PagedQuery<T, K> {
+    client: aws_sdk_dynamodb::Client,
+    page_tokens: PageTokens,
+    table: String,
+    index: Option<String>,
+    partition: Option<(String, AttributeValue)>,
+    sort: Option<(String, SortCondition)>,
+    forward: bool,
+    filter: Option<Condition>,
+    projection: Vec<String>,
+    consistent_read: bool,
+    limit: Option<i32>,
+    page_token: Option<String>,
+    entity: PhantomData<fn() -> (T, K)>,
+}
+
+impl<T, K> PagedQuery<T, K>
 where
-    T: de::DeserializeOwned
+    T: serde::de::DeserializeOwned,
+    K: Serialize + serde::de::DeserializeOwned,
 {
-    let result =
-        client
-            .get_item()
-            .table_name(table_name)
-            .set_key(Some(key))
+    pub fn new(client: &aws_sdk_dynamodb::Client, page_tokens: &PageTokens, table: impl Into<String>) -> Self {
+        PagedQuery {
+            client: client.clone(),
+            page_tokens: page_tokens.clone(),
+            table: table.into(),
+            index: None,
+            partition: None,
+            sort: None,
+            forward: true,
+            filter: None,
+            projection: vec![],
+            consistent_read: false,
+            limit: None,
+            page_token: None,
+            entity: PhantomData,
+        }
+    }
+
+    /// Query a global or local secondary index instead of the table
+    pub fn index(mut self, index: impl Into<String>) -> Self {
+        self.index = Some(index.into());
+        self
+    }
+
+    /// The partition key attribute and the value it has to equal. Required.
+    pub fn partition(mut self, name: impl Into<String>, value: AttributeValue) -> Self {
+        self.partition = Some((name.into(), value));
+        self
+    }
+
+    pub fn sort(mut self, name: impl Into<String>, condition: SortCondition) -> Self {
+        self.sort = Some((name.into(), condition));
+        self
+    }
+
+    /// Descending sort key order, ascending is the default
+    pub fn reverse(mut self) -> Self {
+        self.forward = false;
+        self
+    }
+
+    /// Applied after reading, so a page can hold fewer items than the limit
+    pub fn filter(mut self, filter: Condition) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Only read these attributes. `T` has to deserialize from them.
+    pub fn projection<S: Into<String>>(mut self, attributes: impl IntoIterator<Item = S>) -> Self {
+        self.projection = attributes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Strongly consistent reads, not available on global secondary indexes
+    pub fn consistent_read(mut self) -> Self {
+        self.consistent_read = true;
+        self
+    }
+
+    /// Items read per page, before the filter
+    pub fn limit(self, limit: i32) -> Self {
+        self.set_limit(Some(limit))
+    }
+
+    pub fn set_limit(mut self, limit: Option<i32>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Continue after the page that returned `token`
+    pub fn page_token(self, token: impl Into<String>) -> Self {
+        self.set_page_token(Some(token.into()))
+    }
+
+    pub fn set_page_token(mut self, token: Option<String>) -> Self {
+        self.page_token = token;
+        self
+    }
+
+    pub async fn send(self) -> Result<PaginatedOutput<Vec<T>>, QueryError> {
+        let binding = self.binding();
+        let (partition_name, partition_value) = self
+            .partition
+            .ok_or(QueryError::MissingPartition)?;
+
+        let mut key_condition = "#pk = :pk".to_string();
+        let mut names = HashMap::from([("#pk".to_string(), partition_name)]);
+        let mut values = HashMap::from([(":pk".to_string(), partition_value)]);
+        if let Some((sort_name, condition)) = self.sort {
+            let (expression, sort_values) = condition.expression();
+            key_condition = format!("{key_condition} AND {expression}");
+            names.insert("#sk".to_string(), sort_name);
+            for (index, value) in sort_values.into_iter().enumerate() {
+                values.insert(format!(":sk{index}"), value);
+            }
+        }
+
+        let projection = (!self.projection.is_empty()).then(|| {
+            let placeholders: Vec<String> = (0..self.projection.len()).map(|index| format!("#p{index}")).collect();
+            names.extend(placeholders.iter().cloned().zip(self.projection));
+            placeholders.join(", ")
+        });
+
+        let filter = self.filter.map(|filter| {
+            names.extend(filter.names);
+            values.extend(filter.values);
+            filter.expression
+        });
+
+        let exclusive_start_key = self
+            .page_token
+            .map(|token| self.page_tokens.decode::<K>(&binding, &token))
+            .transpose()?;
+
+        let results = self
+            .client
+            .query()
+            .table_name(&self.table)
+            .set_index_name(self.index)
+            .key_condition_expression(key_condition)
+            .set_filter_expression(filter)
+            .set_projection_expression(projection)
+            .set_expression_attribute_names(Some(names))
+            .set_expression_attribute_values(Some(values))
+            .scan_index_forward(self.forward)
+            .set_consistent_read(self.consistent_read.then_some(true))
+            .set_limit(self.limit)
+            .set_exclusive_start_key(exclusive_start_key)
             .send()
             .await
-            .map_err(|e| e.into_service_error()).context("get item issue")?
-        ;
+            .map_err(DynamoError::from)?;
+
+        let output = serde_dynamo::aws_sdk_dynamodb_1::from_items(results.items.unwrap_or_default())
+            .map_err(DynamoError::from)?;
+        let key = results
+            .last_evaluated_key
+            .map(|last_evaluated_key| self.page_tokens.encode::<K>(&binding, last_evaluated_key))
+            .transpose()?;
+
+        Ok(PaginatedOutput { key, output })
+    }
+
+    /// What a page token is bound to: everything that decides which items come next
+    fn binding(&self) -> String {
+        let mut filter_values: Vec<_> = self.filter.iter().flat_map(|filter| &filter.values).collect();
+        filter_values.sort_by(|a, b| a.0.cmp(b.0));
+        let mut filter_names: Vec<_> = self.filter.iter().flat_map(|filter| &filter.names).collect();
+        filter_names.sort();
+
+        format!(
+            "{}|{:?}|{:?}|{:?}|{}|{:?}|{:?}|{:?}|{:?}|{}",
+            self.table,
+            self.index,
+            self.partition,
+            self.sort,
+            if self.forward { "asc" } else { "desc" },
+            self.filter.as_ref().map(|filter| &filter.expression),
+            filter_names,
+            filter_values,
+            self.projection,
+            self.consistent_read,
+        )
+    }
+}
+
+/// Puts, updates, deletes and condition checks on any `DynamoEntity`, written
+/// all or nothing with one TransactWriteItems call, e.g.
+/// ```ignore
+/// state.transaction()
+///     .create(&order)?
+///     .update::<UserTable>(&other_order, Update::default().add("price", AttributeValue::N("1".into())), Some(3))?
+///     .idempotency_token(idempotency_key)
+///     .execute(&state.client)
+///     .await?;
+/// ```
+/// Creates, updates and version checks work as in `Repository`. A transaction
+/// takes at most 100 items. When DynamoDB cancels it, the error is
+/// `DynamoError::TransactionCancelled` with one reason per item, in order,
+/// naming the operation of the item.
+pub struct Transaction {
+    tables: TableNames,
+    items: Vec<aws_sdk_dynamodb::types::TransactWriteItem>,
+    /// "Put UserTable", ... for each item
+    operations: Vec<String>,
+    idempotency_token: Option<String>,
+}
+
+impl Transaction {
+    pub fn new(tables: &TableNames) -> Self {
+        Transaction {
+            tables: tables.clone(),
+            items: vec![],
+            operations: vec![],
+            idempotency_token: None,
+        }
+    }
+
+    /// Creates or replaces the entity, failing with `DynamoError::Versioned`
+    /// for a versioned one, see `Repository::put`
+    pub fn put<T: DynamoEntity>(self, entity: &T) -> Result<Self, DynamoError> {
+        unversioned::<T>()?;
+        let put = aws_sdk_dynamodb::types::Put::builder()
+            .table_name(T::table_name(&self.tables))
+            .set_item(Some(serde_dynamo::aws_sdk_dynamodb_1::to_item(entity)?))
+            .build()?;
+        Ok(self.push::<T>("Put", aws_sdk_dynamodb::types::TransactWriteItem::builder().put(put)))
+    }
+
+    /// Creates the entity, the transaction is cancelled if one with the same key exists
+    pub fn create<T: DynamoEntity>(self, entity: &T) -> Result<Self, DynamoError> {
+        let condition = Condition::not_exists::<T>();
+        let put = aws_sdk_dynamodb::types::Put::builder()
+            .table_name(T::table_name(&self.tables))
+            .set_item(Some(create_item(entity)?))
+            .condition_expression(condition.expression)
+            .set_expression_attribute_names(Some(condition.names))
+            .build()?;
+        Ok(self.push::<T>("Create", aws_sdk_dynamodb::types::TransactWriteItem::builder().put(put)))
+    }
+
+    /// Updates an existing entity, at version `expected` if given
+    pub fn update<T: DynamoEntity>(self, key: &T::Key, update: Update, expected: Option<u64>) -> Result<Self, DynamoError> {
+        let request = UpdateRequest::new::<T>(update, expected);
+        let update = aws_sdk_dynamodb::types::Update::builder()
+            .table_name(T::table_name(&self.tables))
+            .set_key(Some(T::key_item(key)))
+            .update_expression(request.expression)
+            .condition_expression(request.condition)
+            .set_expression_attribute_names(Some(request.names))
+            .set_expression_attribute_values(request.values)
+            .build()?;
+        Ok(self.push::<T>("Update", aws_sdk_dynamodb::types::TransactWriteItem::builder().update(update)))
+    }
+
+    /// Deletes the entity if there is one, at version `expected` if given
+    pub fn delete<T: DynamoEntity>(self, key: &T::Key, expected: Option<u64>) -> Result<Self, DynamoError> {
+        let mut delete = aws_sdk_dynamodb::types::Delete::builder()
+            .table_name(T::table_name(&self.tables))
+            .set_key(Some(T::key_item(key)));
+        if let Some(condition) = expected.and_then(Condition::version::<T>) {
+            delete = delete
+                .condition_expression(condition.expression)
+                .set_expression_attribute_names(Some(condition.names))
+                .set_expression_attribute_values(Some(condition.values));
+        }
+        let delete = delete.build()?;
+        Ok(self.push::<T>("Delete", aws_sdk_dynamodb::types::TransactWriteItem::builder().delete(delete)))
+    }
+
+    /// Cancels the transaction unless `condition` holds for the entity, without writing it
+    pub fn condition_check<T: DynamoEntity>(self, key: &T::Key, condition: Condition) -> Result<Self, DynamoError> {
+        let check = aws_sdk_dynamodb::types::ConditionCheck::builder()
+            .table_name(T::table_name(&self.tables))
+            .set_key(Some(T::key_item(key)))
+            .condition_expression(condition.expression)
+            .set_expression_attribute_names(Some(condition.names).filter(|names| !names.is_empty()))
+            .set_expression_attribute_values(Some(condition.values).filter(|values| !values.is_empty()))
+            .build()?;
+        Ok(self.push::<T>("Check", aws_sdk_dynamodb::types::TransactWriteItem::builder().condition_check(check)))
+    }
+
+    /// Makes retries safe: for 10 minutes, the same token with the same items
+    /// succeeds without writing again. At most 36 characters.
+    pub fn idempotency_token(mut self, token: impl Into<String>) -> Self {
+        self.idempotency_token = Some(token.into());
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
 
-    match result.item {
-        Some(attribute_hash_map) => {
-            // let i = serde_dynamo::aws_sdk_dynamodb_1::from_item(result.item.unwrap())
-            let i = serde_dynamo::aws_sdk_dynamodb_1::from_item(attribute_hash_map).context("from item issue")?;
-                // .map_err(|e| DynamoError::DynErrorExp {exp: e.to_string()})?;
-            Ok(i)
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
 
+    pub async fn execute(self, client: &aws_sdk_dynamodb::Client) -> Result<(), DynamoError> {
+        if self.items.is_empty() {
+            return Ok(());
         }
-        None => {Ok(None)}
+
+        let result = client
+            .transact_write_items()
+            .set_transact_items(Some(self.items))
+            .set_client_request_token(self.idempotency_token)
+            .send()
+            .await;
+
+        match result.map_err(DynamoError::from) {
+            Ok(_) => Ok(()),
+            Err(DynamoError::TransactionCancelled { mut reasons, source, request_id }) => {
+                for (reason, operation) in reasons.iter_mut().zip(self.operations) {
+                    reason.operation = Some(operation);
+                }
+                Err(DynamoError::TransactionCancelled { reasons, source, request_id })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn push<T: DynamoEntity>(mut self, operation: &str, item: aws_sdk_dynamodb::types::builders::TransactWriteItemBuilder) -> Self {
+        self.operations.push(format!("{operation} {}", T::table_name(&self.tables)));
+        self.items.push(item.build());
+        self
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use crate::repository::Repository;
+    use crate::user_table::{UserTable, UserTableId, UserTableKey, UserTablePatch};
+    use super::*;
+
+    fn id(order: &str) -> UserTableId {
+        UserTableId { user: "user1".to_string(), order: order.to_string() }
+    }
+
+    #[tokio::test]
+    async fn cancelled_transactions_name_the_failed_operation() {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        let tables = TableNames::default();
+        let orders = Repository::<UserTable>::new(fake.client(), &tables);
+        orders.create(&UserTable::new(&id("order1"), "p#prod1".to_string(), 1.0)).await.unwrap();
+
+        let patch = UserTablePatch { price: Some(2.0), ..Default::default() };
+        let result = Transaction::new(&tables)
+            .update::<UserTable>(&id("order1"), patch.update().unwrap(), Some(1))
+            .unwrap()
+            .create(&UserTable::new(&id("order1"), "p#prod2".to_string(), 1.0))
+            .unwrap()
+            .execute(&fake.client())
+            .await;
+
+        let Err(DynamoError::TransactionCancelled { reasons, .. }) = result else {
+            panic!("expected a cancelled transaction, got {result:?}");
+        };
+        assert_eq!(reasons[0].code, "None");
+        assert_eq!((reasons[1].code.as_str(), reasons[1].operation.as_deref()), ("ConditionalCheckFailed", Some("Create UserTable")));
+        // Nothing was written
+        assert_eq!(orders.get(&id("order1")).await.unwrap().unwrap().price, 1.0);
+    }
+
+    #[tokio::test]
+    async fn paged_query_filters_and_projects() {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        let orders = Repository::<UserTable>::new(fake.client(), &TableNames::default());
+        for (n, price) in [1.0, 5.0, 2.0, 7.0].into_iter().enumerate() {
+            orders.create(&UserTable::new(&id(&format!("order{n}")), "p#prod1".to_string(), price)).await.unwrap();
+        }
+
+        let page_tokens = PageTokens::new("test-secret-0123456789abcdefghijklmn", 60);
+        let page = PagedQuery::<HashMap<String, serde_json::Value>, UserTableKey>::new(&fake.client(), &page_tokens, "UserTable")
+            .partition("UserId", AttributeValue::S("u#user1".to_string()))
+            .sort("OrderId", SortCondition::BeginsWith("o#order".to_string()))
+            .filter(Condition::new("#price > :price").name("#price", "price").value(":price", AttributeValue::N("1.5".to_string())))
+            .projection(["OrderId", "price"])
+            .send()
+            .await
+            .unwrap();
+
+        let orders: Vec<_> = page.output.iter().map(|item| item["OrderId"].as_str().unwrap()).collect();
+        assert_eq!(orders, ["o#order1", "o#order2", "o#order3"]);
+        assert!(page.output.iter().all(|item| item.len() == 2));
+        assert!(page.key.is_none());
+    }
+}
@@ -0,0 +1,232 @@
+//! Canned DynamoDB responses for tests, on the SDK's `StaticReplayClient`.
+//!
+//! A test lists the calls it expects, as the operation with its JSON request
+//! body and the response to return, and afterwards asserts that exactly those
+//! requests were sent:
+//! ```ignore
+//! let replay = DynamoReplay::new(vec![Exchange::new("Query", json!({"TableName": "UserTable", ...}), json!({"Items": []}))]);
+//! let orders = Repository::<UserTable>::new(replay.client(), &tables);
+//! ...
+//! replay.assert_requests();
+//! ```
+//!
+//! Conversations can be recorded with `Recorder` and saved as fixtures in
+//! `fixtures/dynamo/{name}.json`, to be replayed with `DynamoReplay::from_fixture`.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use aws_sdk_dynamodb::config::interceptors::{AfterDeserializationInterceptorContextRef, BeforeTransmitInterceptorContextRef};
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::{BehaviorVersion, ConfigBag, Credentials, Intercept, Region, RuntimeComponents};
+use aws_sdk_dynamodb::error::BoxError;
+use aws_sdk_dynamodb::Client;
+use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+use axum::http;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const TARGET_PREFIX: &str = "DynamoDB_20120810.";
+
+/// One DynamoDB call, e.g. `Query` with its request and response bodies
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub operation: String,
+    pub request: Value,
+    #[serde(default = "ok")]
+    pub status: u16,
+    pub response: Value,
+}
+
+fn ok() -> u16 {
+    200
+}
+
+impl Exchange {
+    /// A successful call
+    pub fn new(operation: &str, request: Value, response: Value) -> Self {
+        Exchange { operation: operation.to_string(), request, status: 200, response }
+    }
+
+    /// A call failing with `kind`, e.g. `ConditionalCheckFailedException`
+    pub fn error(operation: &str, request: Value, kind: &str, message: &str) -> Self {
+        let response = serde_json::json!({
+            "__type": format!("com.amazonaws.dynamodb.v20120810#{kind}"),
+            "message": message,
+        });
+        Exchange { operation: operation.to_string(), request, status: 400, response }
+    }
+}
+
+/// A conversation with DynamoDB, stored as `fixtures/dynamo/{name}.json`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Fixture {
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/dynamo").join(format!("{name}.json"))
+    }
+
+    pub fn load(name: &str) -> Self {
+        let path = Fixture::path(name);
+        let json = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("could not read {path:?}: {e}"));
+        serde_json::from_str(&json).unwrap_or_else(|e| panic!("could not parse {path:?}: {e}"))
+    }
+
+    pub fn save(&self, name: &str) {
+        let path = Fixture::path(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, serde_json::to_string_pretty(self).unwrap() + "\n").unwrap();
+    }
+}
+
+/// Replays `Exchange`s in order, one per request
+pub struct DynamoReplay {
+    expected: Vec<Exchange>,
+    http_client: StaticReplayClient,
+}
+
+impl DynamoReplay {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        let events = exchanges
+            .iter()
+            .map(|exchange| {
+                let request = http::Request::builder()
+                    .method("POST")
+                    .uri("https://dynamodb.us-east-1.amazonaws.com/")
+                    .header("x-amz-target", format!("{TARGET_PREFIX}{}", exchange.operation))
+                    .body(SdkBody::from(exchange.request.to_string()))
+                    .unwrap();
+                let response = http::Response::builder()
+                    .status(exchange.status)
+                    .header("content-type", "application/x-amz-json-1.0")
+                    .body(SdkBody::from(exchange.response.to_string()))
+                    .unwrap();
+                ReplayEvent::new(request, response)
+            })
+            .collect();
+
+        DynamoReplay { expected: exchanges, http_client: StaticReplayClient::new(events) }
+    }
+
+    pub fn from_fixture(name: &str) -> Self {
+        DynamoReplay::new(Fixture::load(name).exchanges)
+    }
+
+    /// A client answered by the replay, without retries
+    pub fn client(&self) -> Client {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::for_tests())
+            .retry_config(RetryConfig::disabled())
+            .http_client(self.http_client.clone())
+            .build();
+        Client::from_conf(config)
+    }
+
+    /// Asserts that every exchange was requested, in order, with the same
+    /// operation and the same JSON body
+    pub fn assert_requests(&self) {
+        let actual: Vec<(String, Value)> = self
+            .http_client
+            .actual_requests()
+            .map(|request| {
+                let operation = request.headers().get("x-amz-target").unwrap_or_default();
+                let body = request.body().bytes().and_then(|body| serde_json::from_slice(body).ok());
+                (operation.trim_start_matches(TARGET_PREFIX).to_string(), body.unwrap_or(Value::Null))
+            })
+            .collect();
+
+        for (n, exchange) in self.expected.iter().enumerate() {
+            let Some((operation, request)) = actual.get(n) else {
+                panic!("request {n} ({}) was never sent, only {} were", exchange.operation, actual.len());
+            };
+            assert_eq!(operation, &exchange.operation, "operation of request {n}");
+            assert_eq!(
+                request,
+                &exchange.request,
+                "body of request {n} ({operation})\nactual:   {request}\nexpected: {}",
+                exchange.request
+            );
+        }
+    }
+}
+
+/// Records the calls of a client into a `Fixture`, e.g. against DynamoDB Local
+/// or a development account:
+/// ```ignore
+/// let recorder = Recorder::default();
+/// let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+/// let client = recorder.client(aws_sdk_dynamodb::Config::from(&aws_config));
+/// ...
+/// recorder.fixture().save("date_range_query");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+}
+
+/// The request of the attempt in flight, kept in the operation's config bag so
+/// concurrent calls don't mix up their requests
+#[derive(Clone, Debug)]
+struct RecordedRequest(String, Value);
+
+impl Storable for RecordedRequest {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Recorder {
+    /// A client on `config` that records every call
+    pub fn client(&self, config: aws_sdk_dynamodb::Config) -> Client {
+        Client::from_conf(config.to_builder().interceptor(self.clone()).build())
+    }
+
+    /// The calls so far, in the order they completed
+    pub fn fixture(&self) -> Fixture {
+        Fixture { exchanges: self.exchanges.lock().unwrap().clone() }
+    }
+}
+
+fn json_body(body: &SdkBody) -> Value {
+    body.bytes().and_then(|body| serde_json::from_slice(body).ok()).unwrap_or(Value::Null)
+}
+
+impl Intercept for Recorder {
+    fn name(&self) -> &'static str {
+        "Recorder"
+    }
+
+    fn read_before_transmit(
+        &self,
+        context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let request = context.request();
+        let operation = request.headers().get("x-amz-target").unwrap_or_default().trim_start_matches(TARGET_PREFIX);
+        cfg.interceptor_state().store_put(RecordedRequest(operation.to_string(), json_body(request.body())));
+        Ok(())
+    }
+
+    fn read_after_deserialization(
+        &self,
+        context: &AfterDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(RecordedRequest(operation, request)) = cfg.load::<RecordedRequest>().cloned() {
+            let response = context.response();
+            self.exchanges.lock().unwrap().push(Exchange {
+                operation,
+                request,
+                status: response.status().as_u16(),
+                response: json_body(response.body()),
+            });
+        }
+        Ok(())
+    }
+}
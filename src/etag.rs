@@ -0,0 +1,42 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::{ETAG, IF_MATCH};
+use axum::http::request::Parts;
+use axum::response::AppendHeaders;
+use crate::api_error::ApiError;
+
+/// The strong `ETag` of an entity version, e.g. `"3"`
+pub fn etag(version: u64) -> AppendHeaders<[(axum::http::HeaderName, String); 1]> {
+    AppendHeaders([(ETAG, format!("\"{version}\""))])
+}
+
+/// The version a write expects, from the `If-Match` header.
+///
+/// `None` without the header or with `If-Match: *`, so the write applies to
+/// whatever version is stored. A value that is not an `ETag` from `etag` is a 400.
+#[derive(Clone, Copy, Debug)]
+pub struct IfMatch(pub Option<u64>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IF_MATCH) else {
+            return Ok(IfMatch(None));
+        };
+
+        let invalid = || ApiError::bad_request("invalid_if_match", "If-Match must be an ETag like \"3\" or *");
+        let value = value.to_str().map_err(|_| invalid())?.trim();
+        if value == "*" {
+            return Ok(IfMatch(None));
+        }
+
+        value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .and_then(|version| version.parse().ok())
+            .map(|version| IfMatch(Some(version)))
+            .ok_or_else(invalid)
+    }
+}
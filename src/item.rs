@@ -2,7 +2,10 @@ use std::collections::HashMap;
 use aws_sdk_dynamodb::{Client, Error};
 use aws_sdk_dynamodb::types::AttributeValue;
 use serde::{Deserialize, Serialize};
+use crate::config::TableNames;
 use crate::dynamo::DynamoError;
+use crate::repository::{DynamoEntity, KeyAttribute};
+use crate::validation::{Validate, ValidationErrors};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Item {
@@ -17,6 +20,36 @@ pub struct Item {
 
 
 
+/// `age` stays a string, as stored, but has to be a whole number
+impl Validate for Item {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.not_blank("username", &self.username);
+        errors.integer_in("age", &self.age, 0, 150);
+    }
+}
+
+impl DynamoEntity for Item {
+    /// The username
+    type Key = String;
+
+    const NAME: &'static str = "Item";
+
+    const PARTITION_KEY: KeyAttribute = KeyAttribute::new("username");
+    const SORT_KEY: Option<KeyAttribute> = None;
+
+    fn table_name(tables: &TableNames) -> &str {
+        &tables.items
+    }
+
+    fn key_ids(username: &String) -> (&str, Option<&str>) {
+        (username, None)
+    }
+
+    fn key(&self) -> String {
+        self.username.clone()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ItemOut {
     pub p_type: Option<AttributeValue>,
@@ -39,21 +72,11 @@ pub async fn query_items_scan_serde(
         .scan()
         .table_name(table_name)
         .send()
-        .await
-        .map_err(|e| {
-            let se = e.into_service_error();
-            println!("{}", se.to_string());
-            DynamoError::DynErrorExp {exp: se.to_string()}
-        })?;
+        .await?;
 
     // And deserialize them as strongly-typed data structures
     let items = result.items().to_vec();
-    let users = serde_dynamo::aws_sdk_dynamodb_1::from_items(items)
-        .map_err(|e| {
-            println!("{}", e.to_string());
-            DynamoError::DynErrorExp {exp: e.to_string()}
-            // DynamoError::DynError
-        })?;
+    let users = serde_dynamo::aws_sdk_dynamodb_1::from_items(items)?;
     println!("Got {} users", users.len());
     Ok(users)
 }
@@ -131,20 +154,14 @@ pub async fn query_items_by_field_attribute_serde(
         // .expression_attribute_values(":u", AttributeValue::S("user1".to_string()))
 
         .send()
-        .await
-        .map_err(|e| {
-            println!("{}", e.as_service_error().unwrap().to_string());
-            DynamoError::DynErrorExp {exp: e.as_service_error().unwrap().to_string()}
-            // DynamoError::DynError
-        })?;
+        .await?;
 
     println!("{:?}", results.last_evaluated_key);
 
     if let Some(items) = results.items {
         // let items = results.items().to_vec();
         let items = items.to_vec();
-        let users: Vec<Item> = serde_dynamo::aws_sdk_dynamodb_1::from_items(items)
-            .map_err(|e| DynamoError::DynErrorExp {exp: e.to_string()})?;
+        let users: Vec<Item> = serde_dynamo::aws_sdk_dynamodb_1::from_items(items)?;
         println!("Got {} users", users.len());
         Ok(users)
     } else {
@@ -160,7 +177,7 @@ pub async fn query_items_by_field_scan_paginate_serde(
     client: &Client,
     table_name: &str,
     username: &str,
-) -> anyhow::Result<Vec<Item>, anyhow::Error> {
+) -> anyhow::Result<Vec<Item>, DynamoError> {
 
     let mut hm: Option<HashMap<String, AttributeValue>> = Some(HashMap::from([
         ("username".to_string(), AttributeValue::S("user4".to_string()) )
@@ -172,10 +189,7 @@ pub async fn query_items_by_field_scan_paginate_serde(
         .limit(2)
         .set_exclusive_start_key(hm)
         .send()
-        .await
-        .map_err(|e| {
-            e.into_service_error()
-        })?;
+        .await?;
     // .map_err(|e| {
     //     anyhow!(e.as_service_error().unwrap().to_string())
     // })?;
@@ -319,29 +333,8 @@ pub async fn list_items(client: &Client, table: &str, page_size: Option<i32>) ->
 // }
 
 
-// Used in Item - dynamo_2
-pub async fn add_item_serde(client: &Client, item: Item, table: &String)
-                            -> Result<(), anyhow::Error> {
-
-    // Turn it into an item that aws-sdk-dynamodb understands
-    let item = serde_dynamo::aws_sdk_dynamodb_1::to_item(item)?;
-
-    // Write item to db
-    client
-        .put_item()
-        .table_name(table)
-        .set_item(Some(item))
-        .send()
-        .await
-        .map_err(|e| {
-            e.into_service_error()
-        })?;
-
-    Ok(())
-}
-
 // Add item non-serde - Item dynamo_2
-pub async fn add_item(client: &Client, item: Item, table: &String) -> Result<(), anyhow::Error> {
+pub async fn add_item(client: &Client, item: Item, table: &String) -> Result<(), DynamoError> {
 
     let user_av = AttributeValue::S(item.username);
     // let type_av = AttributeValue::S(item.p_type);
@@ -403,3 +396,32 @@ pub async fn add_item(client: &Client, item: Item, table: &String) -> Result<(),
 
 
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use super::*;
+
+    fn item(username: &str) -> Item {
+        Item {
+            account_type: "standard_user".to_string(),
+            age: "30".to_string(),
+            username: username.to_string(),
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn added_items_are_scanned_back() {
+        let fake = FakeDynamo::new().with_table(TableSpec::items());
+        let client = fake.client();
+        let table = TableNames::default().items;
+        add_item(&client, item("user1"), &table).await.unwrap();
+        add_item(&client, item("user2"), &table).await.unwrap();
+
+        let mut usernames: Vec<_> = query_items_scan_serde(&client, &table, "user1").await.unwrap().into_iter().map(|item| item.username).collect();
+        usernames.sort();
+        assert_eq!(usernames, ["user1", "user2"]);
+    }
+}
@@ -1,14 +1,17 @@
+use crate::api_error::ApiError;
+use crate::app_state::AppState;
 use crate::dynamo::{DynamoError, StatResp};
 use crate::item::*;
+use crate::provision::{self, Attribute, Billing, TableSchema};
+use crate::validation::ValidatedJson;
 
 use std::collections::HashMap;
 use anyhow::Context;
-use aws_config::BehaviorVersion;
 use aws_sdk_dynamodb::Client;
 use aws_sdk_dynamodb::operation::create_table::CreateTableOutput;
-use aws_sdk_dynamodb::types::{AttributeDefinition, AttributeValue, KeySchemaElement, KeyType, ProvisionedThroughput, ScalarAttributeType};
+use aws_sdk_dynamodb::types::AttributeValue;
 use axum::{Extension, Json};
-use axum::extract::Query;
+use axum::extract::{Query, State};
 use axum::http::{HeaderValue, StatusCode};
 use axum::http::header::ToStrError;
 use axum::middleware::Next;
@@ -23,7 +26,6 @@ use serde::{Deserialize, Serialize};
 use serde_dynamo::to_attribute_value;
 use serde_json::json;
 use crate::auth::{AuthError, CurrentUser, VerificationError};
-use crate::dynamo::DynamoError::DynError;
 // use crate::dynamo_add::{add_item, add_item_serde, Item, ItemOut, query_items_by_username, query_items_by_field_attribute_serde, query_items_key_attribute_value_serde, delete_by_key_attribute_value_serde, UserTable, query_by_date_range_serde_dynamo, query_by_sorted_dates_serde_dynamo, UpdateUserTable};
 use crate::dynamo_query_helpers::*;
 use crate::item::*;
@@ -32,83 +34,36 @@ use crate::user_table::{query_by_date_range_serde_dynamo, query_by_sorted_dates_
 
 
 
-pub async fn query_items_by_scan_serde_rest() -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
+pub async fn query_items_by_scan_serde_rest(State(state): State<AppState>) -> Result<Json<Vec<Item>>, ApiError> {
+    let client = &state.client;
+    let table = &state.config.tables.items;
 
-    let table = "lambda_dynamo_2".to_string();
-
-    match crate::item::query_items_scan_serde(&client, &table, "user1").await {
-        Ok(items) => {
-            axum::Json(items).into_response()
-
-        }
-        Err(e) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "failure".to_string(),
-                    message: e.to_string(),
-                    status_code: StatusCode::INTERNAL_SERVER_ERROR
-
-                }
-            )
-
-        }
-    }
+    let items = crate::item::query_items_scan_serde(client, table, "user1").await?;
+    Ok(Json(items))
 }
 
-pub async fn query_items_by_field_rest() -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-
-    let table = "lambda_dynamo_2".to_string();
-
-    match query_items_by_field_attribute_serde(&client, &table, "user1").await {
-        Ok(items) => {
-            axum::Json(items).into_response()
-
-        }
-        Err(e) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "failure".to_string(),
-                    message: e.to_string(),
-                    status_code: StatusCode::INTERNAL_SERVER_ERROR
+pub async fn query_items_by_field_rest(State(state): State<AppState>) -> Result<Json<Vec<Item>>, ApiError> {
+    let client = &state.client;
+    let table = &state.config.tables.items;
 
-                }
-            )
-        }
-    }
+    let items = query_items_by_field_attribute_serde(client, table, "user1").await?;
+    Ok(Json(items))
 }
 
 pub async fn query_items_by_key_username_rest(
+    State(state): State<AppState>,
     axum::extract::Path(username): axum::extract::Path<String>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "lambda_dynamo_2".to_string();
-
-    // Create the unique key of the record in DynamoDB in a way rusoto understands
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("username"), serde_dynamo::to_attribute_value(username).unwrap()),
-        ]);
-
-    // match query_items_key_attribute_value_serde::<Item>(&client, &table, "username".to_string(),username.as_str()).await {
-    match query_items_key_attribute_value_serde::<Item>(&client, &table, key).await {
-        Ok(item) => match item {
-            Some(item_out) => {axum::Json(item_out).into_response()}
-            None => StatResp::new("failure", "no item found", StatusCode::OK).into_response()
-        }
-        Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-    }
+) -> Result<Json<Item>, ApiError> {
+    state.repository::<Item>()
+        .get(&username)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No item found"))
 }
 
 
-pub async fn dynamo_add_item_rest() -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
+pub async fn dynamo_add_item_rest(State(state): State<AppState>) -> Result<StatResp, ApiError> {
+    let client = &state.client;
 
     let item: Item = Item{
         // p_type: "123".to_string(),
@@ -118,85 +73,38 @@ pub async fn dynamo_add_item_rest() -> impl IntoResponse {
         first_name: "john".to_string(),
         last_name: "jones".to_string(),
     };
-    let table = "lambda_dynamo_2".to_string();
+    let table = &state.config.tables.items;
 
-
-    return match add_item(&client, item, &table).await {
-        Ok(item_out) => {
-            StatResp {
-                result: "success".to_string(),
-                message: "added item".to_string(),
-                status_code: StatusCode::OK
-
-            }
-        }
-        Err(e) => {
-            println!("Error adding item: {}", e.to_string());
-            StatResp {
-                result: "failure".to_string(),
-                message: e.to_string(),
-                status_code: StatusCode::INTERNAL_SERVER_ERROR
-
-            }
-        }
-    };
+    add_item(client, item, table).await?;
+    Ok(StatResp::new("success", "added item", StatusCode::OK))
 }
 
-pub async fn dynamo_add_item_rest_serde(axum::extract::Json(payload): axum::extract::Json<Item>) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "lambda_dynamo_2".to_string();
-
+pub async fn dynamo_add_item_rest_serde(
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<Item>,
+) -> Result<StatResp, ApiError> {
     let item = payload;
 
-    return match add_item_serde(&client, item, &table).await {
-        Ok(_) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "success".to_string(),
-                    message: "added item".to_string(),
-                    status_code: StatusCode::OK,
-                }                                           )
-        }
-        Err(e) =>  {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "failure".to_string(),
-                    message: e.to_string(),
-                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
-                }                                           )
-        }
-    };
+    // 409 if the username is taken
+    state.repository::<Item>().create(&item).await?;
+    Ok(StatResp::new("success", "added item", StatusCode::OK))
 }
 
 
 
-pub async fn delete_items_by_key_username_rest(axum::extract::Path(username): axum::extract::Path<String>) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "lambda_dynamo_2".to_string();
-
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("username"), serde_dynamo::to_attribute_value(username)
-                .map_err(|e|
-                    // DynamoError::DynErrorExp {exp: e.to_string()}
-                    StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-                ).unwrap()
-            ),
-        ]);
-
-    // match delete_by_key_attribute_value_serde(&client, &table, "username", username.as_str(), key).await {
-    match delete_by_key_attribute_value_serde(&client, &table, key).await {
-        Ok(item) =>
-            { StatResp::new("success", "deleted item", StatusCode::OK).into_response() }
-        Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-    }
+pub async fn delete_items_by_key_username_rest(
+    State(state): State<AppState>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Result<StatResp, ApiError> {
+    state.repository::<Item>()
+        .delete(&username, None)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No item found"))?;
+    Ok(StatResp::new("success", "deleted item", StatusCode::OK))
 }
 
 // pub async fn dynamo(Extension(currentUser): Extension<CurrentUser>) -> impl IntoResponse {
-pub async fn dynamo_call() -> impl IntoResponse {
+pub async fn dynamo_call(State(state): State<AppState>) -> Result<StatResp, ApiError> {
 
     //  The following code came from the main() function in the exapmles
     //  for setting up tracing, config and client.
@@ -206,97 +114,24 @@ pub async fn dynamo_call() -> impl IntoResponse {
     // required to enable CloudWatch error logging by the runtime (already in main)
     // tracing::init_default_subscriber();
 
-    //Get config from environment.
-    let config = aws_config::load_from_env().await;
-    //Create the DynamoDB client.
-    let client = Client::new(&config);
-
-
-    // run(service_fn(|event: Request| async {
-    // handle_request(&client, event).await
-    // }))
-    // .await
-
-    match create_table(&client, "lambda_dynamo_2", "username").await {
-        Ok(create_table_output) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "success".to_string(),
-                    message: "created table".to_string(),
-                    status_code: StatusCode::OK
-
-                })
-        }
-        Err(e) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "failure".to_string(),
-                    message: e.to_string(),
-                    status_code: StatusCode::INTERNAL_SERVER_ERROR
-
-                })
-
-        }
-    };
+    // The DynamoDB client is created once in main() and shared through AppState
+    let client = &state.client;
 
-    // Json(StatusResponse {
-    //     result: "success".to_string(),
-    //     message: "processed dynamodb transaction".to_string(),
-    //     status_code: StatusCode::OK
-    //
-    // })
-
-    // Json
-    axum::response::IntoResponse::into_response
-        (StatResp {
-            result: "success".to_string(),
-            message: "processed dynamodb transaction".to_string(),
-            status_code: StatusCode::OK
-
-        })
+    // An existing table is reported as a 409 resource_in_use
+    create_table(client, &state.config.tables.items, "username").await?;
+    Ok(StatResp::new("success", "created table", StatusCode::OK))
 }
 
-// Code is from AWS website
+/// Creates the table keyed by `key` with 10 read and 5 write capacity units.
+/// The tables of the API are provisioned by `provision::Provisioner`.
 pub async fn create_table(
     client: &Client,
     table: &str,
     key: &str,
-    // ) -> Result<CreateTableOutput, Error> {
 ) -> Result<CreateTableOutput, DynamoError>  {
-    let a_name: String = key.into();
-    let table_name: String = table.into();
-
-    let ad = AttributeDefinition::builder()
-        .attribute_name(&a_name)
-        .attribute_type(ScalarAttributeType::S)
-        .build()
-        // .map_err(Err(Error))?;
-        .map_err(|_| DynError)?;
-
-    let ks = KeySchemaElement::builder()
-        .attribute_name(&a_name)
-        .key_type(KeyType::Hash)
-        .build()
-        // .map_err(Error::BuildError)?;
-        .map_err(|_| DynError)?;
-
-    let pt = ProvisionedThroughput::builder()
-        .read_capacity_units(10)
-        .write_capacity_units(5)
-        .build()
-        // .map_err(Error::BuildError)?;
-        .map_err(|_| DynError)?;
-
-    let create_table_response = client
-        .create_table()
-        .table_name(table_name)
-        .key_schema(ks)
-        .attribute_definitions(ad)
-        .provisioned_throughput(pt)
-        .send()
-        .await;
+    let schema = TableSchema::new(table, Attribute::s(key)).billing(Billing::Provisioned { read: 10, write: 5 });
 
-    match create_table_response {
+    match provision::create_table(client, &schema).await {
         Ok(out) => {
             println!("Added table {} with key {}", table, key);
             Ok(out)
@@ -304,7 +139,7 @@ pub async fn create_table(
         Err(e) => {
             eprintln!("Got an error creating table:");
             eprintln!("{}", e);
-            Err(DynError)
+            Err(e)
         }
     }
 }
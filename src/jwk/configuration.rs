@@ -1,26 +1,15 @@
 #![allow(unused)]
 
 
-use std::env;
+use serde::Deserialize;
 
-#[derive(Debug)]
+/// One trusted token issuer: where to fetch its signing keys and what the tokens must be issued for.
+/// Loaded as part of `crate::config::Config`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct JwkConfiguration {
+    /// Found through `{issuer}/.well-known/openid-configuration` when empty
     pub jwk_url: String,
     pub audience: String,
     pub issuer: String,
 }
-
-// #[cfg(debug_assertions)]
-fn expect_env_var(name: &str, default: &str) -> String {
-    return env::var(name).unwrap_or(String::from(default));
-}
-
-pub fn get_configuration() -> JwkConfiguration {
-
-    JwkConfiguration {
-        jwk_url: expect_env_var("JWK_URL", ""),
-        audience: expect_env_var("JWK_AUDIENCE", ""),
-        issuer: expect_env_var("JWK_ISSUER", ""),
-    }
-
-}
@@ -0,0 +1,38 @@
+use serde::Deserialize;
+
+/// The part of an OpenID Provider Metadata document we use
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+    #[error("discovery document is for issuer {found}, expected {expected}")]
+    IssuerMismatch { expected: String, found: String },
+}
+
+/// Looks up the `jwks_uri` of an issuer through OpenID Connect Discovery,
+/// `{issuer}/.well-known/openid-configuration`
+pub async fn discover_jwks_uri(client: &reqwest::Client, issuer: &str) -> Result<String, DiscoveryError> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let metadata = client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ProviderMetadata>()
+        .await?;
+
+    // OpenID Connect Discovery 1.0, section 4.3
+    if metadata.issuer != issuer {
+        return Err(DiscoveryError::IssuerMismatch {
+            expected: issuer.to_string(),
+            found: metadata.issuer,
+        });
+    }
+    Ok(metadata.jwks_uri)
+}
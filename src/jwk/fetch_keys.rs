@@ -5,8 +5,7 @@
 // use crate::config::auth::jwk;
 // use crate::config::auth::jwk::get_max_age::get_max_age;
 // use crate::config::auth::jwk::JwkConfiguration;
-use serde::Deserialize;
-use std::error::Error;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 // use reqwest::*;
 use crate::jwk;
@@ -14,18 +13,32 @@ use crate::jwk::*;
 use crate::jwk::get_max_age::get_max_age;
 use crate::jwk::JwkConfiguration;
 
-#[derive(Debug, Deserialize)]
-struct KeyResponse {
-    keys: Vec<JwkKey>,
+/// A JWK Set, as served by a `jwk_url` and by our own `/.well-known/jwks.json`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct KeyResponse {
+    pub keys: Vec<JwkKey>,
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+/// A public signing key. RSA keys use `n` and `e`, EC keys `crv`, `x` and `y`
+/// and OKP (Ed25519) keys `crv` and `x`; fields that don't apply are left empty.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub struct JwkKey {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub e: String,
+    #[serde(default)]
     pub alg: String,
     pub kty: String,
     pub kid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub n: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub crv: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub x: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub y: String,
+    #[serde(rename = "use", default, skip_serializing_if = "String::is_empty")]
+    pub key_use: String,
 }
 
 pub struct JwkKeys {
@@ -35,19 +48,24 @@ pub struct JwkKeys {
 
 const FALLBACK_TIMEOUT: Duration = Duration::from_secs(60);
 
-fn fetch_keys_for_config(
-    config: &JwkConfiguration,
-) -> Result<JwkKeys, Box<dyn std::error::Error>> {
-    let http_response =  reqwest::blocking::get(&config.jwk_url)?;
+async fn fetch_keys_for_config(
+    client: &reqwest::Client,
+    jwk_url: &str,
+) -> Result<JwkKeys, reqwest::Error> {
+    let http_response = client
+        .get(jwk_url)
+        .send()
+        .await?
+        .error_for_status()?;
     let max_age = get_max_age(&http_response).unwrap_or(FALLBACK_TIMEOUT);
-    let result = Result::Ok(http_response.json::<KeyResponse>()?);
+    let result = Result::Ok(http_response.json::<KeyResponse>().await?);
 
-    return result.map(|res| JwkKeys {
+    result.map(|res| JwkKeys {
         keys: res.keys,
         validity: max_age,
-    });
+    })
 }
 
-pub fn fetch_keys() -> Result<JwkKeys, Box<dyn Error>> {
-    return fetch_keys_for_config(&jwk::get_configuration());
+pub async fn fetch_keys(client: &reqwest::Client, jwk_url: &str) -> Result<JwkKeys, reqwest::Error> {
+    fetch_keys_for_config(client, jwk_url).await
 }
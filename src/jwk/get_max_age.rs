@@ -1,7 +1,7 @@
 #![allow(unused)]
 
 
-use reqwest::blocking::Response;
+use reqwest::Response;
 use reqwest::header::HeaderValue;
 use std::time::Duration;
 
@@ -26,9 +26,9 @@ pub fn get_max_age(response: &Response) -> Result<Duration, MaxAgeParseError> {
 fn parse_max_age_value(cache_control_value: &str) -> Result<Duration, MaxAgeParseError> {
     let tokens: Vec<&str> = cache_control_value.split(",").collect();
     for token in tokens {
-        let key_value: Vec<&str> = token.split("=").map(|s| s.trim()).collect();
-        let key = key_value.first().unwrap();
-        let val = key_value.get(1);
+        let mut key_value = token.splitn(2, '=').map(|s| s.trim());
+        let key = key_value.next().unwrap_or_default();
+        let val = key_value.next();
 
         if String::from("max-age").eq(&key.to_lowercase()) {
             match val {
@@ -43,7 +43,7 @@ fn parse_max_age_value(cache_control_value: &str) -> Result<Duration, MaxAgePars
             }
         }
     }
-    return Err(MaxAgeParseError::NoMaxAgeSpecified);
+    Err(MaxAgeParseError::NoMaxAgeSpecified)
 }
 
 fn parse_cache_control_header(header_value: &HeaderValue) -> Result<Duration, MaxAgeParseError> {
@@ -1,85 +1,292 @@
 #![allow(unused)]
 
 
-// use crate::config::auth::jwk;
-// use crate::config::auth::jwk::use_repeating_job::use_repeating_job;
-// use crate::config::auth::jwk::{fetch_keys, Claims, JwkKeys, JwkVerifier};
-use jsonwebtoken::TokenData;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use jsonwebtoken::{decode_header, TokenData};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock as StdRwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
 use crate::auth::AuthError;
-use crate::jwk;
-use crate::jwk::{FBTokenClaims, fetch_keys, JwkKeys, JwkVerifier};
-use crate::jwk::use_repeating_job::use_repeating_job;
+use crate::jwk::{discover_jwks_uri, fetch_keys, JwkConfiguration, JwkVerifier, OidcClaims};
 
-type CleanupFn = Box<dyn Fn() -> () + Send>;
+/// Wait before retrying a failed fetch, and the shortest key lifetime we accept
+const FETCH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// A token with an unknown `kid` triggers at most one refetch per interval
+const UNKNOWN_KID_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keys are refreshed once this share of their `max-age` has passed, ahead of expiry
+const REFRESH_AHEAD: f64 = 0.8;
 
+/// Verifies tokens from any of the configured issuers (Firebase, Cognito, Auth0, ...).
+///
+/// Built once per process and shared through `AppState`; clones share the same caches.
+/// A token is routed to its issuer by the `iss` claim. Each issuer's keys are fetched
+/// on first use, kept for the `max-age` of the response and refreshed in the background
+/// by `start_key_update` at 80% of it. Requests only wait on a fetch while there are
+/// no keys at all. Otherwise they use the cached keys, and a due refresh or an unknown
+/// `kid` starts a fetch in the background.
+#[derive(Clone)]
 pub struct JwkAuth {
-    verifier: Arc<Mutex<JwkVerifier>>,
-    cleanup: Mutex<CleanupFn>,
+    issuers: Arc<HashMap<String, IssuerKeys>>,
 }
 
-impl Drop for JwkAuth {
-    fn drop(&mut self) {
-        // Stop the update thread when the updater is destructed
-        let cleanup_fn = self.cleanup.lock().unwrap();
-        cleanup_fn();
+impl JwkAuth {
+    pub fn new(issuers: Vec<JwkConfiguration>) -> JwkAuth {
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        let issuers = issuers
+            .into_iter()
+            .map(|config| (config.issuer.clone(), IssuerKeys::new(config, client.clone())))
+            .collect();
+        JwkAuth { issuers: Arc::new(issuers) }
+    }
+
+    /// Trusts `keys` for every issuer instead of fetching them, for tests
+    #[cfg(test)]
+    pub fn with_keys(issuers: Vec<JwkConfiguration>, keys: Vec<crate::jwk::JwkKey>) -> JwkAuth {
+        let auth = JwkAuth::new(issuers);
+        for issuer_keys in auth.issuers.values() {
+            let inner = &issuer_keys.inner;
+            inner.verifier.try_write().expect("not shared yet").set_keys(keys.clone());
+            // Fresh, and an unknown `kid` doesn't trigger a fetch either
+            issuer_keys.update_state(|state| {
+                state.refresh_at = Some(Instant::now() + Duration::from_secs(24 * 60 * 60));
+                state.last_attempt = Some(Instant::now());
+            });
+        }
+        auth
+    }
+
+    pub async fn verify_jwt(&self, token: &str) -> Result<TokenData<OidcClaims>, AuthError> {
+        // Only used to pick the key set, the verifier checks `iss` again
+        let issuer = unverified_issuer(token).ok_or(AuthError::TokenDecodeError)?;
+        match self.issuers.get(&issuer) {
+            Some(keys) => keys.verify(token).await,
+            None => Err(AuthError::UnknownIssuerError),
+        }
+    }
+
+    /// Refreshes the keys on the tokio runtime until every `JwkAuth` clone is dropped
+    pub fn start_key_update(&self) {
+        for keys in self.issuers.values() {
+            keys.start_key_update();
+        }
     }
 }
 
-impl JwkAuth {
-    pub fn new() -> JwkAuth {
-        let jwk_key_result = jwk::fetch_keys();
-        let jwk_keys: JwkKeys = match jwk_key_result {
-            Ok(keys) => keys,
-            Err(_) => {
-                panic!("Unable to fetch jwk keys! Cannot verify user tokens! Shutting down...")
-            }
-        };
-        let verifier = Arc::new(Mutex::new(JwkVerifier::new(jwk_keys.keys)));
+#[derive(Deserialize)]
+struct IssuerClaim {
+    iss: String,
+}
 
-        let mut instance = JwkAuth {
-            verifier: verifier,
-            cleanup: Mutex::new(Box::new(|| {})),
-        };
+fn unverified_issuer(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let payload = BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<IssuerClaim>(&payload)
+        .ok()
+        .map(|claim| claim.iss)
+}
+
+/// Key cache of a single issuer
+#[derive(Clone)]
+struct IssuerKeys {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: JwkConfiguration,
+    client: reqwest::Client,
+    verifier: RwLock<JwkVerifier>,
+    /// Read by every request without waiting on a fetch in progress
+    state: StdRwLock<FetchState>,
+    /// Serialises fetches, so concurrent requests share one. Holds `jwk_url`,
+    /// or the `jwks_uri` found through discovery.
+    jwks_uri: Mutex<Option<String>>,
+    /// A fetch started by a request is running, so requests don't start more
+    refreshing: AtomicBool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FetchState {
+    /// `REFRESH_AHEAD` of the way to the end of the `max-age` of the keys
+    refresh_at: Option<Instant>,
+    /// When the last fetch finished, successful or not
+    last_attempt: Option<Instant>,
+}
+
+impl FetchState {
+    fn attempted_within(&self, interval: Duration) -> bool {
+        self.last_attempt.is_some_and(|attempt| attempt.elapsed() < interval)
+    }
+
+    /// Close to expiry or expired, and not already retried within the last few seconds
+    fn refresh_due(&self) -> bool {
+        let due = self.refresh_at.is_none_or(|refresh_at| Instant::now() >= refresh_at);
+        due && !self.attempted_within(FETCH_RETRY_INTERVAL)
+    }
+}
+
+impl IssuerKeys {
+    fn new(config: JwkConfiguration, client: reqwest::Client) -> IssuerKeys {
+        let verifier = JwkVerifier::new(vec![], config.clone());
+        let jwks_uri = Some(config.jwk_url.clone()).filter(|url| !url.is_empty());
 
-        instance.start_key_update();
-        instance
+        IssuerKeys {
+            inner: Arc::new(Inner {
+                config,
+                client,
+                verifier: RwLock::new(verifier),
+                state: StdRwLock::new(FetchState::default()),
+                jwks_uri: Mutex::new(jwks_uri),
+                refreshing: AtomicBool::new(false),
+            }),
+        }
     }
 
-    pub fn verify_firebase_jwt(&self, token: &String) -> Result<TokenData<FBTokenClaims>, AuthError> {
-        let verifier = self.verifier.lock().unwrap();
+    async fn verify(&self, token: &str) -> Result<TokenData<OidcClaims>, AuthError> {
+        let kid = match decode_header(token).map(|header| header.kid) {
+            Ok(Some(kid)) => kid,
+            _ => return Err(AuthError::TokenDecodeError),
+        };
+
+        if self.inner.verifier.read().await.has_keys() {
+            // Keep using the cached keys, even expired ones, while they are refreshed
+            self.refresh_in_background(FetchState::refresh_due);
+            // The issuer may have rotated in a key we haven't fetched yet
+            if !self.inner.verifier.read().await.has_key(&kid) {
+                self.refresh_in_background(|state| !state.attempted_within(UNKNOWN_KID_REFETCH_INTERVAL));
+            }
+        } else {
+            // Cold start, or every fetch so far has failed: there is nothing else to wait for
+            self.refresh_keys(FetchState::refresh_due).await;
+        }
+
+        let verifier = self.inner.verifier.read().await;
+        // Every fetch so far has failed, so the token can't be checked either way
+        if !verifier.has_keys() {
+            return Err(AuthError::KeysUnavailableError);
+        }
         verifier.verify(token)
     }
 
-    // pub fn verify_firebase_jwt(&self, token: &String) -> Option<TokenData<Claims>> {
-    //     let verifier = self.verifier.lock().unwrap();
-    //     verifier.verify(token)
-    // }
+    fn start_key_update(&self) {
+        let inner = Arc::downgrade(&self.inner);
+
+        tokio::spawn(async move {
+            // Only hold on to the cache while refreshing, so dropping it stops the loop
+            while let Some(inner) = inner.upgrade() {
+                let keys = IssuerKeys { inner };
+                keys.refresh_keys(FetchState::refresh_due).await;
+                let delay = keys.next_refresh();
+                drop(keys);
+                tokio::time::sleep(delay).await;
+            }
+        });
+    }
+
+    /// A snapshot of the expiry and last fetch, without waiting on a fetch in progress
+    fn state(&self) -> FetchState {
+        *self.inner.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update_state(&self, update: impl FnOnce(&mut FetchState)) {
+        update(&mut self.inner.state.write().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Starts `refresh_keys` on the runtime unless a request already started one
+    fn refresh_in_background(&self, needed: fn(&FetchState) -> bool) {
+        if !needed(&self.state()) || self.inner.refreshing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let keys = self.clone();
+        tokio::spawn(async move {
+            keys.refresh_keys(needed).await;
+            keys.inner.refreshing.store(false, Ordering::Release);
+        });
+    }
 
-    //  Old title verify
-    // pub fn verify(&self, token: &String) -> Option<TokenData<Claims>> {
-    //     let verifier = self.verifier.lock().unwrap();
-    //     verifier.verify(token)
-    // }
+    /// Fetches the keys if `needed` holds. The fetch lock is only taken when it
+    /// does, and `needed` is checked again once we have it, so callers that
+    /// waited on someone else's fetch don't fetch again.
+    async fn refresh_keys(&self, needed: impl Fn(&FetchState) -> bool) {
+        if !needed(&self.state()) {
+            return;
+        }
+        let mut jwks_uri = self.inner.jwks_uri.lock().await;
+        if !needed(&self.state()) {
+            return;
+        }
 
-    fn start_key_update(&mut self) {
-        let verifier_ref = Arc::clone(&self.verifier);
+        self.fetch(&mut jwks_uri).await;
+        self.update_state(|state| state.last_attempt = Some(Instant::now()));
+    }
 
-        let stop = use_repeating_job(move || match fetch_keys() {
+    async fn fetch(&self, jwks_uri: &mut Option<String>) {
+        let issuer = &self.inner.config.issuer;
+        let jwks_uri = match jwks_uri {
+            Some(jwks_uri) => jwks_uri.clone(),
+            None => match discover_jwks_uri(&self.inner.client, issuer).await {
+                Ok(discovered) => jwks_uri.insert(discovered).clone(),
+                Err(e) => {
+                    tracing::warn!("Unable to discover the JWK keys of {}: {}", issuer, e);
+                    return;
+                }
+            },
+        };
+
+        match fetch_keys(&self.inner.client, &jwks_uri).await {
             Ok(jwk_keys) => {
-                let mut verifier = verifier_ref.lock().unwrap();
-                verifier.set_keys(jwk_keys.keys);
-                println!(
-                    "Updated JWK keys. Next refresh will be in {:?}",
-                    jwk_keys.validity
-                );
-                jwk_keys.validity
+                let validity = jwk_keys.validity.max(FETCH_RETRY_INTERVAL);
+                self.inner.verifier.write().await.set_keys(jwk_keys.keys);
+                let refresh_in = validity.mul_f64(REFRESH_AHEAD);
+                self.update_state(|state| state.refresh_at = Some(Instant::now() + refresh_in));
+                tracing::info!("Updated JWK keys of {}. Next refresh will be in {:?}", issuer, refresh_in);
             }
-            Err(_) => Duration::from_secs(10),
-        });
+            Err(e) => tracing::warn!("Unable to fetch JWK keys from {}: {}", jwks_uri, e),
+        }
+    }
+
+    /// Until `refresh_at`, or the retry interval once it has passed
+    fn next_refresh(&self) -> Duration {
+        match self.state().refresh_at {
+            Some(refresh_at) if Instant::now() < refresh_at => refresh_at.saturating_duration_since(Instant::now()),
+            _ => FETCH_RETRY_INTERVAL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use crate::test_app::TestApp;
+    use super::*;
+
+    #[tokio::test]
+    async fn expired_keys_are_used_while_they_are_refreshed() {
+        let app = TestApp::new();
+        let token = app.firebase_token(json!({}));
+        let auth = app.state.jwk_auth().clone();
+
+        // A JWKS endpoint that takes the connection and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let jwks_uri = format!("http://{}/jwks", listener.local_addr().unwrap());
+        for keys in auth.issuers.values() {
+            *keys.inner.jwks_uri.try_lock().unwrap() = Some(jwks_uri.clone());
+            // Expired a moment ago
+            keys.update_state(|state| *state = FetchState { refresh_at: Some(Instant::now()), last_attempt: None });
+        }
 
-        let mut cleanup = self.cleanup.lock().unwrap();
-        *cleanup = stop;
+        let verified = tokio::time::timeout(Duration::from_secs(1), auth.verify_jwt(&token)).await;
+        assert!(verified.expect("the request waited on the fetch").is_ok());
+        // The refresh did start, in the background
+        tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.expect("no refresh").unwrap();
     }
 }
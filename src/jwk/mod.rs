@@ -2,13 +2,14 @@
 
 
 mod configuration;
+mod discovery;
 mod fetch_keys;
 mod get_max_age;
 mod jwk_auth;
-mod use_repeating_job;
 mod verifier;
 
 pub use configuration::*;
+pub use discovery::*;
 pub use fetch_keys::*;
 pub use jwk_auth::*;
 pub use verifier::*;
@@ -6,7 +6,7 @@
 use jsonwebtoken::decode_header;
 use jsonwebtoken::TokenData;
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use axum::http::StatusCode;
@@ -15,10 +15,12 @@ use crate::jwk;
 // use crate::auth::AuthError;
 use crate::jwk::{JwkConfiguration, JwkKey};
 
+/// Verified claims of an OIDC token, whichever provider issued it
 #[derive(Debug, Deserialize, Clone)]
-pub struct FBTokenClaims {
-    // The audience the token was issued for
-    pub aud: String,
+pub struct OidcClaims {
+    // The audiences the token was issued for, a single string in most ID tokens
+    #[serde(deserialize_with = "one_or_many")]
+    pub aud: Vec<String>,
     // The expiry date -- as epoch seconds
     pub exp: i64,
     // The token issuer
@@ -27,6 +29,26 @@ pub struct FBTokenClaims {
     pub sub: String,
     // Issued at -- as epoch seconds
     pub iat: i64,
+    // Missing for e.g. phone number sign ins
+    #[serde(default)]
+    pub email: Option<String>,
+    // Everything else, e.g. Firebase custom claims or `cognito:groups`
+    #[serde(flatten)]
+    pub custom_claims: serde_json::Map<String, serde_json::Value>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
 
 // enum VerificationError {
@@ -49,17 +71,18 @@ fn keys_to_map(keys: Vec<JwkKey>) -> HashMap<String, JwkKey> {
 }
 
 impl JwkVerifier {
-    pub fn new(keys: Vec<JwkKey>) -> JwkVerifier {
+    pub fn new(keys: Vec<JwkKey>, config: JwkConfiguration) -> JwkVerifier {
         JwkVerifier {
             keys: keys_to_map(keys),
-            config: jwk::get_configuration(),
+            config,
         }
     }
 
-    pub fn verify(&self, token: &String) -> Result<TokenData<FBTokenClaims>, AuthError> {
+    pub fn verify(&self, token: &str) -> Result<TokenData<OidcClaims>, AuthError> {
 
-        let token_kid = match decode_header(token).map(|header| header.kid) {
-            Ok(Some(header)) => header,
+        let header = decode_header(token).map_err(|_| AuthError::TokenDecodeError)?;
+        let token_kid = match header.kid {
+            Some(kid) => kid,
             _ => return Err(AuthError::TokenDecodeError ),
         };
 
@@ -70,7 +93,7 @@ impl JwkVerifier {
         };
 
 
-        let token_data = self.decode_token_with_key(jwk_key, token)?;
+        let token_data = self.decode_token_with_key(jwk_key, header.alg, token)?;
         Ok(token_data)
 
         // match self.decode_token_with_key(jwk_key, token) {
@@ -114,6 +137,14 @@ impl JwkVerifier {
         self.keys = keys_to_map(keys);
     }
 
+    pub fn has_key(&self, key_id: &str) -> bool {
+        self.keys.contains_key(key_id)
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
     fn get_key(&self, key_id: String) -> Option<&JwkKey> {
         self.keys.get(&key_id)
     }
@@ -121,15 +152,25 @@ impl JwkVerifier {
     fn decode_token_with_key(
         &self,
         key: &JwkKey,
-        token: &String,
-    ) -> Result<TokenData<FBTokenClaims>, VerificationError> {
-        let algorithm = match Algorithm::from_str(&key.alg) {
-            Ok(alg) => alg,
-            Err(_error) => return Err(VerificationError::UnknownKeyAlgorithm),
+        token_algorithm: Algorithm,
+        token: &str,
+    ) -> Result<TokenData<OidcClaims>, VerificationError> {
+        let algorithm = if key.alg.is_empty() {
+            // `alg` is optional in a JWK, so go by the token as long as it suits the key type
+            if !key_type_allows(&key.kty, token_algorithm) {
+                return Err(VerificationError::AlgorithmMismatch);
+            }
+            token_algorithm
+        } else {
+            match Algorithm::from_str(&key.alg) {
+                Ok(alg) => alg,
+                Err(_error) => return Err(VerificationError::UnknownKeyAlgorithm),
+            }
         };
 
         let mut validation = Validation::new(algorithm);
         validation.set_audience(&[&self.config.audience]);
+        validation.validate_nbf = true;
 
         // Modified this so validation.iss could be a hashset
         // validation.iss = Some(self.config.issuer.clone());
@@ -137,11 +178,11 @@ impl JwkVerifier {
         // validation_iss.insert(self.config.issuer.clone());
         // validation.iss = Some(validation_iss);
 
-        validation.set_issuer(&[self.config.issuer.clone()]) ;
+        validation.set_issuer(std::slice::from_ref(&self.config.issuer));
 
-        let key = DecodingKey::from_rsa_components(&key.n, &key.e);
-        return decode::<FBTokenClaims>(token, &key.unwrap(), &validation)
-            .map_err(|_| VerificationError::InvalidSignature);
+        let key = crate::signing_keys::decoding_key(key).map_err(|_| VerificationError::InvalidKey)?;
+        decode::<OidcClaims>(token, &key, &validation)
+            .map_err(VerificationError::from)
     }
 
     // fn decode_token_with_key(
@@ -162,3 +203,14 @@ impl JwkVerifier {
     //         .map_err(|_| VerificationError::InvalidSignature);
     // }
 }
+
+fn key_type_allows(kty: &str, algorithm: Algorithm) -> bool {
+    use Algorithm::*;
+
+    match kty {
+        "RSA" => matches!(algorithm, RS256 | RS384 | RS512 | PS256 | PS384 | PS512),
+        "EC" => matches!(algorithm, ES256 | ES384),
+        "OKP" => algorithm == EdDSA,
+        _ => false,
+    }
+}
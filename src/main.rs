@@ -1,16 +1,34 @@
 
 #![allow(unused)]
 pub mod user;
+mod api_error;
+mod app_state;
 mod auth;
+mod authorization;
+mod command;
+mod config;
 mod jwk;
 pub mod dynamo;
 mod dynamo_query_helpers;
-mod error;
+#[cfg(test)]
+mod dynamo_fake;
+#[cfg(test)]
+mod dynamo_replay;
+mod etag;
 mod modyne;
+mod refresh_token;
+mod repository;
 mod item;
 mod user_table;
 mod user_table_handlers;
+mod validation;
 mod item_handlers;
+mod provision;
+mod seed;
+mod server;
+mod signing_keys;
+#[cfg(test)]
+mod test_app;
 
 use crate::item_handlers::*;
 use crate::user::create_user;
@@ -31,14 +49,18 @@ use std::env;
 use std::env::set_var;
 use std::net::SocketAddr;
 
+use crate::app_state::AppState;
+use crate::config::Config;
+use crate::command::Command;
+use crate::server::RunMode;
 use crate::auth::{AuthError, CurrentUser};
+use crate::authorization::{RequireRole, RequireScope};
 // use crate::dynamo::{dynamo_add_item_rest, dynamo_call, query_items_by_key_username_rest, query_items_by_field_rest, query_items_by_scan_serde_rest, dynamo_add_item_rest_serde, delete_items_by_key_username_rest, query_accountusers_handler, query_account_users_by_date_range_handler, create_user_table_serde_rest_handler, delete_user_table_serde_rest_handler, update_user_table_serde_rest_handler};
-use crate::dynamo_query_helpers::query_items_key_attribute_value_serde;
 // use crate::dynamo::dynamo;
-use crate::jwk::FBTokenClaims;
+use crate::jwk::OidcClaims;
 use axum::http::Request;
 use axum::middleware::Next;
-use axum::routing::{delete, put};
+use axum::routing::{delete, patch, put};
 use axum::{body::Body, middleware, response::{IntoResponse, Response}, Extension};
 use jsonwebtoken::TokenData;
 
@@ -50,6 +72,9 @@ pub enum SystemError {
 }
 
 // Run Locally: cargo lambda watch --invoke-port 9003
+//  or as a standalone server: cargo run -- --listen 127.0.0.1:8080
+// Create or update the DynamoDB tables: cargo run -- provision [--model AccountUser.json] [--dry-run]
+// Load the sample items of AccountUser.json: cargo run -- seed [--clear]
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     // Running axum as an AWS cloud function
@@ -61,7 +86,43 @@ async fn main() -> Result<(), Error> {
     // required to enable CloudWatch error logging by the runtime
     tracing::init_default_subscriber();
 
-    let app = Router::new()
+    // `provision` and `seed` only need the table names, not the JWT or JWK settings
+    if let Some(command) = Command::from_args()? {
+        let config = Config::read().map_err(|e| format!("invalid configuration: {e}"))?;
+        let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        command.run(aws_sdk_dynamodb::Client::new(&aws_config), &config.tables).await?;
+        return Ok(());
+    }
+
+    // Fail fast on missing settings rather than at the first request
+    let config = Config::load().map_err(|e| format!("invalid configuration: {e}"))?;
+
+    // Built once and shared by every handler, so the DynamoDB client
+    // is reused across invocations instead of being created per request
+    let state = AppState::from_config(config)
+        .await
+        .map_err(|e| format!("invalid signing keys: {e}"))?;
+
+    let app = build_router(state);
+
+    match server::run_mode()? {
+        // On AWS Lambda, or under `cargo lambda watch`
+        RunMode::Lambda => run(app).await,
+        // Anywhere else run the same router on a plain axum server,
+        // e.g. `cargo run -- --listen 0.0.0.0:8080`
+        RunMode::Standalone(addr) => {
+            info!("No Lambda runtime detected, running standalone server.");
+            server::serve_standalone(app, addr).await?;
+            Ok(())
+        }
+    }
+}
+
+
+/// Every route of the API, shared by the Lambda runtime, the standalone
+/// server and the router tests
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
         .route("/", get(root))
 
         // ******** DynamoDb Handlers ********
@@ -69,23 +130,56 @@ async fn main() -> Result<(), Error> {
         // See the Readme.txt file for loading a sample UserTable
         // into DynamoDb to run these commands and queries
 
+        // Creating, updating and deleting UserTable entities needs a Firebase token
+        // with the "orders:write" permission, see authorization::AuthClaims
         // Creates a UserTable Entity
         .route(
             "/create_user_table_entity",
             post(create_user_table_serde_rest_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
         )
         .route(
             "/update_user_table_entity",
             put(update_user_table_serde_rest_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
+        )
+        // Many orders by key in one request
+        .route("/user_table/batch_get", post(batch_get_user_table_handler))
+        // Puts and deletes many orders, not atomically
+        .route(
+            "/user_table/batch",
+            post(batch_write_user_table_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
+        )
+        // Creates, updates, deletes and checks orders all or nothing
+        .route(
+            "/user_table/transaction",
+            post(order_transaction_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
+        )
+        // Partial update, returns the updated UserTable Entity
+        .route(
+            "/user_table/:user/:order",
+            patch(patch_user_table_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
         )
         // Queries UserTable using key of User, manual
         .route(
             "/dynamo_query_serde_by_key_user_table/:user/:order",
             get(query_items_by_key_account_user_rest),
         )
+        // Deleting also needs the "admin" role
         .route(
             "/delete_user_table_entity/:user_id/:order_id",
             delete(delete_user_table_serde_rest_handler)
+                .layer(RequireScope("orders:write"))
+                .layer(RequireRole("admin"))
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
         )
         // Queries for User_Table
         // curl -H "Content-Type: application/json" \
@@ -114,7 +208,7 @@ async fn main() -> Result<(), Error> {
         .route(
             "/get_fb_token_claims",
             get(get_fb_token_claims)
-                .layer(middleware::from_fn(auth::authorize_firebase)),
+                .layer(middleware::from_fn_with_state(state.clone(), auth::authorize_firebase)),
         )
         // .layer(middleware::from_fn(logging_middleware))
 
@@ -181,19 +275,30 @@ async fn main() -> Result<(), Error> {
         // Example to create a user.
         // curl --header "Content-Type: application/json" \
         // --request POST \
-        // --data '{"username":"Mike"}' \
+        // --data '{"email":"mike@example.com","password":"secret","first_name":"Mike","last_name":"Smith"}' \
         // http://localhost:9003/create_user
         .route("/create_user", post(create_user))
         // Returns a token after signing in
-        // with SignInData in POST. Hardcoded user with
-        // Email: email: "myemail@gmail.com", password: "okon"
+        // with SignInData in POST, for a user
+        // registered through /create_user
         .route("/signin", post(auth::sign_in))
+        // Exchanges the refresh_token returned by /signin for new tokens.
+        // curl --header "Content-Type: application/json" \
+        // --request POST \
+        // --data '{"refresh_token":"REFRESH_TOKEN_FROM_SIGNIN"}' \
+        // http://localhost:9003/token/refresh
+        .route("/token/refresh", post(auth::refresh_token))
+        // Revokes the refresh token and all tokens rotated from the same sign in
+        .route("/signout", post(auth::sign_out))
+        // Public keys of the tokens issued by /signin, for other services to verify them
+        // with JwkVerifier. Empty while tokens are signed with the HS256 jwt.secret.
+        .route("/.well-known/jwks.json", get(auth::jwks))
         // The authorize middleware is getting the current user from
         //  the token and calling the hello function and placing
         //  the user in the function call as an extension parameter
         .route(
             "/get_user_custom_token",
-            get(hello).layer(middleware::from_fn(auth::authorize)),
+            get(hello).layer(middleware::from_fn_with_state(state.clone(), auth::authorize)),
         )
 
 
@@ -204,39 +309,9 @@ async fn main() -> Result<(), Error> {
         .route("/parameters", get(get_parameters))
         .route("/health/", get(health_check))
 
-        ;
-
-    run(app).await
-
-
-
-    // To run locally on an axum server and deploy using Docker,
-    // uncomment the following code and add axum_server dependency
-    // You can then test locally and deploy as Docker image.
-    //
-    // // If app can't find an AWS LAMBDA Environment variable,
-    // // Then switch to using a local server.
-    // match env::var("AWS_LAMBDA_FUNCTION_NAME") {
-    //     Ok(_) => {
-    //         // To run just on Lambda
-    //         run(app).await
-    //     }
-    //     Err(err) => {
-    //         // Run app in locally if not on lambda
-    //         info!( "No env var for lambda: {}, running locally." ,err);
-    //         let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    //         info!("listening on {}", addr);
-    //         axum_server::bind(addr)
-    //             .serve(app.into_make_service())
-    //             .await
-    //             .unwrap();
-    //         Ok(())
-    //
-    //     }
-    // }
+        .with_state(state)
 }
 
-
 async fn root() -> Json<Value> {
     Json(json!({ "msg": "I am GET /" }))
 }
@@ -277,16 +352,16 @@ struct UserResponse {
 // The authorize function places the currentUser in the extension
 // and moves this from the middleware result to the hello function parameter.
 // pub async fn hello(Extension(currentUser): Extension<CurrentUser>) -> impl IntoResponse {
-pub async fn hello(Extension(currentUser): Extension<CurrentUser>) -> impl IntoResponse {
+pub async fn hello(Extension(current_user): Extension<CurrentUser>) -> impl IntoResponse {
     Json(UserResponse {
-        email: currentUser.email,
-        first_name: currentUser.first_name,
-        last_name: currentUser.last_name
+        email: current_user.email,
+        first_name: current_user.first_name,
+        last_name: current_user.last_name
     })
 }
 #[derive(Serialize, Deserialize)]
 struct ClaimsResponse {
-    aud: String,
+    aud: Vec<String>,
     sub: String,
     iss: String
 }
@@ -294,7 +369,7 @@ struct ClaimsResponse {
 // Extensions are used to pass local state
 // and moves this from the middleware result to the hello function parameter.
 // pub async fn hello(Extension(currentUser): Extension<CurrentUser>) -> impl IntoResponse {
-pub async fn get_fb_token_claims(Extension(token_claims): Extension<TokenData<FBTokenClaims>>) -> impl IntoResponse {
+pub async fn get_fb_token_claims(Extension(token_claims): Extension<TokenData<OidcClaims>>) -> impl IntoResponse {
     Json(ClaimsResponse {
         aud: token_claims.claims.aud,
         sub: token_claims.claims.sub,
@@ -331,3 +406,112 @@ async fn logging_middleware(req: Request<Body>, next: Next) -> Response {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use crate::test_app::{TestApp, TestRequest, FIREBASE_PROJECT};
+
+    #[tokio::test]
+    async fn custom_tokens_authorize_registered_users() {
+        let app = TestApp::new();
+        let user = app.register_user("ada@example.com", &[], &[]).await;
+
+        let response = app.send(TestRequest::get("/get_user_custom_token").bearer(&app.custom_token(&user))).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.json(), json!({"email": "ada@example.com", "first_name": "Ada", "last_name": "Lovelace"}));
+
+        let response = app.send(TestRequest::get("/get_user_custom_token")).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.header("www-authenticate"), Some("Bearer"));
+        assert_eq!(response.header("content-type"), Some("application/problem+json"));
+        assert_eq!(response.json()["code"], "missing_token");
+
+        let mut stranger = user.clone();
+        stranger.email = "eve@example.com".to_string();
+        let response = app.send(TestRequest::get("/get_user_custom_token").bearer(&app.custom_token(&stranger))).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.json()["code"], "unknown_user");
+    }
+
+    #[tokio::test]
+    async fn firebase_tokens_are_verified_against_the_jwks() {
+        let app = TestApp::new();
+
+        let response = app.send(TestRequest::get("/get_fb_token_claims").bearer(&app.firebase_token(json!({})))).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.json()["aud"], json!([FIREBASE_PROJECT]));
+        assert_eq!(response.json()["sub"], "firebase-user");
+
+        let other_project = app.firebase_token(json!({"aud": "other-project"}));
+        let response = app.send(TestRequest::get("/get_fb_token_claims").bearer(&other_project)).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.json()["code"], "wrong_audience");
+
+        let long_ago = chrono::Utc::now().timestamp() - 7200;
+        let expired = app.firebase_token(json!({"iat": long_ago, "exp": long_ago + 3600}));
+        let response = app.send(TestRequest::get("/get_fb_token_claims").bearer(&expired)).await;
+        assert_eq!(response.json()["code"], "token_expired");
+        assert!(response.header("www-authenticate").unwrap().contains("invalid_token"));
+    }
+
+    #[tokio::test]
+    async fn orders_need_the_write_scope_and_honour_if_match() {
+        let app = TestApp::new();
+        let order = json!({"UserId": "user7", "OrderId": "order1", "product": "p#prod1", "price": 1.5});
+
+        let reader = app.firebase_token(json!({}));
+        let response = app.send(TestRequest::post("/create_user_table_entity", order.clone()).bearer(&reader)).await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.json()["code"], "insufficient_scope");
+
+        let writer = app.firebase_token(json!({"permissions": ["orders:write"]}));
+        let response = app.send(TestRequest::post("/create_user_table_entity", order.clone()).bearer(&writer)).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header("etag"), Some("\"1\""));
+        let response = app.send(TestRequest::post("/create_user_table_entity", order).bearer(&writer)).await;
+        assert_eq!(response.status, StatusCode::CONFLICT);
+
+        let response = app.send(TestRequest::get("/dynamo_query_serde_by_key_user_table/user7/order1")).await;
+        assert_eq!(response.header("etag"), Some("\"1\""));
+        assert_eq!(response.json()["UserId"], "u#user7");
+
+        let patch = || TestRequest::patch("/user_table/user7/order1", json!({"price_change": 1.0})).header("if-match", "\"1\"").bearer(&writer);
+        let response = app.send(patch()).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header("etag"), Some("\"2\""));
+        assert_eq!(response.json()["price"], 2.5);
+
+        let response = app.send(patch()).await;
+        assert_eq!(response.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(response.json()["code"], "precondition_failed");
+
+        let response = app.send(TestRequest::delete("/delete_user_table_entity/user7/order1").bearer(&writer)).await;
+        assert_eq!(response.json()["code"], "insufficient_role");
+    }
+
+    #[tokio::test]
+    async fn order_queries_return_the_next_page_in_app_token() {
+        let app = TestApp::new();
+        let writer = app.firebase_token(json!({"permissions": ["orders:write"]}));
+        for order in ["order1", "order2", "order3"] {
+            let order = json!({"UserId": "user7", "OrderId": order, "product": "p#prod1", "price": 1.0});
+            app.send(TestRequest::post("/create_user_table_entity", order).bearer(&writer)).await;
+        }
+
+        let response = app.send(TestRequest::get("/dynamo_query_accountusers_handler?page_size=2")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let orders: Vec<_> = response.json().as_array().unwrap().iter().map(|order| order["OrderId"].clone()).collect();
+        assert_eq!(orders, [json!("o#order3"), json!("o#order2")]);
+        let token = response.header("app-token").expect("a next page").to_string();
+
+        let response = app.send(TestRequest::get(&format!("/dynamo_query_accountusers_handler?page_size=2&token={token}"))).await;
+        assert_eq!(response.json().as_array().unwrap().len(), 1);
+        assert_eq!(response.header("app-token"), None);
+
+        let response = app.send(TestRequest::get("/dynamo_query_accountusers_handler?token=not-a-token")).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.json()["code"], "invalid_page_token");
+    }
+}
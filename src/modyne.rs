@@ -2,7 +2,6 @@ use std::collections::HashMap;
 use std::future::Future;
 use std::str::FromStr;
 use aliri_braid::braid;
-use aws_config::BehaviorVersion;
 use aws_sdk_dynamodb::Client;
 use aws_sdk_dynamodb::operation::create_table::{CreateTableError, CreateTableOutput};
 use modyne::{expr, keys, types::Expiry, Aggregate, Entity, EntityDef, EntityExt, Error, Projection, ProjectionExt, QueryInput, QueryInputExt, Table, EntityTypeNameRef};
@@ -14,10 +13,7 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(client: aws_sdk_dynamodb::Client) -> Self {
-        Self::new_with_table(client, "SessionStore")
-    }
-
+    /// The table name comes from `config.tables.sessions`
     pub fn new_with_table(client: aws_sdk_dynamodb::Client, table_name: &str) -> Self {
         Self {
             table_name: std::sync::Arc::from(table_name),
@@ -54,9 +50,58 @@ impl App {
     }
 
 
-    pub async fn update_session(&self, session: Session) -> Result<(), Error> {
-        Session::replace(session).execute(self).await?;
-        Ok(())
+    /// Replaces a session that is still at `session.version` and returns it
+    /// at the next version. Fails with a conditional check error if the session
+    /// is gone or another write got there first.
+    pub async fn update_session(&self, mut session: Session) -> Result<Session, Error> {
+        let expected = session.version;
+        session.version += 1;
+
+        // Sessions written before versioning have no version, they count as 0
+        let condition = if expected == 0 {
+            "attribute_exists(#PK) AND (attribute_not_exists(#version) OR #version = :expected)"
+        } else {
+            "attribute_exists(#PK) AND #version = :expected"
+        };
+        session
+            .clone()
+            .put()
+            .condition(
+                expr::Condition::new(condition)
+                    .name("#PK", "session_token")
+                    .name("#version", "version")
+                    .value(":expected", expected),
+            )
+            .execute(self)
+            .await?;
+        Ok(session)
+    }
+
+    /// Sets the username of an existing session in one UpdateItem call, at the
+    /// next version so `update_session` calls holding the old one fail.
+    /// Returns the updated session, or `None` if there is no such session.
+    pub async fn update_session_username(
+        &self,
+        session_token: uuid::Uuid,
+        username: Username,
+    ) -> Result<Option<Session>, Error> {
+        let result = Session::update(session_token)
+            .expression(
+                expr::Update::new("SET #username = :username ADD #version :one")
+                    .name("#username", "username")
+                    .name("#version", "version")
+                    .value(":username", username)
+                    .value(":one", 1u64),
+            )
+            .condition(expr::Condition::new("attribute_exists(#PK)").name("#PK", "session_token"))
+            .execute_with_return(self, aws_sdk_dynamodb::types::ReturnValue::AllNew)
+            .await;
+
+        match result.map_err(Error::from) {
+            Ok(output) => output.attributes.map(Session::from_item).transpose(),
+            Err(e) if e.is_conditional_check_failed_exception() => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
 
@@ -96,6 +141,76 @@ impl App {
         }
     }
 
+    /// Starts a new refresh token family with its first token
+    pub async fn create_refresh_token_family(
+        &self,
+        family: RefreshTokenFamily,
+        token: RefreshToken,
+    ) -> Result<(), Error> {
+        TransactWrite::new()
+            .operation(family.create())
+            .operation(token.create())
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_refresh_token(&self, token_id: uuid::Uuid) -> Result<Option<RefreshToken>, Error> {
+        let result = RefreshToken::get(token_id).execute(self).await?;
+        result.item.map(RefreshToken::from_item).transpose()
+    }
+
+    pub async fn get_refresh_token_family(
+        &self,
+        family_id: uuid::Uuid,
+    ) -> Result<Option<RefreshTokenFamily>, Error> {
+        let result = RefreshTokenFamily::get(family_id).execute(self).await?;
+        result.item.map(RefreshTokenFamily::from_item).transpose()
+    }
+
+    /// Marks `used_token_id` as used and stores its replacement in one transaction.
+    ///
+    /// Fails with a conditional check error if the old token was already used or
+    /// the family has been revoked, which callers must treat as token reuse.
+    pub async fn rotate_refresh_token(
+        &self,
+        used_token_id: uuid::Uuid,
+        replacement: RefreshToken,
+    ) -> Result<(), Error> {
+        let mark_used = RefreshToken::update(used_token_id)
+            .expression(expr::Update::new("SET #used = :used").name("#used", "used").value(":used", true))
+            .condition(expr::Condition::new("#used = :unused").name("#used", "used").value(":unused", false));
+
+        // Keep the family around for as long as its newest token
+        let extend_family = RefreshTokenFamily::update(replacement.family_id)
+            .expression(expr::Update::new("SET #ttl = :ttl").name("#ttl", "ttl").value(":ttl", replacement.ttl))
+            .condition(expr::Condition::new("#revoked = :revoked").name("#revoked", "revoked").value(":revoked", false));
+
+        TransactWrite::new()
+            .operation(mark_used)
+            .operation(replacement.create())
+            .operation(extend_family)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes every refresh token of the family
+    pub async fn revoke_refresh_token_family(&self, family_id: uuid::Uuid) -> Result<(), Error> {
+        let result = RefreshTokenFamily::update(family_id)
+            .expression(expr::Update::new("SET #revoked = :revoked").name("#revoked", "revoked").value(":revoked", true))
+            .condition(expr::Condition::new("attribute_exists(#PK)").name("#PK", "session_token"))
+            .execute(self)
+            .await;
+
+        match result.map_err(Error::from) {
+            Ok(_) => Ok(()),
+            // Nothing to revoke
+            Err(e) if e.is_conditional_check_failed_exception() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     // pub async fn delete_user_sessions(&self, user: &UsernameRef) -> Result<(), Error> {
     //     let mut joiner = tokio::task::JoinSet::new();
     //     loop {
@@ -187,6 +302,9 @@ pub struct Session {
     #[serde(with = "time::serde::rfc3339")]
     pub expires_at: time::OffsetDateTime,
     pub ttl: Expiry,
+    /// Incremented by `App::update_session`
+    #[serde(default)]
+    pub version: u64,
 }
 
 impl Entity for Session {
@@ -223,38 +341,134 @@ impl EntityDef for Session {
         "created_at",
         "expires_at",
         "ttl",
+        "version",
     ];
 }
 
+/// A refresh token of the custom JWT flow. Only a SHA-256 hash of the
+/// token's secret is stored; the token itself is handed to the client once.
+///
+/// Each token can be exchanged exactly once, after which `used` is set and a
+/// new token of the same family is issued.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RefreshToken {
+    pub session_token: uuid::Uuid,
+    pub username: Username,
+    pub family_id: uuid::Uuid,
+    pub secret_hash: String,
+    pub used: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: time::OffsetDateTime,
+    pub ttl: Expiry,
+}
+
+impl Entity for RefreshToken {
+    type KeyInput<'a> = uuid::Uuid;
+    type Table = App;
+    type IndexKeys = UsernameKey;
 
-pub async fn get_session_modyne_handler(
-    axum::extract::Path((session_id)): axum::extract::Path<(String)>,
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let app = App::new(client);
-
-    match get_session_modyne(app, session_id.as_str()).await {
-        Ok(session) => match session {
-            Some(session) => {axum::Json(session).into_response()}
-            None => {
-                StatResp::new("failure",
-                              "not found",
-                              StatusCode::OK).into_response()
-            }
+    fn primary_key(input: Self::KeyInput<'_>) -> SessionToken {
+        SessionToken {
+            session_token: input,
+        }
+    }
+
+    fn full_key(&self) -> keys::FullKey<SessionToken, Self::IndexKeys> {
+        keys::FullKey {
+            primary: Self::primary_key(self.session_token),
+            indexes: UsernameKey {
+                username: self.username.clone(),
+            },
+        }
+    }
+}
+
+impl EntityDef for RefreshToken {
+    const ENTITY_TYPE: &'static EntityTypeNameRef =
+        EntityTypeNameRef::from_static("RefreshToken");
+
+    const PROJECTED_ATTRIBUTES: &'static [&'static str] = &[
+        "session_token",
+        "username",
+        "family_id",
+        "secret_hash",
+        "used",
+        "created_at",
+        "expires_at",
+        "ttl",
+    ];
+}
+
+/// All refresh tokens rotated from one sign in share a family, keyed by `family_id`.
+/// Revoking the family invalidates every token in it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RefreshTokenFamily {
+    pub session_token: uuid::Uuid,
+    pub username: Username,
+    pub revoked: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    pub ttl: Expiry,
+}
+
+impl Entity for RefreshTokenFamily {
+    type KeyInput<'a> = uuid::Uuid;
+    type Table = App;
+    type IndexKeys = UsernameKey;
+
+    fn primary_key(input: Self::KeyInput<'_>) -> SessionToken {
+        SessionToken {
+            session_token: input,
         }
-        Err(e) => {
-            dbg!("{:?}", &e);
-            StatResp::new("failure",
-                          e.to_string().as_str(),
-                          StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    }
+
+    fn full_key(&self) -> keys::FullKey<SessionToken, Self::IndexKeys> {
+        keys::FullKey {
+            primary: Self::primary_key(self.session_token),
+            indexes: UsernameKey {
+                username: self.username.clone(),
+            },
         }
     }
 }
 
-pub async fn get_session_modyne(app: App, uuid_str: &str) -> Result<Option<Session>, anyhow::Error> {
+impl EntityDef for RefreshTokenFamily {
+    const ENTITY_TYPE: &'static EntityTypeNameRef =
+        EntityTypeNameRef::from_static("RefreshTokenFamily");
+
+    const PROJECTED_ATTRIBUTES: &'static [&'static str] = &[
+        "session_token",
+        "username",
+        "revoked",
+        "created_at",
+        "ttl",
+    ];
+}
+
+
+pub async fn get_session_modyne_handler(
+    State(state): State<AppState>,
+    axum::extract::Path((session_id)): axum::extract::Path<(String)>,
+) -> Result<Json<Session>, ApiError> {
+    let app = state.session_app();
+
+    get_session_modyne(app, parse_session_id(&session_id)?)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No session found"))
+}
+
+/// Session ids are UUIDs, anything else is a 400
+fn parse_session_id(session_id: &str) -> Result<Uuid, ApiError> {
+    Uuid::from_str(session_id)
+        .map_err(|_| ApiError::bad_request("invalid_session_id", "Session id must be a UUID"))
+}
+
+pub async fn get_session_modyne(app: App, session_token: Uuid) -> Result<Option<Session>, anyhow::Error> {
     let session = app
-            .get_any_session(uuid::Uuid::from_str(uuid_str).unwrap())
+            .get_any_session(session_token)
             .await?;
     Ok(session)
 }
@@ -262,37 +476,27 @@ pub async fn get_session_modyne(app: App, uuid_str: &str) -> Result<Option<Sessi
 
 
 pub async fn create_session_modyne_handler(
+    State(state): State<AppState>,
     // axum::extract::Path((session_id)): axum::extract::Path<(String)>,
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let app = App::new(client);
-
-    match create_session_modyne(app).await {
-        Ok(session_token) => {
-            StatResp::new("success",
-                          session_token.to_string().as_str(),
-                          StatusCode::OK).into_response()
-        }
+) -> Result<StatResp, ApiError> {
+    let app = state.session_app();
 
-        Err(e) => {
-            dbg!("{:?}", &e);
-            StatResp::new("failure",
-                          e.to_string().as_str(),
-                          StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
-    }
+    let session_token = create_session_modyne(app).await?;
+    Ok(StatResp::new("success",
+                     session_token.to_string().as_str(),
+                     StatusCode::OK))
 }
 
 // pub async fn create_session_modyne(app: App, uuid_str: &str) -> Result<uuid::Uuid, anyhow::Error> {
 pub async fn create_session_modyne(app: App) -> Result<uuid::Uuid, anyhow::Error> {
     let session_token =  uuid::Uuid::new_v4();
     let session = Session {
-        session_token: session_token,
-        username: Username::from(format!("mtest_{}", 3 % 13)),
+        session_token,
+        username: Username::from(format!("mtest_{}", 3)),
         created_at: time::OffsetDateTime::now_utc(),
         expires_at: time::OffsetDateTime::now_utc(),
         ttl: Expiry::from(time::OffsetDateTime::now_utc()),
+        version: 1,
     };
     app.create_session(session).await?;
     Ok(session_token)
@@ -302,107 +506,125 @@ pub async fn create_session_modyne(app: App) -> Result<uuid::Uuid, anyhow::Error
 /// Updates the session username - Looks up the session by id,
 /// Changes the username and updates the whole Session
 pub async fn update_session_username_modyne_handler(
+    State(state): State<AppState>,
     axum::extract::Path((session_id, username)): axum::extract::Path<(String, String)>,
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let app = App::new(client);
-
-    match crate::modyne::update_session_username_modyne(app, session_id.clone(), username).await {
-        Ok(session_token) => {
-            StatResp::new("success",
-                          format!("updated item: {}", session_id.as_str()).as_str(),
-                          StatusCode::OK).into_response()
-        }
+) -> Result<StatResp, ApiError> {
+    let app = state.session_app();
 
-        Err(e) => {
-            dbg!("{:?}", &e);
-            StatResp::new("failure",
-                          e.to_string().as_str(),
-                          StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
-    }
-}
+    crate::modyne::update_session_username_modyne(app, parse_session_id(&session_id)?, username)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No session found"))?;
 
-pub async fn update_session_username_modyne(app: App, session_id: String, username: String) -> Result<(), anyhow::Error> {
-    let session_query_result = get_session_modyne(app.clone(), session_id.as_str()).await?;
-
-    if let Some(mut session) = session_query_result {
-        session.username = Username::from(username);
-        update_session_modyne(app, session).await?;
-        Ok(())
-    } else {
-        Err(DynErrorExp {exp: "None found".to_string()})?
-    }
+    Ok(StatResp::new("success",
+                     format!("updated item: {}", session_id.as_str()).as_str(),
+                     StatusCode::OK))
 }
 
-/// Update the session username
-pub async fn update_session_modyne(app: App, session: Session) -> Result<(), anyhow::Error> {
-    // let uuid = Uuid::from_str(uuid_str)?;
-    app.update_session(session).await?;
-    Ok(())
+/// Returns the updated session, or `None` if there is no session with this id
+pub async fn update_session_username_modyne(app: App, session_token: Uuid, username: String) -> Result<Option<Session>, anyhow::Error> {
+    Ok(app.update_session_username(session_token, Username::from(username)).await?)
 }
 
 
 pub async fn delete_session_modyne_handler(
+    State(state): State<AppState>,
     axum::extract::Path((session_id)): axum::extract::Path<(String)>,
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let app = App::new(client);
-
-    match crate::modyne::delete_session_modyne(app, session_id.as_str()).await {
-        Ok(session_token) => {
-            StatResp::new("success",
-                          format!("deleted item: {}", session_id.as_str()).as_str(),
-                          StatusCode::OK).into_response()
-        }
+) -> Result<StatResp, ApiError> {
+    let app = state.session_app();
 
-        Err(e) => {
-            dbg!("{:?}", &e);
-            StatResp::new("failure",
-                          e.to_string().as_str(),
-                          StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
-    }
+    crate::modyne::delete_session_modyne(app, parse_session_id(&session_id)?).await?;
+    Ok(StatResp::new("success",
+                     format!("deleted item: {}", session_id.as_str()).as_str(),
+                     StatusCode::OK))
 }
 
-pub async fn delete_session_modyne(app: App, uuid_str: &str) -> Result<(), anyhow::Error> {
-    let uuid = Uuid::from_str(uuid_str)?;
-    app.delete_session(uuid).await?;
+pub async fn delete_session_modyne(app: App, session_token: Uuid) -> Result<(), anyhow::Error> {
+    app.delete_session(session_token).await?;
     Ok(())
 }
 
-pub async fn blah() -> impl IntoResponse{
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-
-    let app = App::new(client);
-
-    match app.get_any_session(uuid::Uuid::from_str("07b2bc80-1caa-400b-9aea-090819f49937").unwrap())
-        .await {
-            Ok(session) => {{println!("Session Output: {:?}", session)}}
-            Err(e) => {println!("ERROR {:?}", e)}
-    }
-
-
+pub async fn blah(State(state): State<AppState>) -> Result<(), ApiError> {
+    let app = state.session_app();
 
+    let session = app.get_any_session(uuid!("07b2bc80-1caa-400b-9aea-090819f49937")).await?;
+    println!("Session Output: {:?}", session);
+    Ok(())
 }
 
 
 use aws_sdk_dynamodb::types::TimeToLiveSpecification;
+use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use lambda_runtime::IntoFunctionResponse;
 use modyne::{
 
-    model::{BatchGet, BatchWrite},
+    model::{BatchGet, BatchWrite, TransactWrite},
 
   TestTableExt,
 };
 use uuid::{uuid, Uuid};
-use crate::dynamo::DynamoError::{DynError, DynErrorExp};
+use axum::Json;
+use crate::api_error::ApiError;
+use crate::app_state::AppState;
 use crate::dynamo::StatResp;
-use crate::dynamo_query_helpers::{query_items_key_attribute_value_serde};
 
 
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use super::*;
+
+    fn session(username: &str, lifetime: time::Duration) -> Session {
+        let now = time::OffsetDateTime::now_utc();
+        Session {
+            session_token: uuid::Uuid::new_v4(),
+            username: Username::from(username.to_string()),
+            created_at: now,
+            expires_at: now + lifetime,
+            ttl: Expiry::from(now + lifetime),
+            version: 1,
+        }
+    }
+
+    fn app() -> (FakeDynamo, App) {
+        let fake = FakeDynamo::new().with_table(TableSpec::sessions());
+        let app = App::new_with_table(fake.client(), "SessionStore");
+        (fake, app)
+    }
+
+    #[tokio::test]
+    async fn sessions_are_versioned() {
+        let (_, app) = app();
+        let session = session("user1", time::Duration::hours(1));
+        app.create_session(session.clone()).await.unwrap();
+
+        let renamed = app.update_session_username(session.session_token, Username::from("user2".to_string())).await.unwrap().unwrap();
+        assert_eq!((renamed.username.as_str(), renamed.version), ("user2", 2));
+        // `session` is from before the rename, it must not undo it
+        assert!(app.update_session(session.clone()).await.unwrap_err().is_conditional_check_failed_exception());
+
+        let stored = app.get_session(session.session_token).await.unwrap().unwrap();
+        let updated = app.update_session(stored.clone()).await.unwrap();
+        assert_eq!(updated.version, 3);
+        // `stored` is at version 2, which is gone
+        assert!(app.update_session(stored).await.unwrap_err().is_conditional_check_failed_exception());
+
+        let missing = app.update_session_username(uuid::Uuid::new_v4(), Username::from("user3".to_string())).await.unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_hidden_then_swept() {
+        let (fake, app) = app();
+        let expired = session("user1", time::Duration::seconds(-10));
+        app.create_session(expired.clone()).await.unwrap();
+
+        assert!(app.get_session(expired.session_token).await.unwrap().is_none());
+        assert!(app.get_any_session(expired.session_token).await.unwrap().is_some());
+
+        assert_eq!(fake.expire(), 1);
+        assert!(app.get_any_session(expired.session_token).await.unwrap().is_none());
+    }
+}
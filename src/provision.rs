@@ -0,0 +1,799 @@
+//! Creates and updates the DynamoDB tables, from the NoSQL Workbench model in
+//! AccountUser.json or from the schemas of `TableSchema::all`.
+//!
+//! Provisioning is idempotent: `Provisioner::plan` compares the schema with the
+//! table as described by DynamoDB, and `Provisioner::apply` makes only the
+//! missing changes, waiting for the table to be `ACTIVE` after each of them:
+//! ```ignore
+//! let provisioner = Provisioner::new(client);
+//! for schema in TableSchema::from_workbench(&WorkbenchModel::load("AccountUser.json".as_ref())?) {
+//!     provisioner.apply(&schema).await?;
+//! }
+//! ```
+//! Keys can't be changed in place, a table or index whose key differs from the
+//! schema is reported as `ProvisionError::KeyChanged`. Indexes missing from the
+//! schema are left alone.
+//!
+//! From the command line:
+//! ```text
+//! cargo run -- provision                              # every table of the config, from TableSchema::all
+//! cargo run -- provision --model AccountUser.json     # the tables of a NoSQL Workbench model
+//! cargo run -- provision --table UserTable --dry-run  # only print the changes
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use aws_sdk_dynamodb::error::BuildError;
+use aws_sdk_dynamodb::operation::create_table::CreateTableOutput;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, BillingMode, CreateGlobalSecondaryIndexAction, GlobalSecondaryIndex, GlobalSecondaryIndexUpdate,
+    IndexStatus, KeySchemaElement, KeyType, Projection as SdkProjection, ProjectionType, ProvisionedThroughput,
+    ScalarAttributeType, StreamSpecification, StreamViewType, TableDescription, TableStatus, TimeToLiveSpecification,
+    TimeToLiveStatus, UpdateGlobalSecondaryIndexAction,
+};
+use aws_sdk_dynamodb::Client;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::command::options;
+use crate::config::TableNames;
+use crate::dynamo::DynamoError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProvisionError {
+    #[error(transparent)]
+    Dynamo(#[from] DynamoError),
+    #[error("could not read model {path:?}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("could not parse model {path:?}: {message}")]
+    Parse { path: PathBuf, message: String },
+    /// The key of the table or index differs from the schema, it has to be recreated
+    #[error("the key of {0} differs from the schema and can't be changed in place")]
+    KeyChanged(String),
+    #[error("no table {0} in the schema")]
+    UnknownTable(String),
+}
+
+impl From<BuildError> for ProvisionError {
+    fn from(error: BuildError) -> Self {
+        ProvisionError::Dynamo(error.into())
+    }
+}
+
+/// A key attribute, e.g. `Attribute::s("UserId")`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub attribute_type: ScalarAttributeType,
+}
+
+impl Attribute {
+    pub fn new(name: &str, attribute_type: ScalarAttributeType) -> Self {
+        Attribute { name: name.to_string(), attribute_type }
+    }
+
+    /// A string attribute
+    pub fn s(name: &str) -> Self {
+        Attribute::new(name, ScalarAttributeType::S)
+    }
+
+    /// A number attribute
+    pub fn n(name: &str) -> Self {
+        Attribute::new(name, ScalarAttributeType::N)
+    }
+
+    fn definition(&self) -> Result<AttributeDefinition, BuildError> {
+        AttributeDefinition::builder().attribute_name(&self.name).attribute_type(self.attribute_type.clone()).build()
+    }
+}
+
+/// The partition and optional sort key of a table or index
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyDefinition {
+    pub partition: Attribute,
+    pub sort: Option<Attribute>,
+}
+
+impl KeyDefinition {
+    fn attributes(&self) -> impl Iterator<Item = &Attribute> {
+        std::iter::once(&self.partition).chain(self.sort.as_ref())
+    }
+
+    fn key_schema(&self) -> Result<Vec<KeySchemaElement>, BuildError> {
+        let element = |attribute: &Attribute, key_type| {
+            KeySchemaElement::builder().attribute_name(&attribute.name).key_type(key_type).build()
+        };
+        let mut schema = vec![element(&self.partition, KeyType::Hash)?];
+        if let Some(sort) = &self.sort {
+            schema.push(element(sort, KeyType::Range)?);
+        }
+        Ok(schema)
+    }
+
+    /// Whether DynamoDB's key schema has the same attributes, of the same types
+    fn matches(&self, schema: &[KeySchemaElement], definitions: &[AttributeDefinition]) -> bool {
+        let by_type = |key_type: KeyType| schema.iter().find(|element| element.key_type() == &key_type);
+        let same = |attribute: Option<&Attribute>, element: Option<&KeySchemaElement>| match (attribute, element) {
+            (None, None) => true,
+            (Some(attribute), Some(element)) => {
+                attribute.name == element.attribute_name()
+                    && definitions
+                        .iter()
+                        .find(|definition| definition.attribute_name() == attribute.name)
+                        .is_none_or(|definition| definition.attribute_type() == &attribute.attribute_type)
+            }
+            _ => false,
+        };
+        same(Some(&self.partition), by_type(KeyType::Hash)) && same(self.sort.as_ref(), by_type(KeyType::Range))
+    }
+}
+
+/// The attributes copied into an index
+#[derive(Clone, Debug, PartialEq)]
+pub enum Projection {
+    All,
+    KeysOnly,
+    Include(Vec<String>),
+}
+
+impl Projection {
+    fn to_sdk(&self) -> SdkProjection {
+        match self {
+            Projection::All => SdkProjection::builder().projection_type(ProjectionType::All).build(),
+            Projection::KeysOnly => SdkProjection::builder().projection_type(ProjectionType::KeysOnly).build(),
+            Projection::Include(attributes) => SdkProjection::builder()
+                .projection_type(ProjectionType::Include)
+                .set_non_key_attributes(Some(attributes.clone()))
+                .build(),
+        }
+    }
+}
+
+/// A global secondary index
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexSchema {
+    pub name: String,
+    pub key: KeyDefinition,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Billing {
+    /// `PAY_PER_REQUEST`
+    OnDemand,
+    /// Capacity units of the table, and of each of its indexes
+    Provisioned { read: i64, write: i64 },
+}
+
+impl Billing {
+    fn mode(&self) -> BillingMode {
+        match self {
+            Billing::OnDemand => BillingMode::PayPerRequest,
+            Billing::Provisioned { .. } => BillingMode::Provisioned,
+        }
+    }
+
+    fn throughput(&self) -> Result<Option<ProvisionedThroughput>, BuildError> {
+        match self {
+            Billing::OnDemand => Ok(None),
+            Billing::Provisioned { read, write } => {
+                ProvisionedThroughput::builder().read_capacity_units(*read).write_capacity_units(*write).build().map(Some)
+            }
+        }
+    }
+
+    /// The billing of an existing table
+    fn of(description: &TableDescription) -> Billing {
+        let mode = description.billing_mode_summary().and_then(|summary| summary.billing_mode());
+        match (mode, description.provisioned_throughput()) {
+            (Some(BillingMode::PayPerRequest), _) => Billing::OnDemand,
+            (_, Some(throughput)) => Billing::Provisioned {
+                read: throughput.read_capacity_units().unwrap_or_default(),
+                write: throughput.write_capacity_units().unwrap_or_default(),
+            },
+            (_, None) => Billing::Provisioned { read: 0, write: 0 },
+        }
+    }
+}
+
+/// A table with its indexes, billing, TTL and stream, e.g.
+/// `TableSchema::new("Items", Attribute::s("username")).ttl("expires_at")`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    pub key: KeyDefinition,
+    pub indexes: Vec<IndexSchema>,
+    pub billing: Billing,
+    /// Attribute holding the expiry time, in seconds since the epoch
+    pub ttl_attribute: Option<String>,
+    pub stream: Option<StreamViewType>,
+}
+
+impl TableSchema {
+    /// An on-demand table without a sort key
+    pub fn new(name: &str, partition: Attribute) -> Self {
+        TableSchema {
+            name: name.to_string(),
+            key: KeyDefinition { partition, sort: None },
+            indexes: Vec::new(),
+            billing: Billing::OnDemand,
+            ttl_attribute: None,
+            stream: None,
+        }
+    }
+
+    pub fn sort(mut self, sort: Attribute) -> Self {
+        self.key.sort = Some(sort);
+        self
+    }
+
+    /// A global secondary index projecting all attributes
+    pub fn index(mut self, name: &str, partition: Attribute, sort: Option<Attribute>) -> Self {
+        self.indexes.push(IndexSchema {
+            name: name.to_string(),
+            key: KeyDefinition { partition, sort },
+            projection: Projection::All,
+        });
+        self
+    }
+
+    pub fn billing(mut self, billing: Billing) -> Self {
+        self.billing = billing;
+        self
+    }
+
+    /// Enables TTL on `attribute`
+    pub fn ttl(mut self, attribute: &str) -> Self {
+        self.ttl_attribute = Some(attribute.to_string());
+        self
+    }
+
+    pub fn stream(mut self, view_type: StreamViewType) -> Self {
+        self.stream = Some(view_type);
+        self
+    }
+
+    /// Every table of the API, under the configured names
+    pub fn all(tables: &TableNames) -> Vec<TableSchema> {
+        vec![
+            TableSchema::items(&tables.items),
+            TableSchema::user_table(&tables.user_table),
+            TableSchema::sessions(&tables.sessions),
+            TableSchema::users(&tables.users),
+        ]
+    }
+
+    /// The `Item` table, keyed by `username`
+    pub fn items(name: &str) -> Self {
+        TableSchema::new(name, Attribute::s("username"))
+    }
+
+    /// `UserTable` of AccountUser.json, with orders by date in `gsi1` and the
+    /// model's 5 read and 5 write capacity units
+    pub fn user_table(name: &str) -> Self {
+        TableSchema::new(name, Attribute::s("UserId"))
+            .sort(Attribute::s("OrderId"))
+            .index("gsi1", Attribute::n("gsi_pk"), Some(Attribute::s("date_ordered")))
+            .billing(Billing::Provisioned { read: 5, write: 5 })
+    }
+
+    /// The modyne sessions and refresh tokens, expiring on `ttl`
+    pub fn sessions(name: &str) -> Self {
+        TableSchema::new(name, Attribute::s("session_token")).index("UserIndex", Attribute::s("username"), None).ttl("ttl")
+    }
+
+    /// The users of `/signin`, found by email through `email_index`
+    pub fn users(name: &str) -> Self {
+        TableSchema::new(name, Attribute::s("user_id")).index(crate::user::EMAIL_INDEX, Attribute::s("email"), None)
+    }
+
+    /// Definitions of every key attribute of the table and its indexes
+    fn attribute_definitions(&self) -> Result<Vec<AttributeDefinition>, BuildError> {
+        let mut attributes: Vec<&Attribute> = Vec::new();
+        for attribute in self.key.attributes().chain(self.indexes.iter().flat_map(|index| index.key.attributes())) {
+            if !attributes.iter().any(|known| known.name == attribute.name) {
+                attributes.push(attribute);
+            }
+        }
+        attributes.into_iter().map(Attribute::definition).collect()
+    }
+
+    fn stream_specification(&self) -> Result<StreamSpecification, BuildError> {
+        StreamSpecification::builder().stream_enabled(self.stream.is_some()).set_stream_view_type(self.stream.clone()).build()
+    }
+}
+
+/// Creates the table with its indexes, billing and stream, without waiting for it.
+/// An existing table fails with `DynamoError::ResourceInUse`.
+pub async fn create_table(client: &Client, schema: &TableSchema) -> Result<CreateTableOutput, DynamoError> {
+    let throughput = schema.billing.throughput()?;
+    let mut request = client
+        .create_table()
+        .table_name(&schema.name)
+        .set_key_schema(Some(schema.key.key_schema()?))
+        .set_attribute_definitions(Some(schema.attribute_definitions()?))
+        .billing_mode(schema.billing.mode())
+        .set_provisioned_throughput(throughput.clone());
+    for index in &schema.indexes {
+        request = request.global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(&index.name)
+                .set_key_schema(Some(index.key.key_schema()?))
+                .projection(index.projection.to_sdk())
+                .set_provisioned_throughput(throughput.clone())
+                .build()?,
+        );
+    }
+    if schema.stream.is_some() {
+        request = request.stream_specification(schema.stream_specification()?);
+    }
+    Ok(request.send().await?)
+}
+
+/// One step of bringing a table in line with its schema
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The table with its indexes, billing and stream
+    CreateTable,
+    Billing(Billing),
+    /// Enables the stream with the view type, or disables it
+    Stream(Option<StreamViewType>),
+    CreateIndex(String),
+    EnableTtl(String),
+    DisableTtl(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::CreateTable => write!(f, "create table"),
+            Change::Billing(Billing::OnDemand) => write!(f, "switch to on-demand billing"),
+            Change::Billing(Billing::Provisioned { read, write }) => {
+                write!(f, "provision {read} read and {write} write capacity units")
+            }
+            Change::Stream(Some(view_type)) => write!(f, "enable the {} stream", view_type.as_str()),
+            Change::Stream(None) => write!(f, "disable the stream"),
+            Change::CreateIndex(index) => write!(f, "create index {index}"),
+            Change::EnableTtl(attribute) => write!(f, "enable TTL on {attribute}"),
+            Change::DisableTtl(attribute) => write!(f, "disable TTL on {attribute}"),
+        }
+    }
+}
+
+/// Creates and updates tables to match their `TableSchema`
+#[derive(Clone, Debug)]
+pub struct Provisioner {
+    client: Client,
+    /// Pause between two `DescribeTable` calls while waiting for `ACTIVE`
+    interval: Duration,
+    attempts: u32,
+}
+
+impl Provisioner {
+    /// Waits up to 10 minutes for each change to complete
+    pub fn new(client: Client) -> Self {
+        Provisioner { client, interval: Duration::from_secs(5), attempts: 120 }
+    }
+
+    /// Checks `attempts` times, `interval` apart, whether a changed table is `ACTIVE`
+    pub fn wait(mut self, interval: Duration, attempts: u32) -> Self {
+        self.interval = interval;
+        self.attempts = attempts;
+        self
+    }
+
+    /// The changes `apply` would make, in order
+    pub async fn plan(&self, schema: &TableSchema) -> Result<Vec<Change>, ProvisionError> {
+        let Some(table) = self.describe(&schema.name).await? else {
+            let mut changes = vec![Change::CreateTable];
+            changes.extend(schema.ttl_attribute.clone().map(Change::EnableTtl));
+            return Ok(changes);
+        };
+
+        if !schema.key.matches(table.key_schema(), table.attribute_definitions()) {
+            return Err(ProvisionError::KeyChanged(schema.name.clone()));
+        }
+
+        let mut changes = Vec::new();
+        if Billing::of(&table) != schema.billing {
+            changes.push(Change::Billing(schema.billing.clone()));
+        }
+
+        let stream = table
+            .stream_specification()
+            .filter(|stream| stream.stream_enabled())
+            .and_then(|stream| stream.stream_view_type().cloned());
+        if stream != schema.stream {
+            // The view type of an enabled stream can't be changed, only disabled first
+            if stream.is_some() {
+                changes.push(Change::Stream(None));
+            }
+            if schema.stream.is_some() {
+                changes.push(Change::Stream(schema.stream.clone()));
+            }
+        }
+
+        for index in &schema.indexes {
+            let existing = table.global_secondary_indexes().iter().find(|existing| existing.index_name() == Some(&index.name));
+            match existing {
+                None => changes.push(Change::CreateIndex(index.name.clone())),
+                Some(existing) if !index.key.matches(existing.key_schema(), table.attribute_definitions()) => {
+                    return Err(ProvisionError::KeyChanged(format!("{}.{}", schema.name, index.name)));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let ttl = self.ttl_attribute(&schema.name).await?;
+        if ttl != schema.ttl_attribute {
+            changes.extend(ttl.map(Change::DisableTtl));
+            changes.extend(schema.ttl_attribute.clone().map(Change::EnableTtl));
+        }
+        Ok(changes)
+    }
+
+    /// Makes the changes of `plan`, waiting for the table to be `ACTIVE` after each
+    pub async fn apply(&self, schema: &TableSchema) -> Result<Vec<Change>, ProvisionError> {
+        let changes = self.plan(schema).await?;
+        // An existing table may still be busy with an earlier change
+        if changes.first().is_some_and(|change| change != &Change::CreateTable) {
+            self.wait_until_active(&schema.name).await?;
+        }
+        for change in &changes {
+            self.change(schema, change).await?;
+            self.wait_until_active(&schema.name).await?;
+        }
+        Ok(changes)
+    }
+
+    /// Polls the table until it and all its indexes are `ACTIVE`.
+    /// Fails with `DynamoError::TableNotReady` once the attempts are used up.
+    pub async fn wait_until_active(&self, table: &str) -> Result<TableDescription, DynamoError> {
+        for attempt in 0..self.attempts {
+            if attempt > 0 {
+                tokio::time::sleep(self.interval).await;
+            }
+            let Some(description) = self.describe(table).await? else {
+                continue;
+            };
+            let indexes_active = description
+                .global_secondary_indexes()
+                .iter()
+                .all(|index| index.index_status().is_none_or(|status| status == &IndexStatus::Active));
+            if description.table_status() == Some(&TableStatus::Active) && indexes_active {
+                return Ok(description);
+            }
+        }
+        Err(DynamoError::table_not_ready(table))
+    }
+
+    async fn describe(&self, table: &str) -> Result<Option<TableDescription>, DynamoError> {
+        match self.client.describe_table().table_name(table).send().await.map_err(DynamoError::from) {
+            Ok(output) => Ok(output.table),
+            Err(DynamoError::ResourceNotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The attribute TTL is enabled on, or being enabled on
+    async fn ttl_attribute(&self, table: &str) -> Result<Option<String>, DynamoError> {
+        let output = self.client.describe_time_to_live().table_name(table).send().await?;
+        Ok(output
+            .time_to_live_description()
+            .filter(|ttl| matches!(ttl.time_to_live_status(), Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)))
+            .and_then(|ttl| ttl.attribute_name())
+            .map(str::to_string))
+    }
+
+    async fn change(&self, schema: &TableSchema, change: &Change) -> Result<(), ProvisionError> {
+        let update = || self.client.update_table().table_name(&schema.name);
+        match change {
+            Change::CreateTable => {
+                create_table(&self.client, schema).await?;
+            }
+            Change::Billing(billing) => {
+                // Provisioned capacity has to be given for every index as well
+                let throughput = billing.throughput()?;
+                let mut request = update().billing_mode(billing.mode()).set_provisioned_throughput(throughput.clone());
+                if let Some(throughput) = throughput {
+                    for index in self.describe(&schema.name).await?.iter().flat_map(|table| table.global_secondary_indexes()) {
+                        let action = UpdateGlobalSecondaryIndexAction::builder()
+                            .set_index_name(index.index_name().map(str::to_string))
+                            .provisioned_throughput(throughput.clone())
+                            .build()?;
+                        request = request.global_secondary_index_updates(GlobalSecondaryIndexUpdate::builder().update(action).build());
+                    }
+                }
+                request.send().await.map_err(DynamoError::from)?;
+            }
+            Change::Stream(view_type) => {
+                let stream = StreamSpecification::builder()
+                    .stream_enabled(view_type.is_some())
+                    .set_stream_view_type(view_type.clone())
+                    .build()?;
+                update().stream_specification(stream).send().await.map_err(DynamoError::from)?;
+            }
+            Change::CreateIndex(name) => {
+                let index = schema.indexes.iter().find(|index| &index.name == name).expect("indexes are planned from the schema");
+                let action = CreateGlobalSecondaryIndexAction::builder()
+                    .index_name(&index.name)
+                    .set_key_schema(Some(index.key.key_schema()?))
+                    .projection(index.projection.to_sdk())
+                    .set_provisioned_throughput(schema.billing.throughput()?)
+                    .build()?;
+                update()
+                    .set_attribute_definitions(Some(schema.attribute_definitions()?))
+                    .global_secondary_index_updates(GlobalSecondaryIndexUpdate::builder().create(action).build())
+                    .send()
+                    .await
+                    .map_err(DynamoError::from)?;
+            }
+            Change::EnableTtl(attribute) | Change::DisableTtl(attribute) => {
+                let specification = TimeToLiveSpecification::builder()
+                    .attribute_name(attribute)
+                    .enabled(matches!(change, Change::EnableTtl(_)))
+                    .build()?;
+                self.client
+                    .update_time_to_live()
+                    .table_name(&schema.name)
+                    .time_to_live_specification(specification)
+                    .send()
+                    .await
+                    .map_err(DynamoError::from)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A data model exported by NoSQL Workbench, such as AccountUser.json
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchModel {
+    pub model_name: String,
+    pub data_model: Vec<WorkbenchTable>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchTable {
+    pub table_name: String,
+    pub key_attributes: WorkbenchKey,
+    #[serde(default)]
+    pub global_secondary_indexes: Vec<WorkbenchIndex>,
+    /// "PROVISIONED" or "PAY_PER_REQUEST", on-demand when missing
+    pub billing_mode: Option<String>,
+    /// Auto scaling settings are not applied, only the provisioned throughput
+    pub provisioned_capacity_settings: Option<WorkbenchCapacity>,
+    /// Sample items in the wire format, e.g. `{"UserId": {"S": "u#user1"}}`
+    #[serde(default)]
+    pub table_data: Vec<Map<String, Value>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchKey {
+    pub partition_key: WorkbenchAttribute,
+    pub sort_key: Option<WorkbenchAttribute>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchAttribute {
+    pub attribute_name: String,
+    pub attribute_type: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchIndex {
+    pub index_name: String,
+    pub key_attributes: WorkbenchKey,
+    pub projection: Option<WorkbenchProjection>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchProjection {
+    pub projection_type: String,
+    #[serde(default)]
+    pub non_key_attributes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchCapacity {
+    pub provisioned_throughput: WorkbenchThroughput,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorkbenchThroughput {
+    pub read_capacity_units: i64,
+    pub write_capacity_units: i64,
+}
+
+impl WorkbenchModel {
+    pub fn load(path: &Path) -> Result<Self, ProvisionError> {
+        let json = std::fs::read_to_string(path).map_err(|source| ProvisionError::Read { path: path.to_path_buf(), source })?;
+        serde_json::from_str(&json).map_err(|e| ProvisionError::Parse { path: path.to_path_buf(), message: e.to_string() })
+    }
+
+    pub fn table(&self, name: &str) -> Option<&WorkbenchTable> {
+        self.data_model.iter().find(|table| table.table_name == name)
+    }
+}
+
+impl From<&WorkbenchAttribute> for Attribute {
+    fn from(attribute: &WorkbenchAttribute) -> Self {
+        Attribute::new(&attribute.attribute_name, ScalarAttributeType::from(attribute.attribute_type.as_str()))
+    }
+}
+
+impl From<&WorkbenchKey> for KeyDefinition {
+    fn from(key: &WorkbenchKey) -> Self {
+        KeyDefinition { partition: (&key.partition_key).into(), sort: key.sort_key.as_ref().map(Attribute::from) }
+    }
+}
+
+impl From<&WorkbenchTable> for TableSchema {
+    fn from(table: &WorkbenchTable) -> Self {
+        let billing = match (table.billing_mode.as_deref(), &table.provisioned_capacity_settings) {
+            (Some("PROVISIONED"), Some(capacity)) => Billing::Provisioned {
+                read: capacity.provisioned_throughput.read_capacity_units,
+                write: capacity.provisioned_throughput.write_capacity_units,
+            },
+            _ => Billing::OnDemand,
+        };
+        let indexes = table
+            .global_secondary_indexes
+            .iter()
+            .map(|index| IndexSchema {
+                name: index.index_name.clone(),
+                key: (&index.key_attributes).into(),
+                projection: match &index.projection {
+                    Some(projection) if projection.projection_type == "KEYS_ONLY" => Projection::KeysOnly,
+                    Some(projection) if projection.projection_type == "INCLUDE" => {
+                        Projection::Include(projection.non_key_attributes.clone())
+                    }
+                    _ => Projection::All,
+                },
+            })
+            .collect();
+
+        TableSchema {
+            name: table.table_name.clone(),
+            key: (&table.key_attributes).into(),
+            indexes,
+            billing,
+            ttl_attribute: None,
+            stream: None,
+        }
+    }
+}
+
+impl TableSchema {
+    /// Every table of the model
+    pub fn from_workbench(model: &WorkbenchModel) -> Vec<TableSchema> {
+        model.data_model.iter().map(TableSchema::from).collect()
+    }
+}
+
+/// `provision [--model PATH] [--table NAME]... [--dry-run]`
+#[derive(Debug, Default, PartialEq)]
+pub struct ProvisionCommand {
+    /// NoSQL Workbench model to read the tables from, instead of `TableSchema::all`
+    pub model: Option<PathBuf>,
+    /// Only these tables, all of them when empty
+    pub tables: Vec<String>,
+    /// Print the changes without making them
+    pub dry_run: bool,
+}
+
+impl ProvisionCommand {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut command = ProvisionCommand::default();
+        for (flag, value) in options("provision", args, &["--dry-run"], &["--model", "--table"])? {
+            match (flag.as_str(), value) {
+                ("--model", Some(path)) => command.model = Some(PathBuf::from(path)),
+                ("--table", Some(table)) => command.tables.push(table),
+                _ => command.dry_run = true,
+            }
+        }
+        Ok(command)
+    }
+
+    /// The schemas to provision, checked against `--table`
+    pub fn schemas(&self, tables: &TableNames) -> Result<Vec<TableSchema>, ProvisionError> {
+        let schemas = match &self.model {
+            Some(path) => TableSchema::from_workbench(&WorkbenchModel::load(path)?),
+            None => TableSchema::all(tables),
+        };
+        if let Some(unknown) = self.tables.iter().find(|name| !schemas.iter().any(|schema| &schema.name == *name)) {
+            return Err(ProvisionError::UnknownTable(unknown.clone()));
+        }
+        Ok(schemas.into_iter().filter(|schema| self.tables.is_empty() || self.tables.contains(&schema.name)).collect())
+    }
+
+    /// Provisions the tables, printing each change
+    pub async fn run(&self, client: Client, tables: &TableNames) -> Result<(), ProvisionError> {
+        let provisioner = Provisioner::new(client);
+        for schema in self.schemas(tables)? {
+            let changes = if self.dry_run { provisioner.plan(&schema).await? } else { provisioner.apply(&schema).await? };
+            if changes.is_empty() {
+                println!("{}: up to date", schema.name);
+            }
+            for change in changes {
+                println!("{}: {}{}", schema.name, if self.dry_run { "would " } else { "" }, change);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use super::*;
+
+    fn provisioner(fake: &FakeDynamo) -> Provisioner {
+        Provisioner::new(fake.client()).wait(Duration::ZERO, 3)
+    }
+
+    #[tokio::test]
+    async fn workbench_tables_are_created_once() {
+        let model = WorkbenchModel::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("AccountUser.json")).unwrap();
+        let schema = TableSchema::from(model.table("UserTable").unwrap());
+        assert_eq!(schema, TableSchema::user_table("UserTable"));
+
+        let fake = FakeDynamo::new();
+        assert_eq!(provisioner(&fake).apply(&schema).await.unwrap(), [Change::CreateTable]);
+        assert_eq!(provisioner(&fake).apply(&schema).await.unwrap(), []);
+
+        let table = fake.client().describe_table().table_name("UserTable").send().await.unwrap().table.unwrap();
+        let gsi1 = &table.global_secondary_indexes()[0];
+        assert_eq!(gsi1.index_name(), Some("gsi1"));
+        assert!(schema.indexes[0].key.matches(gsi1.key_schema(), table.attribute_definitions()));
+    }
+
+    #[tokio::test]
+    async fn existing_tables_get_their_missing_settings() {
+        let fake = FakeDynamo::new().with_table(TableSpec::new("SessionStore", "session_token", None));
+        let schema = TableSchema::sessions("SessionStore")
+            .billing(Billing::Provisioned { read: 5, write: 5 })
+            .stream(StreamViewType::NewAndOldImages);
+
+        let changes = provisioner(&fake).apply(&schema).await.unwrap();
+        assert_eq!(
+            changes,
+            [
+                Change::Billing(Billing::Provisioned { read: 5, write: 5 }),
+                Change::Stream(Some(StreamViewType::NewAndOldImages)),
+                Change::CreateIndex("UserIndex".to_string()),
+                Change::EnableTtl("ttl".to_string()),
+            ]
+        );
+        assert_eq!(provisioner(&fake).plan(&schema).await.unwrap(), []);
+
+        let without_stream = TableSchema { stream: None, ..schema };
+        assert_eq!(provisioner(&fake).plan(&without_stream).await.unwrap(), [Change::Stream(None)]);
+    }
+
+    #[tokio::test]
+    async fn changes_wait_until_the_table_is_active() {
+        let fake = FakeDynamo::new();
+        fake.slow_changes(2);
+        provisioner(&fake).apply(&TableSchema::items("Items")).await.unwrap();
+
+        fake.slow_changes(5);
+        let result = provisioner(&fake).apply(&TableSchema::users("Users")).await;
+        assert!(matches!(result, Err(ProvisionError::Dynamo(DynamoError::TableNotReady(table))) if table == "Users"));
+    }
+
+    #[tokio::test]
+    async fn keys_are_never_changed() {
+        let fake = FakeDynamo::new().with_table(TableSpec::new("Users", "email", None));
+        let result = provisioner(&fake).plan(&TableSchema::users("Users")).await;
+        assert!(matches!(result, Err(ProvisionError::KeyChanged(table)) if table == "Users"));
+    }
+}
@@ -0,0 +1,145 @@
+use modyne::types::Expiry;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use uuid::Uuid;
+use crate::config::JwtConfig;
+use crate::modyne::{App, RefreshToken, RefreshTokenFamily, Username};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RefreshTokenError {
+    #[error("Invalid refresh token")]
+    Invalid,
+    #[error("Refresh token has expired")]
+    Expired,
+    #[error("Refresh token has been revoked")]
+    Revoked,
+    #[error("Refresh token reuse detected, all tokens of this sign in have been revoked")]
+    Reused,
+    #[error("Error in Dynamo: {0}")]
+    Store(#[from] modyne::Error),
+}
+
+/// A refresh token handed to the client, "{token id}.{secret}"
+pub struct IssuedRefreshToken {
+    pub token: String,
+    pub username: Username,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Starts a new token family for a user that just signed in
+pub async fn issue_refresh_token(
+    app: &App,
+    username: &str,
+    config: &JwtConfig,
+) -> Result<IssuedRefreshToken, RefreshTokenError> {
+    let now = OffsetDateTime::now_utc();
+    let family_id = Uuid::new_v4();
+    let (token, issued) = new_token(family_id, Username::from(username), now, config);
+
+    let family = RefreshTokenFamily {
+        session_token: family_id,
+        username: token.username.clone(),
+        revoked: false,
+        created_at: now,
+        ttl: token.ttl,
+    };
+    app.create_refresh_token_family(family, token).await?;
+    Ok(issued)
+}
+
+/// Exchanges a refresh token for a new one of the same family.
+///
+/// A token can only be exchanged once. Presenting an already used token means it
+/// has leaked, so the whole family is revoked and the legitimate holder has to sign in again.
+pub async fn rotate_refresh_token(
+    app: &App,
+    presented: &str,
+    config: &JwtConfig,
+) -> Result<IssuedRefreshToken, RefreshTokenError> {
+    let now = OffsetDateTime::now_utc();
+    let stored = find_token(app, presented).await?;
+
+    let family = app
+        .get_refresh_token_family(stored.family_id)
+        .await?
+        .ok_or(RefreshTokenError::Invalid)?;
+    if family.revoked {
+        return Err(RefreshTokenError::Revoked);
+    }
+
+    if stored.used {
+        app.revoke_refresh_token_family(stored.family_id).await?;
+        return Err(RefreshTokenError::Reused);
+    }
+
+    if stored.expires_at <= now {
+        return Err(RefreshTokenError::Expired);
+    }
+
+    let (replacement, issued) = new_token(stored.family_id, stored.username, now, config);
+    match app.rotate_refresh_token(stored.session_token, replacement).await {
+        Ok(()) => Ok(issued),
+        // Lost a race with another exchange of the same token
+        Err(e) if e.is_conditional_check_failed_exception() => {
+            app.revoke_refresh_token_family(stored.family_id).await?;
+            Err(RefreshTokenError::Reused)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Revokes the family of the presented refresh token
+pub async fn revoke_refresh_token(app: &App, presented: &str) -> Result<(), RefreshTokenError> {
+    let stored = find_token(app, presented).await?;
+    app.revoke_refresh_token_family(stored.family_id).await?;
+    Ok(())
+}
+
+/// Loads the stored token and checks the presented secret against its hash
+async fn find_token(app: &App, presented: &str) -> Result<RefreshToken, RefreshTokenError> {
+    let (token_id, secret) = presented.split_once('.').ok_or(RefreshTokenError::Invalid)?;
+    let token_id = Uuid::parse_str(token_id).map_err(|_| RefreshTokenError::Invalid)?;
+
+    let stored = app
+        .get_refresh_token(token_id)
+        .await?
+        .ok_or(RefreshTokenError::Invalid)?;
+
+    if stored.secret_hash != hash_secret(secret) {
+        return Err(RefreshTokenError::Invalid);
+    }
+    Ok(stored)
+}
+
+fn new_token(
+    family_id: Uuid,
+    username: Username,
+    now: OffsetDateTime,
+    config: &JwtConfig,
+) -> (RefreshToken, IssuedRefreshToken) {
+    let token_id = Uuid::new_v4();
+    // 244 random bits from two v4 uuids
+    let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let expires_at = now + time::Duration::days(config.refresh_token_days);
+
+    let token = RefreshToken {
+        session_token: token_id,
+        username: username.clone(),
+        family_id,
+        secret_hash: hash_secret(&secret),
+        used: false,
+        created_at: now,
+        expires_at,
+        ttl: Expiry::from(expires_at),
+    };
+    let issued = IssuedRefreshToken {
+        token: format!("{token_id}.{secret}"),
+        username,
+        expires_at,
+    };
+    (token, issued)
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
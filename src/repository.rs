@@ -0,0 +1,772 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::time::Duration;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, DeleteRequest, KeysAndAttributes, PutRequest, ReturnValue, ReturnValuesOnConditionCheckFailure,
+    WriteRequest,
+};
+use tokio::task::JoinSet;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::config::TableNames;
+use crate::dynamo::DynamoError;
+
+/// A key attribute and the prefix its values are stored with, e.g. `UserId` = "u#user7"
+#[derive(Clone, Copy, Debug)]
+pub struct KeyAttribute {
+    pub name: &'static str,
+    pub prefix: &'static str,
+}
+
+impl KeyAttribute {
+    pub const fn new(name: &'static str) -> Self {
+        KeyAttribute { name, prefix: "" }
+    }
+
+    pub const fn prefixed(name: &'static str, prefix: &'static str) -> Self {
+        KeyAttribute { name, prefix }
+    }
+
+    /// The stored form of an id. Ids may be given with or without the prefix.
+    pub fn stored(&self, id: &str) -> String {
+        if id.starts_with(self.prefix) {
+            id.to_string()
+        } else {
+            format!("{}{}", self.prefix, id)
+        }
+    }
+
+    pub fn value(&self, id: &str) -> AttributeValue {
+        AttributeValue::S(self.stored(id))
+    }
+}
+
+/// A type stored in one DynamoDB table through serde_dynamo.
+///
+/// Declares the table and how a `Key` maps to the key attributes,
+/// so callers of `Repository` never build key maps themselves.
+pub trait DynamoEntity: Serialize + DeserializeOwned + Send + Sync {
+    /// What identifies one entity, without the stored prefixes
+    type Key: Clone + Send + Sync;
+
+    /// Names the entity in errors, e.g. "Order already exists"
+    const NAME: &'static str;
+
+    const PARTITION_KEY: KeyAttribute;
+    const SORT_KEY: Option<KeyAttribute>;
+
+    /// Opt-in optimistic concurrency: the number attribute holding the version.
+    /// `Repository` starts it at 1 on create and increments it on every update.
+    const VERSION: Option<&'static str> = None;
+
+    fn table_name(tables: &TableNames) -> &str;
+
+    /// Partition and sort key ids of `key`
+    fn key_ids(key: &Self::Key) -> (&str, Option<&str>);
+
+    fn key(&self) -> Self::Key;
+
+    /// The stored version, for entities with a `VERSION` attribute
+    fn version(&self) -> Option<u64> {
+        None
+    }
+
+    /// Partition and sort key values as stored, the same for ids given with and without their prefixes
+    fn stored_key(key: &Self::Key) -> (String, Option<String>) {
+        let (partition, sort) = Self::key_ids(key);
+        (Self::PARTITION_KEY.stored(partition), Self::SORT_KEY.zip(sort).map(|(attribute, sort)| attribute.stored(sort)))
+    }
+
+    /// The key as DynamoDB expects it in GetItem, DeleteItem and UpdateItem
+    fn key_item(key: &Self::Key) -> HashMap<String, AttributeValue> {
+        let (partition, sort) = Self::stored_key(key);
+        let mut item = HashMap::from([(Self::PARTITION_KEY.name.to_string(), AttributeValue::S(partition))]);
+        if let (Some(attribute), Some(sort)) = (Self::SORT_KEY, sort) {
+            item.insert(attribute.name.to_string(), AttributeValue::S(sort));
+        }
+        item
+    }
+}
+
+/// An `UpdateExpression` built from SET, REMOVE and ADD clauses, e.g.
+/// `Update::default().set("product", AttributeValue::S(product)).add("price", AttributeValue::N("1".into()))`
+/// becomes `SET #a0 = :v0 ADD #a1 :v1`.
+#[derive(Clone, Debug, Default)]
+pub struct Update {
+    set: Vec<String>,
+    remove: Vec<String>,
+    add: Vec<String>,
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+}
+
+impl Update {
+    /// The SET and REMOVE clauses of a patch struct. Every attribute the patch
+    /// serializes is SET, and attributes serialized as null are REMOVEd, so
+    /// `Option` fields with `skip_serializing_if = "Option::is_none"` are only
+    /// written when they are `Some`.
+    pub fn from_patch<P: Serialize>(patch: &P) -> Result<Self, DynamoError> {
+        let item: HashMap<String, AttributeValue> = serde_dynamo::aws_sdk_dynamodb_1::to_item(patch)?;
+
+        // Sorted so the expression is the same for the same patch
+        let mut attributes: Vec<_> = item.into_iter().collect();
+        attributes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(attributes.into_iter().fold(Update::default(), |update, (name, value)| match value {
+            AttributeValue::Null(_) => update.remove(name),
+            value => update.set(name, value),
+        }))
+    }
+
+    pub fn set(mut self, name: impl Into<String>, value: AttributeValue) -> Self {
+        let (name, value) = (self.name(name), self.value(value));
+        self.set.push(format!("{name} = {value}"));
+        self
+    }
+
+    pub fn remove(mut self, name: impl Into<String>) -> Self {
+        let name = self.name(name);
+        self.remove.push(name);
+        self
+    }
+
+    /// Adds to a number, or adds the elements of a set
+    pub fn add(mut self, name: impl Into<String>, value: AttributeValue) -> Self {
+        let (name, value) = (self.name(name), self.value(value));
+        self.add.push(format!("{name} {value}"));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty() && self.add.is_empty()
+    }
+
+    pub fn expression(&self) -> String {
+        [("SET", &self.set), ("REMOVE", &self.remove), ("ADD", &self.add)]
+            .into_iter()
+            .filter(|(_, clauses)| !clauses.is_empty())
+            .map(|(action, clauses)| format!("{action} {}", clauses.join(", ")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Placeholders are numbered, attribute names may contain anything
+    fn name(&mut self, name: impl Into<String>) -> String {
+        let placeholder = format!("#a{}", self.names.len());
+        self.names.insert(placeholder.clone(), name.into());
+        placeholder
+    }
+
+    fn value(&mut self, value: AttributeValue) -> String {
+        let placeholder = format!(":v{}", self.values.len());
+        self.values.insert(placeholder.clone(), value);
+        placeholder
+    }
+}
+
+/// Typed get, put, delete, query and update of one `DynamoEntity`, e.g.
+/// ```ignore
+/// let orders = state.repository::<UserTable>();
+/// let order = orders.get(&UserTableId { user, order }).await?;
+/// ```
+pub struct Repository<T> {
+    client: Client,
+    table: String,
+    entity: PhantomData<fn() -> T>,
+}
+
+impl<T: DynamoEntity> Repository<T> {
+    pub fn new(client: Client, tables: &TableNames) -> Self {
+        Repository {
+            client,
+            table: T::table_name(tables).to_string(),
+            entity: PhantomData,
+        }
+    }
+
+    pub async fn get(&self, key: &T::Key) -> Result<Option<T>, DynamoError> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(T::key_item(key)))
+            .send()
+            .await?;
+
+        Ok(result.item.map(serde_dynamo::aws_sdk_dynamodb_1::from_item).transpose()?)
+    }
+
+    /// Creates the entity, failing with `DynamoError::AlreadyExists` if one with
+    /// the same key exists
+    pub async fn create(&self, entity: &T) -> Result<(), DynamoError> {
+        create_items(self.client.clone(), self.table.clone(), vec![create_item(entity)?], T::NAME, Condition::not_exists::<T>()).await
+    }
+
+    /// Creates or replaces the entity, unconditionally. Fails with
+    /// `DynamoError::Versioned` for a versioned entity, replacing it would
+    /// reset its version; use `create` or `update` for those.
+    pub async fn put(&self, entity: &T) -> Result<(), DynamoError> {
+        unversioned::<T>()?;
+        let item = serde_dynamo::aws_sdk_dynamodb_1::to_item(entity)?;
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(item))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the deleted entity, or `None` if there was none.
+    /// With `expected`, only deletes that version of a versioned entity.
+    pub async fn delete(&self, key: &T::Key, expected: Option<u64>) -> Result<Option<T>, DynamoError> {
+        let mut request = self
+            .client
+            .delete_item()
+            .table_name(&self.table)
+            .set_key(Some(T::key_item(key)))
+            .return_values(ReturnValue::AllOld);
+
+        if let Some(condition) = expected.and_then(Condition::version::<T>) {
+            request = request
+                .condition_expression(condition.expression)
+                .set_expression_attribute_names(Some(condition.names))
+                .set_expression_attribute_values(Some(condition.values))
+                .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld);
+        }
+
+        match request.send().await.map_err(DynamoError::from) {
+            Ok(output) => Ok(output.attributes.map(serde_dynamo::aws_sdk_dynamodb_1::from_item).transpose()?),
+            // The item is returned when it exists at another version
+            Err(e @ DynamoError::ConditionalCheckFailed { .. }) => match e.condition_failed_item() {
+                Some(_) => Err(DynamoError::VersionMismatch(T::NAME.to_string())),
+                None => Ok(None),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every entity with the partition key `partition`, across all pages
+    pub async fn query(&self, partition: &str) -> Result<Vec<T>, DynamoError> {
+        let mut entities = vec![];
+        let mut start_key = None;
+        loop {
+            let result = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .key_condition_expression("#pk = :pk")
+                .expression_attribute_names("#pk", T::PARTITION_KEY.name)
+                .expression_attribute_values(":pk", T::PARTITION_KEY.value(partition))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            let items = result.items.unwrap_or_default();
+            entities.extend(serde_dynamo::aws_sdk_dynamodb_1::from_items::<T>(items)?);
+
+            match result.last_evaluated_key {
+                Some(key) => start_key = Some(key),
+                None => return Ok(entities),
+            }
+        }
+    }
+
+    /// The entities with `keys`, in no particular order. Missing keys are left out.
+    ///
+    /// Sends one BatchGetItem per 100 keys, all at once, and retries unprocessed
+    /// keys with backoff. Fails with `DynamoError::Unprocessed` if some are left.
+    pub async fn batch_get(&self, keys: &[T::Key]) -> Result<Vec<T>, DynamoError> {
+        // DynamoDB rejects a batch that names a key twice, "order1" and "o#order1" included
+        let mut seen = HashSet::new();
+        let keys: Vec<_> = keys
+            .iter()
+            .filter(|key| seen.insert(T::stored_key(key)))
+            .map(T::key_item)
+            .collect();
+
+        let mut chunks = JoinSet::new();
+        for chunk in keys.chunks(BATCH_GET_LIMIT) {
+            chunks.spawn(batch_get_chunk(self.client.clone(), self.table.clone(), chunk.to_vec()));
+        }
+
+        let mut items = vec![];
+        while let Some(chunk) = chunks.join_next().await {
+            items.extend(chunk.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?);
+        }
+        Ok(serde_dynamo::aws_sdk_dynamodb_1::from_items(items)?)
+    }
+
+    /// Puts `puts` and deletes `deletes` with one BatchWriteItem per 25 writes,
+    /// all at once, retrying unprocessed items with backoff. The report lists
+    /// which writes were applied; the writes of a chunk that failed, or that
+    /// were still unprocessed after the retries, are in its `failed`.
+    ///
+    /// Batch writes can't be conditional, so the versions of versioned entities
+    /// are read first, and each put is written at the stored version + 1, or 1
+    /// for a new entity. A stale `If-Match` version never matches the replaced
+    /// entity, but an update between the read and the batch is overwritten.
+    /// Fails before writing anything if the versions can't be read.
+    ///
+    /// Each stored key may only be written once, DynamoDB rejects the batch
+    /// otherwise, see `BatchWriteUserTable`.
+    pub async fn batch_write(&self, puts: &[T], deletes: &[T::Key]) -> Result<BatchWriteReport<T::Key>, DynamoError> {
+        let versions: HashMap<_, _> = match T::VERSION {
+            Some(_) if !puts.is_empty() => {
+                let keys: Vec<_> = puts.iter().map(T::key).collect();
+                let stored = self.batch_get(&keys).await?;
+                stored.iter().map(|entity| (T::stored_key(&entity.key()), entity.version().unwrap_or(0))).collect()
+            }
+            _ => HashMap::new(),
+        };
+
+        let mut keys = Vec::with_capacity(puts.len() + deletes.len());
+        let mut writes = Vec::with_capacity(puts.len() + deletes.len());
+        for entity in puts {
+            let key = entity.key();
+            let mut item = serde_dynamo::aws_sdk_dynamodb_1::to_item(entity)?;
+            if let Some(version) = T::VERSION {
+                let next = versions.get(&T::stored_key(&key)).map_or(1, |stored| stored + 1);
+                item.insert(version.to_string(), AttributeValue::N(next.to_string()));
+            }
+            let put = PutRequest::builder().set_item(Some(item)).build()?;
+            writes.push(WriteRequest::builder().put_request(put).build());
+            keys.push(key);
+        }
+        for key in deletes {
+            let delete = DeleteRequest::builder().set_key(Some(T::key_item(key))).build()?;
+            writes.push(WriteRequest::builder().delete_request(delete).build());
+            keys.push(key.clone());
+        }
+
+        let mut failed_keys = HashSet::new();
+        let mut error = None;
+        for failure in send_batch_writes(&self.client, &self.table, writes).await {
+            failed_keys.extend(failure.writes.iter().map(write_key::<T>));
+            error.get_or_insert(failure.error);
+        }
+        let (failed, written) = keys.into_iter().partition(|key| failed_keys.contains(&T::stored_key(key)));
+        Ok(BatchWriteReport { written, failed, error })
+    }
+
+    /// Applies `update` to an existing entity in one UpdateItem call and
+    /// returns it as it is now (`ReturnValues::AllNew`).
+    /// Fails with `DynamoError::NotFound` rather than creating the entity.
+    ///
+    /// Versioned entities get their version incremented. With `expected`, the
+    /// update only applies to that version and fails with
+    /// `DynamoError::VersionMismatch` otherwise.
+    pub async fn update(&self, key: &T::Key, update: Update, expected: Option<u64>) -> Result<T, DynamoError> {
+        let request = UpdateRequest::new::<T>(update, expected);
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(T::key_item(key)))
+            .update_expression(request.expression)
+            .condition_expression(request.condition)
+            .set_expression_attribute_names(Some(request.names))
+            .set_expression_attribute_values(request.values)
+            .return_values(ReturnValue::AllNew)
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .send()
+            .await;
+
+        match result.map_err(DynamoError::from) {
+            Ok(output) => Ok(serde_dynamo::aws_sdk_dynamodb_1::from_item(output.attributes.unwrap_or_default())?),
+            // The item is returned when it exists at another version
+            Err(e @ DynamoError::ConditionalCheckFailed { .. }) => match e.condition_failed_item() {
+                Some(_) => Err(DynamoError::VersionMismatch(T::NAME.to_string())),
+                None => Err(DynamoError::not_found(T::NAME)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// What `Repository::batch_write` applied. Batches are not atomic, the writes
+/// in `written` stay done when others fail.
+#[derive(Debug)]
+pub struct BatchWriteReport<K> {
+    /// Keys of the puts and deletes that were written, in the order given
+    pub written: Vec<K>,
+    /// Keys of the ones that were not
+    pub failed: Vec<K>,
+    /// Why the first of the `failed` writes failed, `DynamoError::Unprocessed`
+    /// when DynamoDB left them unprocessed after the retries
+    pub error: Option<DynamoError>,
+}
+
+/// Most keys DynamoDB takes in one BatchGetItem
+const BATCH_GET_LIMIT: usize = 100;
+/// Most writes DynamoDB takes in one BatchWriteItem
+const BATCH_WRITE_LIMIT: usize = 25;
+/// Calls per chunk before its unprocessed items are given up on
+const BATCH_ATTEMPTS: u32 = 8;
+
+async fn batch_get_chunk(
+    client: Client,
+    table: String,
+    keys: Vec<HashMap<String, AttributeValue>>,
+) -> Result<Vec<HashMap<String, AttributeValue>>, DynamoError> {
+    let mut items = vec![];
+    let mut request = KeysAndAttributes::builder().set_keys(Some(keys)).build()?;
+    for attempt in 1.. {
+        let output = client.batch_get_item().request_items(&table, request).send().await?;
+        if let Some(mut responses) = output.responses {
+            items.extend(responses.remove(&table).unwrap_or_default());
+        }
+
+        let unprocessed = output.unprocessed_keys.and_then(|mut keys| keys.remove(&table));
+        match unprocessed.filter(|unprocessed| !unprocessed.keys.is_empty()) {
+            None => break,
+            Some(unprocessed) if attempt == BATCH_ATTEMPTS => return Err(DynamoError::Unprocessed(unprocessed.keys.len())),
+            Some(unprocessed) => {
+                tokio::time::sleep(backoff(attempt)).await;
+                request = unprocessed;
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Puts `items` one after the other, each only if `condition` (no item with
+/// its key) holds, failing with `DynamoError::AlreadyExists(name)` otherwise
+async fn create_items(
+    client: Client,
+    table: String,
+    items: Vec<HashMap<String, AttributeValue>>,
+    name: &'static str,
+    condition: Condition,
+) -> Result<(), DynamoError> {
+    for item in items {
+        let result = client
+            .put_item()
+            .table_name(&table)
+            .set_item(Some(item))
+            .condition_expression(&condition.expression)
+            .set_expression_attribute_names(Some(condition.names.clone()))
+            .send()
+            .await;
+
+        match result.map_err(DynamoError::from) {
+            Ok(_) => {}
+            Err(DynamoError::ConditionalCheckFailed { .. }) => return Err(DynamoError::AlreadyExists(name.to_string())),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `writes` with one BatchWriteItem per 25, all at once, retrying
+/// unprocessed items with backoff, for items that aren't a `DynamoEntity`.
+/// Fails with the error of the first chunk that failed, the others stay done.
+pub(crate) async fn batch_write_items(client: &Client, table: &str, writes: Vec<WriteRequest>) -> Result<(), DynamoError> {
+    match send_batch_writes(client, table, writes).await.into_iter().next() {
+        Some(failure) => Err(failure.error),
+        None => Ok(()),
+    }
+}
+
+/// Writes of a chunk that were not applied, and why
+struct FailedWrites {
+    writes: Vec<WriteRequest>,
+    error: DynamoError,
+}
+
+/// The failed writes of each chunk, none when everything was written
+async fn send_batch_writes(client: &Client, table: &str, writes: Vec<WriteRequest>) -> Vec<FailedWrites> {
+    let mut chunks = JoinSet::new();
+    for chunk in writes.chunks(BATCH_WRITE_LIMIT) {
+        chunks.spawn(batch_write_chunk(client.clone(), table.to_string(), chunk.to_vec()));
+    }
+
+    let mut failed = vec![];
+    while let Some(chunk) = chunks.join_next().await {
+        if let Err(failure) = chunk.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())) {
+            failed.push(failure);
+        }
+    }
+    failed
+}
+
+/// Writes of earlier attempts stay done when a later attempt fails, so only
+/// the writes of the failed attempt are returned
+async fn batch_write_chunk(client: Client, table: String, mut writes: Vec<WriteRequest>) -> Result<(), FailedWrites> {
+    for attempt in 1.. {
+        let output = match client.batch_write_item().request_items(&table, writes.clone()).send().await {
+            Ok(output) => output,
+            Err(e) => return Err(FailedWrites { writes, error: e.into() }),
+        };
+
+        let unprocessed = output.unprocessed_items.and_then(|mut items| items.remove(&table));
+        match unprocessed.filter(|unprocessed| !unprocessed.is_empty()) {
+            None => break,
+            Some(unprocessed) if attempt == BATCH_ATTEMPTS => {
+                return Err(FailedWrites { error: DynamoError::Unprocessed(unprocessed.len()), writes: unprocessed })
+            }
+            Some(unprocessed) => {
+                tokio::time::sleep(backoff(attempt)).await;
+                writes = unprocessed;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The stored key a put or delete of a `T` writes, see `DynamoEntity::stored_key`
+fn write_key<T: DynamoEntity>(write: &WriteRequest) -> (String, Option<String>) {
+    let item = write.put_request().map(|put| put.item()).or_else(|| write.delete_request().map(|delete| delete.key()));
+    let id = |name: &str| item.and_then(|item| item.get(name)).and_then(|value| value.as_s().ok()).cloned();
+    (id(T::PARTITION_KEY.name).unwrap_or_default(), T::SORT_KEY.and_then(|attribute| id(attribute.name)))
+}
+
+/// Exponential backoff with full jitter: a random wait of up to 50ms, 100ms, 200ms, ... 5s
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = 50u64.saturating_mul(1 << (attempt - 1).min(16)).min(5_000);
+    Duration::from_millis(fastrand::u64(0..=ceiling))
+}
+
+/// Refuses unconditional puts of a versioned `T`
+pub(crate) fn unversioned<T: DynamoEntity>() -> Result<(), DynamoError> {
+    match T::VERSION {
+        Some(_) => Err(DynamoError::Versioned(T::NAME.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// The item a create writes, versioned entities start at version 1
+pub(crate) fn create_item<T: DynamoEntity>(entity: &T) -> Result<HashMap<String, AttributeValue>, DynamoError> {
+    let mut item = serde_dynamo::aws_sdk_dynamodb_1::to_item(entity)?;
+    if let Some(version) = T::VERSION {
+        item.insert(version.to_string(), AttributeValue::N("1".to_string()));
+    }
+    Ok(item)
+}
+
+/// The expressions of an UpdateItem on an existing `T`, also used in transactions
+pub(crate) struct UpdateRequest {
+    pub expression: String,
+    pub condition: String,
+    pub names: HashMap<String, String>,
+    /// `None` when empty, DynamoDB rejects an empty map
+    pub values: Option<HashMap<String, AttributeValue>>,
+}
+
+impl UpdateRequest {
+    pub fn new<T: DynamoEntity>(mut update: Update, expected: Option<u64>) -> Self {
+        let mut condition = Condition::exists::<T>();
+        if let Some(version) = T::VERSION {
+            update = update.add(version, AttributeValue::N("1".to_string()));
+        }
+        if let Some(version_condition) = expected.and_then(Condition::version::<T>) {
+            condition = condition.and(version_condition);
+        }
+
+        let expression = update.expression();
+        let (mut names, mut values) = (update.names, update.values);
+        names.extend(condition.names);
+        values.extend(condition.values);
+        UpdateRequest {
+            expression,
+            condition: condition.expression,
+            names,
+            values: Some(values).filter(|values| !values.is_empty()),
+        }
+    }
+}
+
+/// A `ConditionExpression` with its placeholders, e.g.
+/// `Condition::new("#price < :max").name("#price", "price").value(":max", AttributeValue::N("10".into()))`
+#[derive(Clone, Debug, Default)]
+pub struct Condition {
+    pub(crate) expression: String,
+    pub(crate) names: HashMap<String, String>,
+    pub(crate) values: HashMap<String, AttributeValue>,
+}
+
+impl Condition {
+    pub fn new(expression: impl Into<String>) -> Self {
+        Condition { expression: expression.into(), ..Condition::default() }
+    }
+
+    pub fn name(mut self, placeholder: impl Into<String>, name: impl Into<String>) -> Self {
+        self.names.insert(placeholder.into(), name.into());
+        self
+    }
+
+    pub fn value(mut self, placeholder: impl Into<String>, value: AttributeValue) -> Self {
+        self.values.insert(placeholder.into(), value);
+        self
+    }
+
+    /// Both conditions have to hold. Their placeholders must not clash.
+    pub fn and(mut self, other: Condition) -> Self {
+        self.expression = format!("{} AND {}", self.expression, other.expression);
+        self.names.extend(other.names);
+        self.values.extend(other.values);
+        self
+    }
+
+    /// An item of `T` exists with the key
+    pub fn exists<T: DynamoEntity>() -> Self {
+        Condition::new("attribute_exists(#key)").name("#key", T::PARTITION_KEY.name)
+    }
+
+    /// No item exists with the key
+    pub fn not_exists<T: DynamoEntity>() -> Self {
+        Condition::new("attribute_not_exists(#key)").name("#key", T::PARTITION_KEY.name)
+    }
+
+    /// The item is at version `expected`, `None` if `T` is not versioned.
+    /// Items written before versioning have no version attribute, they count as version 0.
+    pub fn version<T: DynamoEntity>(expected: u64) -> Option<Self> {
+        let expression = if expected == 0 {
+            "(attribute_not_exists(#version) OR #version = :expected)"
+        } else {
+            "#version = :expected"
+        };
+        T::VERSION.map(|version| {
+            Condition::new(expression)
+                .name("#version", version)
+                .value(":expected", AttributeValue::N(expected.to_string()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use crate::dynamo_query_helpers::Transaction;
+    use crate::user_table::{UserTable, UserTableId, UserTablePatch};
+    use super::*;
+
+    fn orders() -> (FakeDynamo, Repository<UserTable>) {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        let repository = Repository::new(fake.client(), &TableNames::default());
+        (fake, repository)
+    }
+
+    fn id(order: &str) -> UserTableId {
+        UserTableId { user: "user1".to_string(), order: order.to_string() }
+    }
+
+    fn price(price: f64) -> Update {
+        UserTablePatch { price: Some(price), ..Default::default() }.update().unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_refuses_an_existing_key() {
+        let (_, orders) = orders();
+        let order = UserTable::new(&id("order1"), "p#prod1".to_string(), 1.5);
+        orders.create(&order).await.unwrap();
+
+        let stored = orders.get(&id("o#order1")).await.unwrap().unwrap();
+        assert_eq!((stored.UserId.as_str(), stored.version), ("u#user1", 1));
+        let existing = orders.create(&order).await.unwrap_err();
+        assert!(matches!(existing, DynamoError::AlreadyExists(_)));
+        assert_eq!(existing.to_string(), "Order already exists");
+    }
+
+    #[tokio::test]
+    async fn update_checks_the_version() {
+        let (_, orders) = orders();
+        orders.create(&UserTable::new(&id("order1"), "p#prod1".to_string(), 1.5)).await.unwrap();
+
+        let updated = orders.update(&id("order1"), price(2.5), Some(1)).await.unwrap();
+        assert_eq!((updated.price, updated.version), (2.5, 2));
+
+        let stale = orders.update(&id("order1"), price(3.0), Some(1)).await;
+        assert!(matches!(stale, Err(DynamoError::VersionMismatch(_))));
+        let missing = orders.update(&id("order2"), price(3.0), None).await;
+        assert!(matches!(missing, Err(DynamoError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn delete_checks_the_version() {
+        let (fake, orders) = orders();
+        orders.create(&UserTable::new(&id("order1"), "p#prod1".to_string(), 1.5)).await.unwrap();
+
+        assert!(matches!(orders.delete(&id("order1"), Some(2)).await, Err(DynamoError::VersionMismatch(_))));
+        assert!(orders.delete(&id("order1"), Some(1)).await.unwrap().is_some());
+        assert!(orders.delete(&id("order1"), Some(1)).await.unwrap().is_none());
+        assert!(fake.items("UserTable").is_empty());
+    }
+
+    #[tokio::test]
+    async fn puts_refuse_versioned_entities() {
+        let (fake, orders) = orders();
+        let order = UserTable::new(&id("order1"), "p#prod1".to_string(), 1.5);
+
+        assert!(matches!(orders.put(&order).await, Err(DynamoError::Versioned(_))));
+        let transaction = Transaction::new(&TableNames::default()).put(&order);
+        assert!(matches!(transaction, Err(DynamoError::Versioned(_))));
+        assert!(fake.items("UserTable").is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches_retry_unprocessed_items() {
+        let (fake, orders) = orders();
+        let puts: Vec<_> = (0..30).map(|n| UserTable::new(&id(&format!("order{n}")), "p#prod1".to_string(), 1.0)).collect();
+
+        // The first call of each of the two chunks is left unprocessed
+        fake.leave_unprocessed(2);
+        orders.batch_write(&puts, &[]).await.unwrap();
+        assert_eq!(fake.items("UserTable").len(), 30);
+
+        let mut keys: Vec<_> = (0..30).map(|n| id(&format!("order{n}"))).collect();
+        keys.push(id("order0"));
+        keys.push(id("o#order1"));
+        keys.push(id("missing"));
+        fake.leave_unprocessed(1);
+        assert_eq!(orders.batch_get(&keys).await.unwrap().len(), 30);
+
+        orders.batch_write(&[], &keys[..10]).await.unwrap();
+        assert_eq!(fake.items("UserTable").len(), 20);
+    }
+
+    fn orders_of(keys: &[UserTableId]) -> Vec<&str> {
+        keys.iter().map(|key| key.order.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn batch_puts_replace_existing_entities_at_their_next_version() {
+        let (_, orders) = orders();
+        orders.create(&UserTable::new(&id("order1"), "p#prod1".to_string(), 1.5)).await.unwrap();
+        orders.update(&id("order1"), price(2.5), Some(1)).await.unwrap();
+        orders.create(&UserTable::new(&id("order3"), "p#prod1".to_string(), 1.5)).await.unwrap();
+
+        let puts = [
+            UserTable::new(&id("order1"), "p#prod2".to_string(), 4.0),
+            UserTable::new(&id("order2"), "p#prod2".to_string(), 5.0),
+        ];
+        let report = orders.batch_write(&puts, &[id("order3")]).await.unwrap();
+        assert_eq!(orders_of(&report.written), ["o#order1", "o#order2", "order3"]);
+        assert!(report.failed.is_empty() && report.error.is_none());
+
+        let replaced = orders.get(&id("order1")).await.unwrap().unwrap();
+        assert_eq!((replaced.price, replaced.version), (4.0, 3));
+        let created = orders.get(&id("order2")).await.unwrap().unwrap();
+        assert_eq!((created.price, created.version), (5.0, 1));
+        assert!(orders.get(&id("order3")).await.unwrap().is_none());
+        // A version read before the batch no longer matches
+        assert!(matches!(orders.update(&id("order1"), price(3.0), Some(2)).await, Err(DynamoError::VersionMismatch(_))));
+    }
+
+    #[tokio::test]
+    async fn batch_writes_report_the_writes_that_failed() {
+        let (fake, orders) = orders();
+        let puts: Vec<_> = (0..3).map(|n| UserTable::new(&id(&format!("order{n}")), "p#prod1".to_string(), 1.0)).collect();
+        orders.batch_write(&puts, &[]).await.unwrap();
+
+        // Every attempt of the one chunk is left unprocessed
+        fake.leave_unprocessed(BATCH_ATTEMPTS as usize);
+        let report = orders.batch_write(&[], &[id("order2"), id("o#order0")]).await.unwrap();
+
+        assert!(matches!(report.error, Some(DynamoError::Unprocessed(2))));
+        assert!(report.written.is_empty());
+        assert_eq!(orders_of(&report.failed), ["order2", "o#order0"]);
+        assert_eq!(fake.items("UserTable").len(), 3);
+    }
+}
@@ -0,0 +1,276 @@
+//! Loads the sample items of a NoSQL Workbench model, e.g. the `TableData` of
+//! `UserTable` in AccountUser.json, so local and test tables start from the
+//! same data:
+//! ```text
+//! cargo run -- seed                        # AccountUser.json's UserTable into tables.user_table
+//! cargo run -- seed --clear                # after deleting every item of the table
+//! cargo run -- seed --model model.json --table Orders --into Orders-dev
+//! ```
+//! Items are written with BatchWriteItem, so existing items with the same key are replaced.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{AttributeValue, DeleteRequest, PutRequest, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+use crate::command::options;
+use crate::config::TableNames;
+use crate::dynamo::DynamoError;
+use crate::provision::{ProvisionError, WorkbenchModel, WorkbenchTable};
+use crate::repository::batch_write_items;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeedError {
+    #[error(transparent)]
+    Dynamo(#[from] DynamoError),
+    #[error(transparent)]
+    Model(#[from] ProvisionError),
+    #[error("no table {0} in the model")]
+    UnknownTable(String),
+    /// `item` counts from 0 in the `TableData` of the table
+    #[error("item {item} of {table}: {message}")]
+    InvalidItem { table: String, item: usize, message: String },
+}
+
+/// What `seed` did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeedReport {
+    pub deleted: usize,
+    pub written: usize,
+}
+
+/// The `TableData` of a model table as SDK items
+pub fn table_items(table: &WorkbenchTable) -> Result<Vec<HashMap<String, AttributeValue>>, SeedError> {
+    table
+        .table_data
+        .iter()
+        .enumerate()
+        .map(|(n, item)| {
+            item_from_json(item).map_err(|message| SeedError::InvalidItem { table: table.table_name.clone(), item: n, message })
+        })
+        .collect()
+}
+
+/// An item in the typed JSON of DynamoDB, e.g. `{"UserId": {"S": "u#user1"}, "price": {"N": "0.7"}}`
+pub fn item_from_json(item: &Map<String, Value>) -> Result<HashMap<String, AttributeValue>, String> {
+    item.iter()
+        .map(|(name, value)| Ok((name.clone(), attribute_value(value).map_err(|message| format!("{name}: {message}"))?)))
+        .collect()
+}
+
+/// One typed value: `S`, `N`, `BOOL`, `NULL`, `B` (base64), `SS`, `NS`, `BS`, `L` or `M`
+pub fn attribute_value(value: &Value) -> Result<AttributeValue, String> {
+    let Some((kind, value)) = value.as_object().filter(|typed| typed.len() == 1).and_then(|typed| typed.iter().next()) else {
+        return Err(format!("expected one type and its value, e.g. {{\"S\": \"text\"}}, got {value}"));
+    };
+    let string = |value: &Value| value.as_str().map(str::to_string).ok_or_else(|| format!("{kind} needs a string, got {value}"));
+    let number = |value: &Value| {
+        let number = string(value)?;
+        number.parse::<f64>().map(|_| number.clone()).map_err(|_| format!("{number:?} is not a number"))
+    };
+    let binary = |value: &Value| {
+        BASE64_STANDARD.decode(string(value)?).map(Blob::new).map_err(|e| format!("{kind} is not base64: {e}"))
+    };
+    let array = |value: &Value| value.as_array().cloned().ok_or_else(|| format!("{kind} needs an array, got {value}"));
+
+    match kind.as_str() {
+        "S" => string(value).map(AttributeValue::S),
+        "N" => number(value).map(AttributeValue::N),
+        "BOOL" => value.as_bool().map(AttributeValue::Bool).ok_or_else(|| format!("BOOL needs true or false, got {value}")),
+        "NULL" => Ok(AttributeValue::Null(true)),
+        "B" => binary(value).map(AttributeValue::B),
+        "SS" => array(value)?.iter().map(string).collect::<Result<_, _>>().map(AttributeValue::Ss),
+        "NS" => array(value)?.iter().map(number).collect::<Result<_, _>>().map(AttributeValue::Ns),
+        "BS" => array(value)?.iter().map(binary).collect::<Result<_, _>>().map(AttributeValue::Bs),
+        "L" => array(value)?.iter().map(attribute_value).collect::<Result<_, _>>().map(AttributeValue::L),
+        "M" => value
+            .as_object()
+            .ok_or_else(|| format!("M needs an object, got {value}"))
+            .and_then(item_from_json)
+            .map(AttributeValue::M),
+        _ => Err(format!("unknown type {kind:?}")),
+    }
+}
+
+/// Writes `items` into `table`, after deleting all its items when `clear` is set
+pub async fn seed(
+    client: &Client,
+    table: &str,
+    items: Vec<HashMap<String, AttributeValue>>,
+    clear: bool,
+) -> Result<SeedReport, DynamoError> {
+    let mut report = SeedReport::default();
+    if clear {
+        report.deleted = clear_table(client, table).await?;
+    }
+
+    report.written = items.len();
+    let mut writes = Vec::with_capacity(items.len());
+    for item in items {
+        let put = PutRequest::builder().set_item(Some(item)).build()?;
+        writes.push(WriteRequest::builder().put_request(put).build());
+    }
+    batch_write_items(client, table, writes).await?;
+    Ok(report)
+}
+
+/// Deletes every item of `table` and returns how many there were
+pub async fn clear_table(client: &Client, table: &str) -> Result<usize, DynamoError> {
+    let description = client.describe_table().table_name(table).send().await?.table;
+    let key_names: Vec<String> =
+        description.iter().flat_map(|table| table.key_schema()).map(|key| key.attribute_name().to_string()).collect();
+
+    // Only the keys are read, the names are placeholders in case they are reserved words
+    let placeholders: Vec<String> = (0..key_names.len()).map(|n| format!("#k{n}")).collect();
+    let names: HashMap<String, String> = placeholders.iter().cloned().zip(key_names.iter().cloned()).collect();
+
+    let mut writes = Vec::new();
+    let mut start_key = None;
+    loop {
+        let page = client
+            .scan()
+            .table_name(table)
+            .projection_expression(placeholders.join(", "))
+            .set_expression_attribute_names(Some(names.clone()))
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for key in page.items.unwrap_or_default() {
+            let delete = DeleteRequest::builder().set_key(Some(key)).build()?;
+            writes.push(WriteRequest::builder().delete_request(delete).build());
+        }
+        start_key = page.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+
+    let deleted = writes.len();
+    batch_write_items(client, table, writes).await?;
+    Ok(deleted)
+}
+
+/// `seed [--model PATH] [--table NAME] [--into NAME] [--clear]`
+#[derive(Debug, PartialEq)]
+pub struct SeedCommand {
+    /// AccountUser.json by default
+    pub model: PathBuf,
+    /// Table of the model whose items are loaded, `UserTable` by default
+    pub table: String,
+    /// Table written to. By default `tables.user_table` for `UserTable`, and the
+    /// table of the same name for the other tables of the model.
+    pub into: Option<String>,
+    /// Delete the items of the table first
+    pub clear: bool,
+}
+
+impl Default for SeedCommand {
+    fn default() -> Self {
+        SeedCommand {
+            model: PathBuf::from("AccountUser.json"),
+            table: TableNames::default().user_table,
+            into: None,
+            clear: false,
+        }
+    }
+}
+
+impl SeedCommand {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut command = SeedCommand::default();
+        for (flag, value) in options("seed", args, &["--clear"], &["--model", "--table", "--into"])? {
+            match (flag.as_str(), value) {
+                ("--model", Some(path)) => command.model = PathBuf::from(path),
+                ("--table", Some(table)) => command.table = table,
+                ("--into", Some(table)) => command.into = Some(table),
+                _ => command.clear = true,
+            }
+        }
+        Ok(command)
+    }
+
+    fn target(&self, tables: &TableNames) -> String {
+        match &self.into {
+            Some(table) => table.clone(),
+            None if self.table == TableNames::default().user_table => tables.user_table.clone(),
+            None => self.table.clone(),
+        }
+    }
+
+    /// Seeds the table, printing what was done
+    pub async fn run(&self, client: Client, tables: &TableNames) -> Result<(), SeedError> {
+        let model = WorkbenchModel::load(&self.model)?;
+        let table = model.table(&self.table).ok_or_else(|| SeedError::UnknownTable(self.table.clone()))?;
+        let items = table_items(table)?;
+
+        let target = self.target(tables);
+        let report = seed(&client, &target, items, self.clear).await?;
+        if self.clear {
+            println!("{target}: deleted {} items", report.deleted);
+        }
+        println!("{target}: wrote {} items from {} of {:?}", report.written, self.table, self.model);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use serde_json::json;
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use crate::repository::Repository;
+    use crate::user_table::{UserTable, UserTableId};
+    use super::*;
+
+    fn account_users() -> Vec<HashMap<String, AttributeValue>> {
+        let model = WorkbenchModel::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("AccountUser.json")).unwrap();
+        table_items(model.table("UserTable").unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn account_users_are_seeded_and_reseeded() {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        let client = fake.client();
+        let items = account_users();
+        let count = items.len();
+
+        let report = seed(&client, "UserTable", items.clone(), false).await.unwrap();
+        assert_eq!(report, SeedReport { deleted: 0, written: count });
+
+        let orders = Repository::<UserTable>::new(client.clone(), &TableNames::default());
+        let order = orders.get(&UserTableId { user: "user1".to_string(), order: "order1".to_string() }).await.unwrap().unwrap();
+        assert_eq!(order.price, 0.711358378874138);
+        assert_eq!(order.gsi_pk, 1);
+
+        fake.insert("UserTable", json!({"UserId": {"S": "u#extra"}, "OrderId": {"S": "o#extra"}}));
+        let report = seed(&client, "UserTable", items, true).await.unwrap();
+        assert_eq!(report, SeedReport { deleted: count + 1, written: count });
+        assert_eq!(fake.items("UserTable").len(), count);
+    }
+
+    #[test]
+    fn typed_values_are_checked() {
+        let item = json!({
+            "tags": {"SS": ["a", "b"]},
+            "sizes": {"L": [{"N": "1"}, {"M": {"unit": {"S": "cm"}}}]},
+            "gift": {"BOOL": true},
+        });
+        let item = item_from_json(item.as_object().unwrap()).unwrap();
+        assert_eq!(item["tags"], AttributeValue::Ss(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(
+            item["sizes"],
+            AttributeValue::L(vec![
+                AttributeValue::N("1".to_string()),
+                AttributeValue::M(HashMap::from([("unit".to_string(), AttributeValue::S("cm".to_string()))])),
+            ])
+        );
+
+        assert!(attribute_value(&json!({"N": "cheap"})).is_err());
+        assert!(attribute_value(&json!({"N": 1})).is_err());
+        assert!(attribute_value(&json!({"S": "a", "N": "1"})).is_err());
+        assert!(attribute_value(&json!({"DATE": "2024-10-01"})).is_err());
+    }
+}
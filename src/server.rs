@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+use axum::Router;
+use lambda_http::tracing::log::info;
+use tokio::net::TcpListener;
+
+/// Address used by the standalone server when `--listen` has no value
+const DEFAULT_LISTEN_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 8080);
+
+/// How the binary should serve the router
+#[derive(Debug, PartialEq)]
+pub enum RunMode {
+    /// Hand requests to the AWS Lambda runtime (also used by `cargo lambda watch`)
+    Lambda,
+    /// Run a plain axum HTTP server bound to the address
+    Standalone(SocketAddr),
+}
+
+/// Picks the run mode from the command line and environment.
+///
+/// `--listen [ADDR]` (or `--listen=ADDR`) always starts the standalone server.
+/// Otherwise the Lambda runtime is used when `AWS_LAMBDA_RUNTIME_API` is set,
+/// which is the case both on AWS and under `cargo lambda watch`, and the
+/// standalone server on the default address is used when it is not.
+pub fn run_mode() -> Result<RunMode, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let lambda_runtime = std::env::var("AWS_LAMBDA_RUNTIME_API").is_ok();
+    parse_run_mode(&args, lambda_runtime)
+}
+
+fn parse_run_mode(args: &[String], lambda_runtime: bool) -> Result<RunMode, String> {
+    let default_addr = SocketAddr::from(DEFAULT_LISTEN_ADDR);
+    let mut args = args.iter().peekable();
+
+    while let Some(arg) = args.next() {
+        let addr = if let Some(value) = arg.strip_prefix("--listen=") {
+            Some(value.to_string())
+        } else if arg == "--listen" {
+            // The address is optional, so only consume the next argument if it isn't a flag
+            match args.next_if(|value| !value.starts_with("--")) {
+                Some(value) => Some(value.clone()),
+                None => Some(default_addr.to_string()),
+            }
+        } else {
+            None
+        };
+
+        if let Some(addr) = addr {
+            return addr
+                .parse()
+                .map(RunMode::Standalone)
+                .map_err(|e| format!("invalid --listen address {addr:?}: {e}"));
+        }
+    }
+
+    if lambda_runtime {
+        Ok(RunMode::Lambda)
+    } else {
+        Ok(RunMode::Standalone(default_addr))
+    }
+}
+
+/// Serves the router on a local TCP listener until SIGTERM or Ctrl-C is received.
+/// In-flight requests are allowed to finish before returning.
+pub async fn serve_standalone(app: Router, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+}
+
+/// Completes when the process receives Ctrl-C (SIGINT) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            info!("unable to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                info!("unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutdown signal received, draining connections");
+}
@@ -0,0 +1,264 @@
+use std::path::Path;
+use std::str::FromStr;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use simple_asn1::ASN1Block;
+use crate::config::{JwtConfig, SigningKeyConfig};
+use crate::jwk::JwkKey;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SigningKeyError {
+    #[error("key {kid}: could not read {path}: {source}")]
+    Read { kid: String, path: String, source: std::io::Error },
+    #[error("key {kid}: invalid key: {message}")]
+    InvalidKey { kid: String, message: String },
+    #[error("key {kid}: unsupported algorithm {algorithm}, expected RS256, RS384, RS512, PS256, PS384, PS512, ES256, ES384 or EdDSA")]
+    UnsupportedAlgorithm { kid: String, algorithm: String },
+}
+
+/// Key used to verify self-issued tokens. `kid` is `None` for the HS256 secret.
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Keys for the self-issued JWTs of the `/signin` flow.
+///
+/// With `jwt.signing_keys` configured, tokens are signed by the `jwt.active_kid` key
+/// and carry its `kid` in the header. Every configured key stays valid for verification
+/// and is published on `/.well-known/jwks.json`, so a key can be rotated by adding the new
+/// key, switching `active_kid` to it and removing the old key once its tokens have expired.
+///
+/// Without signing keys, tokens fall back to HS256 with `jwt.secret` and no JWKS is published.
+pub struct SigningKeys {
+    signing_kid: Option<String>,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying: Vec<VerifyingKey>,
+    jwks: Vec<JwkKey>,
+}
+
+impl SigningKeys {
+    pub fn from_config(config: &JwtConfig) -> Result<Self, SigningKeyError> {
+        if config.signing_keys.is_empty() {
+            return Ok(Self::from_secret(&config.secret));
+        }
+
+        let mut signing = None;
+        let mut verifying = Vec::new();
+        let mut jwks = Vec::new();
+
+        for key_config in &config.signing_keys {
+            let kid = &key_config.kid;
+            let algorithm = Algorithm::from_str(&key_config.algorithm)
+                .ok()
+                .filter(|algorithm| key_family(*algorithm).is_some())
+                .ok_or_else(|| SigningKeyError::UnsupportedAlgorithm {
+                    kid: kid.clone(),
+                    algorithm: key_config.algorithm.clone(),
+                })?;
+
+            let public_pem = read_key_file(kid, &key_config.public_key_path)?;
+            let public_der = pem::parse(&public_pem)
+                .map_err(|e| invalid_key(kid, e))?
+                .into_contents();
+            let jwk = public_jwk(kid, algorithm, &public_der)?;
+
+            verifying.push(VerifyingKey {
+                kid: Some(kid.clone()),
+                algorithm,
+                key: decoding_key(&jwk).map_err(|e| invalid_key(kid, e))?,
+            });
+            jwks.push(jwk);
+
+            if *kid == config.active_kid {
+                let path = key_config.private_key_path.as_deref().ok_or_else(|| SigningKeyError::InvalidKey {
+                    kid: kid.clone(),
+                    message: "the active key needs a private_key_path".to_string(),
+                })?;
+                let private_pem = read_key_file(kid, path)?;
+                let key = match key_family(algorithm) {
+                    Some(KeyFamily::Rsa) => EncodingKey::from_rsa_pem(&private_pem),
+                    Some(KeyFamily::Ec(..)) => EncodingKey::from_ec_pem(&private_pem),
+                    _ => EncodingKey::from_ed_pem(&private_pem),
+                }
+                    .map_err(|e| invalid_key(kid, e))?;
+                signing = Some((kid.clone(), algorithm, key));
+            }
+        }
+
+        // Config validation guarantees the active key is one of the signing keys
+        let (kid, algorithm, key) = signing.ok_or_else(|| SigningKeyError::InvalidKey {
+            kid: config.active_kid.clone(),
+            message: "jwt.active_kid does not name a configured signing key".to_string(),
+        })?;
+
+        Ok(SigningKeys {
+            signing_kid: Some(kid),
+            signing_algorithm: algorithm,
+            signing_key: key,
+            verifying,
+            jwks,
+        })
+    }
+
+    fn from_secret(secret: &str) -> Self {
+        SigningKeys {
+            signing_kid: None,
+            signing_algorithm: Algorithm::HS256,
+            signing_key: EncodingKey::from_secret(secret.as_ref()),
+            verifying: vec![VerifyingKey {
+                kid: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_ref()),
+            }],
+            jwks: vec![],
+        }
+    }
+
+    /// Header for a new token, naming the active key
+    pub fn header(&self) -> Header {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = self.signing_kid.clone();
+        header
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.signing_key
+    }
+
+    /// Finds the key a token was signed with from the `kid` in its header
+    pub fn decoding_key(&self, kid: Option<&str>) -> Option<(Algorithm, &DecodingKey)> {
+        self.verifying
+            .iter()
+            .find(|key| key.kid.as_deref() == kid)
+            .map(|key| (key.algorithm, &key.key))
+    }
+
+    /// Public keys to publish on `/.well-known/jwks.json`
+    pub fn jwks(&self) -> &[JwkKey] {
+        &self.jwks
+    }
+}
+
+enum KeyFamily {
+    Rsa,
+    /// Curve name and coordinate length in bytes
+    Ec(&'static str, usize),
+    Ed25519,
+}
+
+fn key_family(algorithm: Algorithm) -> Option<KeyFamily> {
+    match algorithm {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => Some(KeyFamily::Rsa),
+        Algorithm::ES256 => Some(KeyFamily::Ec("P-256", 32)),
+        Algorithm::ES384 => Some(KeyFamily::Ec("P-384", 48)),
+        Algorithm::EdDSA => Some(KeyFamily::Ed25519),
+        _ => None,
+    }
+}
+
+/// Builds the JWK for a PEM `SubjectPublicKeyInfo`
+fn public_jwk(kid: &str, algorithm: Algorithm, spki_der: &[u8]) -> Result<JwkKey, SigningKeyError> {
+    let public_key = spki_public_key(spki_der).ok_or_else(|| SigningKeyError::InvalidKey {
+        kid: kid.to_string(),
+        message: "expected a PEM encoded public key".to_string(),
+    })?;
+
+    let mut jwk = JwkKey {
+        kid: kid.to_string(),
+        alg: format!("{algorithm:?}"),
+        key_use: "sig".to_string(),
+        ..JwkKey::default()
+    };
+
+    match key_family(algorithm) {
+        Some(KeyFamily::Rsa) => {
+            let (n, e) = rsa_components(&public_key).ok_or_else(|| SigningKeyError::InvalidKey {
+                kid: kid.to_string(),
+                message: format!("{algorithm:?} needs an RSA public key"),
+            })?;
+            jwk.kty = "RSA".to_string();
+            jwk.n = BASE64_URL_SAFE_NO_PAD.encode(n);
+            jwk.e = BASE64_URL_SAFE_NO_PAD.encode(e);
+        }
+        Some(KeyFamily::Ec(curve, coordinate_len)) => {
+            // Uncompressed point: 0x04 || x || y
+            if public_key.len() != 1 + 2 * coordinate_len || public_key[0] != 0x04 {
+                return Err(SigningKeyError::InvalidKey {
+                    kid: kid.to_string(),
+                    message: format!("{algorithm:?} needs an uncompressed {curve} public key"),
+                });
+            }
+            jwk.kty = "EC".to_string();
+            jwk.crv = curve.to_string();
+            jwk.x = BASE64_URL_SAFE_NO_PAD.encode(&public_key[1..=coordinate_len]);
+            jwk.y = BASE64_URL_SAFE_NO_PAD.encode(&public_key[1 + coordinate_len..]);
+        }
+        _ => {
+            if public_key.len() != 32 {
+                return Err(SigningKeyError::InvalidKey {
+                    kid: kid.to_string(),
+                    message: "EdDSA needs an Ed25519 public key".to_string(),
+                });
+            }
+            jwk.kty = "OKP".to_string();
+            jwk.crv = "Ed25519".to_string();
+            jwk.x = BASE64_URL_SAFE_NO_PAD.encode(&public_key);
+        }
+    }
+    Ok(jwk)
+}
+
+/// Decoding key for a JWK, shared with `jwk::JwkVerifier`
+pub fn decoding_key(jwk: &JwkKey) -> jsonwebtoken::errors::Result<DecodingKey> {
+    match jwk.kty.as_str() {
+        "EC" => DecodingKey::from_ec_components(&jwk.x, &jwk.y),
+        "OKP" => DecodingKey::from_ed_components(&jwk.x),
+        _ => DecodingKey::from_rsa_components(&jwk.n, &jwk.e),
+    }
+}
+
+/// The `subjectPublicKey` bit string of a `SubjectPublicKeyInfo`
+fn spki_public_key(spki_der: &[u8]) -> Option<Vec<u8>> {
+    let blocks = simple_asn1::from_der(spki_der).ok()?;
+    match blocks.first()? {
+        ASN1Block::Sequence(_, fields) => match fields.get(1)? {
+            ASN1Block::BitString(_, _, bits) => Some(bits.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Modulus and exponent of a DER `RSAPublicKey`, as unsigned big endian bytes
+fn rsa_components(public_key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let blocks = simple_asn1::from_der(public_key).ok()?;
+    match blocks.first()? {
+        ASN1Block::Sequence(_, fields) => match (fields.first()?, fields.get(1)?) {
+            (ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)) => {
+                Some((n.to_bytes_be().1, e.to_bytes_be().1))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn read_key_file(kid: &str, path: &Path) -> Result<Vec<u8>, SigningKeyError> {
+    std::fs::read(path).map_err(|source| SigningKeyError::Read {
+        kid: kid.to_string(),
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+fn invalid_key(kid: &str, error: impl std::fmt::Display) -> SigningKeyError {
+    SigningKeyError::InvalidKey {
+        kid: kid.to_string(),
+        message: error.to_string(),
+    }
+}
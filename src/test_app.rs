@@ -0,0 +1,213 @@
+//! Sends requests through `build_router` with every table in `FakeDynamo`, e.g.
+//! ```ignore
+//! let app = TestApp::new();
+//! let token = app.firebase_token(json!({"permissions": ["orders:write"]}));
+//! let response = app.send(TestRequest::patch("/user_table/user7/order1", json!({"price": 2.5})).bearer(&token)).await;
+//! assert_eq!(response.status, StatusCode::OK);
+//! ```
+//!
+//! Tokens for `auth::authorize` are signed with the HS256 `jwt.secret`, tokens
+//! for `auth::authorize_firebase` with an Ed25519 key whose JWK is preloaded
+//! into `JwkAuth` in place of the Google JWKS.
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::Router;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use crate::app_state::AppState;
+use crate::auth::{encode_jwt, CurrentUser};
+use crate::build_router;
+use crate::config::Config;
+use crate::dynamo_fake::{FakeDynamo, TableSpec};
+use crate::jwk::{JwkAuth, JwkConfiguration, JwkKey};
+use crate::user::{UserRecord, UserStatus};
+
+/// Firebase project the test tokens are issued for
+pub const FIREBASE_PROJECT: &str = "test-project";
+const FIREBASE_KID: &str = "test-key";
+
+pub struct TestApp {
+    pub fake: FakeDynamo,
+    pub state: AppState,
+    router: Router,
+    firebase_key: EncodingKey,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        let fake = FakeDynamo::new()
+            .with_table(TableSpec::items())
+            .with_table(TableSpec::user_table())
+            .with_table(TableSpec::sessions())
+            .with_table(TableSpec::users());
+        let client = fake.client();
+        TestApp::with_client(fake, client)
+    }
+
+    /// Handlers call DynamoDB through `client`, e.g. one from `DynamoReplay`.
+    /// `fake` is only there to be looked at.
+    pub fn with_client(fake: FakeDynamo, client: aws_sdk_dynamodb::Client) -> Self {
+        let mut config = Config::default();
+        config.jwt.secret = "test-jwt-secret".to_string();
+        config.jwk = JwkConfiguration {
+            jwk_url: "https://www.googleapis.com/service_accounts/v1/jwk/securetoken@system.gserviceaccount.com".to_string(),
+            audience: FIREBASE_PROJECT.to_string(),
+            issuer: format!("https://securetoken.google.com/{FIREBASE_PROJECT}"),
+        };
+        config.pagination.secret = "test-pagination-secret-0123456789abcdef".to_string();
+        config.validate().expect("a valid test configuration");
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwk = JwkKey {
+            kty: "OKP".to_string(),
+            crv: "Ed25519".to_string(),
+            alg: "EdDSA".to_string(),
+            kid: FIREBASE_KID.to_string(),
+            x: BASE64_URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+            key_use: "sig".to_string(),
+            ..JwkKey::default()
+        };
+
+        let jwk_auth = JwkAuth::with_keys(config.jwk_issuers(), vec![jwk]);
+        let state = AppState::new(client, config).unwrap().with_jwk_auth(jwk_auth);
+        TestApp {
+            fake,
+            router: build_router(state.clone()),
+            state,
+            firebase_key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+        }
+    }
+
+    pub async fn send(&self, request: TestRequest) -> TestResponse {
+        let response = self.router.clone().oneshot(request.build()).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        TestResponse { status, headers, body }
+    }
+
+    /// Stores an active user for `auth::authorize` to find by email
+    pub async fn register_user(&self, email: &str, roles: &[&str], permissions: &[&str]) -> UserRecord {
+        let user = UserRecord {
+            user_id: format!("u#{}", uuid::Uuid::new_v4()),
+            email: email.to_string(),
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            password_hash: String::new(),
+            created_at: Utc::now().to_rfc3339(),
+            status: UserStatus::Active,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+        };
+        self.state
+            .client
+            .put_item()
+            .table_name(&self.state.config.tables.users)
+            .set_item(Some(serde_dynamo::aws_sdk_dynamodb_1::to_item(&user).unwrap()))
+            .send()
+            .await
+            .unwrap();
+        user
+    }
+
+    /// An access token as issued by `/signin`
+    pub fn custom_token(&self, user: &UserRecord) -> String {
+        encode_jwt(&CurrentUser::from(user.clone()), &self.state.signing_keys, &self.state.config.jwt).unwrap()
+    }
+
+    /// A Firebase ID token valid for an hour, `claims` are added to or replace the defaults
+    pub fn firebase_token(&self, claims: Value) -> String {
+        let now = Utc::now().timestamp();
+        let mut token_claims = json!({
+            "aud": FIREBASE_PROJECT,
+            "iss": format!("https://securetoken.google.com/{FIREBASE_PROJECT}"),
+            "sub": "firebase-user",
+            "email": "firebase-user@example.com",
+            "iat": now,
+            "exp": now + 3600,
+        });
+        if let (Some(token_claims), Value::Object(claims)) = (token_claims.as_object_mut(), claims) {
+            token_claims.extend(claims);
+        }
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(FIREBASE_KID.to_string());
+        jsonwebtoken::encode(&header, &token_claims, &self.firebase_key).unwrap()
+    }
+}
+
+pub struct TestRequest {
+    request: axum::http::request::Builder,
+    body: Body,
+}
+
+impl TestRequest {
+    pub fn get(uri: &str) -> Self {
+        TestRequest::new(Method::GET, uri, None)
+    }
+
+    pub fn delete(uri: &str) -> Self {
+        TestRequest::new(Method::DELETE, uri, None)
+    }
+
+    pub fn post(uri: &str, body: Value) -> Self {
+        TestRequest::new(Method::POST, uri, Some(body))
+    }
+
+    pub fn put(uri: &str, body: Value) -> Self {
+        TestRequest::new(Method::PUT, uri, Some(body))
+    }
+
+    pub fn patch(uri: &str, body: Value) -> Self {
+        TestRequest::new(Method::PATCH, uri, Some(body))
+    }
+
+    fn new(method: Method, uri: &str, body: Option<Value>) -> Self {
+        let mut request = Request::builder().method(method).uri(uri);
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+        TestRequest { request, body }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request = self.request.header(name, value);
+        self
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION.as_str(), &format!("Bearer {token}"))
+    }
+
+    fn build(self) -> Request<Body> {
+        self.request.body(self.body).unwrap()
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("{e} in a {} response: {}", self.status, String::from_utf8_lossy(&self.body)))
+    }
+}
@@ -1,36 +1,213 @@
 #![allow(unused)]
 
 
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, TransactWriteItem};
+use axum::extract::State;
 use axum::http::StatusCode;
+use axum::response::IntoResponse;
 use axum::Json;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use crate::app_state::AppState;
+use crate::auth::hash_password;
+use crate::api_error::ApiError;
+use crate::dynamo::DynamoError;
 
+/// Global secondary index on `email` used to look users up at sign in
+pub const EMAIL_INDEX: &str = "email_index";
 
+/// Prefix of the rows that reserve an email address
+const EMAIL_CLAIM_PREFIX: &str = "email#";
+
+#[derive(Debug, thiserror::Error)]
+pub enum UserStoreError {
+    #[error("A user with this email already exists")]
+    EmailTaken,
+    #[error("Could not hash password")]
+    HashError(#[from] bcrypt::BcryptError),
+    #[error("{0}")]
+    Dynamo(#[from] DynamoError),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    Disabled,
+}
+
+/// A registered user as stored in the users table.
+///
+/// Keyed by `user_id` ("u#{uuid}"), with `email` projected into the `email_index` GSI.
+/// Each user also has an "email#{email}" claim row in the same table that only
+/// exists to make the email unique (see `register_user`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub user_id: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub password_hash: String,
+    pub created_at: String,
+    pub status: UserStatus,
+    /// Copied into the access tokens issued at `/signin`
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+/// Row that reserves an email address for a user. It has no `email`
+/// attribute, so it never shows up in the `email_index` GSI.
+#[derive(Serialize)]
+struct EmailClaim<'a> {
+    user_id: String,
+    owner_id: &'a str,
+}
+
+/// Emails are compared case-insensitively
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Creates a user, failing with `EmailTaken` if the email is already registered.
+///
+/// The user row and its email claim row are written in one transaction, each with
+/// an `attribute_not_exists` condition, so two concurrent sign ups for the same
+/// email can't both succeed.
+pub async fn register_user(
+    client: &Client,
+    table: &str,
+    email: &str,
+    password: &str,
+    first_name: String,
+    last_name: String,
+) -> Result<UserRecord, UserStoreError> {
+    let email = normalize_email(email);
+    let user = UserRecord {
+        user_id: format!("u#{}", uuid::Uuid::new_v4()),
+        email: email.clone(),
+        first_name,
+        last_name,
+        password_hash: hash_password(password)?,
+        created_at: Utc::now().to_rfc3339(),
+        status: UserStatus::Active,
+        roles: vec![],
+        permissions: vec![],
+    };
+    let claim = EmailClaim {
+        user_id: format!("{EMAIL_CLAIM_PREFIX}{email}"),
+        owner_id: &user.user_id,
+    };
+
+    let user_put = conditional_put(table, &user)?;
+    let claim_put = conditional_put(table, &claim)?;
+
+    client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().put(user_put).build())
+        .transact_items(TransactWriteItem::builder().put(claim_put).build())
+        .send()
+        .await
+        .map_err(|e| match DynamoError::from(e) {
+            e if e.is_conditional_check_failed() => UserStoreError::EmailTaken,
+            other => UserStoreError::Dynamo(other),
+        })?;
+
+    Ok(user)
+}
+
+/// Put that only succeeds if no row with the same `user_id` exists yet
+fn conditional_put<T: Serialize>(table: &str, row: &T) -> Result<Put, UserStoreError> {
+    let item = serde_dynamo::aws_sdk_dynamodb_1::to_item(row).map_err(DynamoError::from)?;
+    Put::builder()
+        .table_name(table)
+        .set_item(Some(item))
+        .condition_expression("attribute_not_exists(user_id)")
+        .build()
+        .map_err(|e| DynamoError::from(e).into())
+}
+
+/// Looks a user up by email through the `email_index` GSI
+pub async fn find_user_by_email(
+    client: &Client,
+    table: &str,
+    email: &str,
+) -> Result<Option<UserRecord>, UserStoreError> {
+    let results = client
+        .query()
+        .table_name(table)
+        .index_name(EMAIL_INDEX)
+        .key_condition_expression("#email = :email")
+        .expression_attribute_names("#email", "email")
+        .expression_attribute_values(":email", AttributeValue::S(normalize_email(email)))
+        .limit(1)
+        .send()
+        .await
+        .map_err(DynamoError::from)?;
+
+    match results.items.and_then(|items| items.into_iter().next()) {
+        Some(item) => {
+            let user = serde_dynamo::aws_sdk_dynamodb_1::from_item(item).map_err(DynamoError::from)?;
+            Ok(Some(user))
+        }
+        None => Ok(None),
+    }
+}
+
+
+// Example to create a user.
+// curl --header "Content-Type: application/json" \
+// --request POST \
+// --data '{"email":"mike@example.com","password":"secret","first_name":"Mike","last_name":"Smith"}' \
+// http://localhost:9003/create_user
 pub(crate) async fn create_user(
+    State(state): State<AppState>,
     // this argument tells axum to parse the request body
     // as JSON into a `CreateUser` type
     Json(payload): Json<CreateUser>,
-) -> (StatusCode, Json<User>) {
-    // insert your application logic here
-    let user = User {
-        id: 1337,
-        username: payload.username,
-    };
+) -> Result<(StatusCode, Json<User>), ApiError> {
+    let user = register_user(
+        &state.client,
+        &state.config.tables.users,
+        &payload.email,
+        &payload.password,
+        payload.first_name,
+        payload.last_name,
+    )
+        .await?;
 
     // this will be converted into a JSON response
     // with a status code of `201 Created`
-    (StatusCode::CREATED, Json(user))
+    Ok((StatusCode::CREATED, Json(User::from(user))))
 }
 
 // the input to our `create_user` handler
 #[derive(Deserialize)]
 pub struct CreateUser {
-    username: String,
+    email: String,
+    password: String,
+    first_name: String,
+    last_name: String,
 }
 
 // the output to our `create_user` handler
 #[derive(Serialize)]
 pub struct User {
-    id: u64,
-    username: String,
+    id: String,
+    email: String,
+    first_name: String,
+    last_name: String,
+}
+
+impl From<UserRecord> for User {
+    fn from(user: UserRecord) -> Self {
+        Self {
+            id: user.user_id,
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+        }
+    }
 }
@@ -1,8 +1,16 @@
 use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, Utc};
 use aws_sdk_dynamodb::Client;
 use aws_sdk_dynamodb::types::AttributeValue;
 use serde::{Deserialize, Serialize};
+use crate::config::TableNames;
+use crate::dynamo::DynamoError;
+use crate::dynamo_query_helpers::{PageTokens, PagedQuery, PaginatedOutput, QueryError, SortCondition};
+use crate::repository::{DynamoEntity, KeyAttribute, Update};
+use crate::validation::{Validate, ValidationErrors};
 
+// Field names match the DynamoDB attribute names of AccountUser.json
+#[allow(non_snake_case)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserTable {
     // #[partition]
@@ -13,8 +21,12 @@ pub struct UserTable {
     pub price: f64,
     pub gsi_pk: i64,
     pub date_ordered: String,
+    /// Incremented on every update, 0 for items written before versioning
+    #[serde(default)]
+    pub version: u64,
 }
 
+#[allow(non_snake_case)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UpdateUserTable {
     pub UserId: String,
@@ -23,14 +35,124 @@ pub struct UpdateUserTable {
     pub price: f64,
 }
 
+/// A partial update of an order, only the `Some` fields are written, e.g.
+/// `{"price": 2.5}` or `{"product": "p#prod3", "price_change": -0.5}`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UserTablePatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    /// Added to the stored price
+    #[serde(skip_serializing)]
+    pub price_change: Option<f64>,
+}
+
+impl UserTablePatch {
+    /// `SET` for `product` and `price`, `ADD` for `price_change`
+    pub fn update(&self) -> Result<Update, DynamoError> {
+        let mut update = Update::from_patch(self)?;
+        if let Some(change) = self.price_change {
+            update = update.add("price", AttributeValue::N(change.to_string()));
+        }
+        Ok(update)
+    }
+}
+
+/// Identifies one order, e.g. `UserTableId { user: "user7", order: "order1" }`.
+/// The "u#" and "o#" prefixes are optional.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserTableId {
+    pub user: String,
+    pub order: String,
+}
+
+const USER_ID: KeyAttribute = KeyAttribute::prefixed("UserId", "u#");
+const ORDER_ID: KeyAttribute = KeyAttribute::prefixed("OrderId", "o#");
+
+impl Validate for UserTableId {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.key_id("user", &self.user, "u#");
+        errors.key_id("order", &self.order, "o#");
+    }
+}
+
+impl Validate for UpdateUserTable {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.key_id("UserId", &self.UserId, "u#");
+        errors.key_id("OrderId", &self.OrderId, "o#");
+        errors.not_blank("product", &self.product);
+        errors.non_negative("price", self.price);
+    }
+}
+
+/// The stored price is not checked, so `price_change` may still take it below 0
+impl Validate for UserTablePatch {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if let Some(product) = &self.product {
+            errors.not_blank("product", product);
+        }
+        if let Some(price) = self.price {
+            errors.non_negative("price", price);
+        }
+        if self.price_change.is_some_and(|change| !change.is_finite()) {
+            errors.add("price_change", "out_of_range", "must be a number");
+        }
+    }
+}
+
 impl UserTable {
+    /// A new order, listed in `gsi1` under the current time
+    pub fn new(id: &UserTableId, product: String, price: f64) -> Self {
+        UserTable {
+            UserId: USER_ID.stored(&id.user),
+            OrderId: ORDER_ID.stored(&id.order),
+            product,
+            price,
+            gsi_pk: 1,
+            date_ordered: date_ordered(Utc::now()),
+            version: 1,
+        }
+    }
+}
+
+/// The format of `date_ordered` in AccountUser.json, e.g. `2024-09-08T02:37:08.733Z`.
+/// Dates in `gsi1` are compared as strings, so every date written or queried uses it.
+pub fn date_ordered(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl DynamoEntity for UserTable {
+    type Key = UserTableId;
+
+    const NAME: &'static str = "Order";
+
+    const PARTITION_KEY: KeyAttribute = USER_ID;
+    const SORT_KEY: Option<KeyAttribute> = Some(ORDER_ID);
+    const VERSION: Option<&'static str> = Some("version");
+
+    fn table_name(tables: &TableNames) -> &str {
+        &tables.user_table
+    }
+
+    fn key_ids(key: &UserTableId) -> (&str, Option<&str>) {
+        (&key.user, Some(&key.order))
+    }
 
+    fn key(&self) -> UserTableId {
+        UserTableId { user: self.UserId.clone(), order: self.OrderId.clone() }
+    }
+
+    fn version(&self) -> Option<u64> {
+        Some(self.version)
+    }
 }
 
 
-/// Last key returned in Dynamo paginated query
-/// Convert to this then JSON Base64 to return
+/// Last key returned in Dynamo paginated query of gsi1.
+/// Sealed into the page token returned
 /// on HTTP header
+#[allow(non_snake_case)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserTableKey {
     pub UserId: String,
@@ -39,12 +161,6 @@ pub struct UserTableKey {
     pub date_ordered: String,
 }
 
-#[derive(Clone, Debug)]
-pub struct PaginatedOutput<T> {
-    pub key: Option<String>,
-    pub output: T,
-}
-
 
 /// Serde dynamo - https://docs.rs/serde_dynamo/latest/serde_dynamo/aws_sdk_dynamodb_1/index.html
 ///
@@ -59,67 +175,16 @@ pub struct PaginatedOutput<T> {
 ///
 pub async fn query_by_sorted_dates_serde_dynamo(
     client: &Client,
+    page_tokens: &PageTokens,
     table_name: &str,
     page_size: Option<i32>,
     paginator_token: Option<&String>
-) -> anyhow::Result<PaginatedOutput<Vec<UserTable>>, anyhow::Error> {
-
-    let mut query = client
-        .query()
-        .table_name(table_name)
-        .index_name("gsi1")
-        .scan_index_forward(false)
-        .key_condition_expression("#gsi1_pk = :gsi1_pk_val")
-        .expression_attribute_values(":gsi1_pk_val", AttributeValue::N("1".to_string()))
-        .expression_attribute_names("#gsi1_pk","gsi_pk")
-        ;
-
-    // If there is a page_size parameter, add to query
-    if let Some(limit) = page_size {
-        query = query.clone().limit(limit);
-    };
-
-    // If there is a paginator_token start point parameter, add to query
-    if let Some(paginator_token) = paginator_token {
-        let last_evaluated_key =
-            crate::dynamo_query_helpers::get_last_evaluated_key::<UserTableKey>(paginator_token.as_str())?;
-            // crate::dynamo_add::UserTableKey::<UserTableKey>(paginator_token.as_str())?;
-
-
-        query = query.clone().set_exclusive_start_key(Some(last_evaluated_key));
-    }
-
-    // Execute Query
-    let results = query
+) -> Result<PaginatedOutput<Vec<UserTable>>, QueryError> {
+    orders_by_date(client, page_tokens, table_name)
+        .set_limit(page_size)
+        .set_page_token(paginator_token.cloned())
         .send()
         .await
-        .map_err(|e| {
-            e.into_service_error()
-        })?;
-
-    // Handle Results
-    if let Some(items) = results.items {
-        let items = items.to_vec();
-        // Convert from HashMap of AttributeValues to struct
-        let users: Vec<UserTable> =
-            serde_dynamo::aws_sdk_dynamodb_1::from_items(items)?;
-
-        let last_evaluated_key_base64 =
-            results.last_evaluated_key
-                .map_or(None, | last_evaluated_key| {
-                    Some(crate::dynamo_query_helpers::generate_evaluated_key_base64::<UserTableKey>(last_evaluated_key))
-                }).transpose()?;
-
-        Ok(PaginatedOutput{
-            key: last_evaluated_key_base64,
-            output: users,
-        })
-    } else {
-        Ok(PaginatedOutput{
-            key: None,
-            output: vec![],
-        })
-    }
 }
 
 /// Serde dynamo - https://docs.rs/serde_dynamo/latest/serde_dynamo/aws_sdk_dynamodb_1/index.html
@@ -137,76 +202,86 @@ pub async fn query_by_sorted_dates_serde_dynamo(
 ///
 pub async fn query_by_date_range_serde_dynamo(
     client: &Client,
+    page_tokens: &PageTokens,
     table_name: &str,
     page_size: Option<i32>,
     paginator_token: Option<&String>,
     start_date: String,
     end_date: String,
-) -> Result<PaginatedOutput<Vec<UserTable>>, anyhow::Error> {
-
-    let mut query = client
-        .query()
-        .table_name(table_name)
-        .index_name("gsi1")
-        .scan_index_forward(false)
-        .key_condition_expression("#gsi1_pk = :gsi1_pk_val AND #SK between :from AND :to")
-        .expression_attribute_values(":gsi1_pk_val", AttributeValue::N("1".to_string()),)
-        .expression_attribute_values(":from", AttributeValue::S(start_date))
-        .expression_attribute_values(":to", AttributeValue::S(end_date))
-        // .expression_attribute_values(":from", AttributeValue::S("2025-07-10T19:00:22.819Z".to_string()),)
-        // .expression_attribute_values(":to", AttributeValue::S("2026-07-10T19:00:22.819Z".to_string()),)
-        // .expression_attribute_values(":from", AttributeValue::S("2024-10-03".to_string()),)
-        // .expression_attribute_values(":to", AttributeValue::S("2025-10-07".to_string()),)
-        .expression_attribute_names("#gsi1_pk","gsi_pk")
-        .expression_attribute_names("#SK","date_ordered")
-        ;
-
-    // If there is a page_size parameter, add to query
-    if let Some(limit) = page_size {
-        query = query.clone().limit(limit);
-    };
-
-    // If there is a paginator_token start point parameter, add to query
-    if let Some(paginator_token) = paginator_token {
-        let last_evaluated_key =
-            crate::dynamo_query_helpers::get_last_evaluated_key::<UserTableKey>(paginator_token.as_str())
-                .context("Token Error")?;
-
-        query = query.clone().set_exclusive_start_key(Some(last_evaluated_key));
-    }
-
-    // Execute Query
-    let results = query
+) -> Result<PaginatedOutput<Vec<UserTable>>, QueryError> {
+    orders_by_date(client, page_tokens, table_name)
+        .sort("date_ordered", SortCondition::Between(AttributeValue::S(start_date), AttributeValue::S(end_date)))
+        .set_limit(page_size)
+        .set_page_token(paginator_token.cloned())
         .send()
         .await
-        .map_err(|e| {
-            let se = e.into_service_error();
-            // handle_error(se)
-            se
-        })?;
-
-    // Handle Results
-    if let Some(items) = results.items {
-        let items = items.to_vec();
-        // Convert from HashMap of AttributeValues to struct
-        let users: Vec<UserTable> =
-            serde_dynamo::aws_sdk_dynamodb_1::from_items(items)?;
-        // .map_err(handle_error)?;
-
-        let last_evaluated_key_base64 =
-            results.last_evaluated_key
-                .map_or(None, | last_evaluated_key| {
-                    Some(crate::dynamo_query_helpers::generate_evaluated_key_base64::<UserTableKey>(last_evaluated_key))
-                }).transpose()?;
-
-        Ok(PaginatedOutput{
-            key: last_evaluated_key_base64,
-            output: users,
-        })
-    } else {
-        Ok(PaginatedOutput{
-            key: None,
-            output: vec![],
-        })
+}
+
+/// Every order through `gsi1`, newest first
+fn orders_by_date(client: &Client, page_tokens: &PageTokens, table_name: &str) -> PagedQuery<UserTable, UserTableKey> {
+    PagedQuery::new(client, page_tokens, table_name)
+        .index("gsi1")
+        .partition("gsi_pk", AttributeValue::N("1".to_string()))
+        .reverse()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TableNames;
+    use crate::dynamo_fake::{FakeDynamo, TableSpec};
+    use crate::repository::Repository;
+    use super::*;
+
+    async fn orders(dates: &[&str]) -> (Client, PageTokens) {
+        let fake = FakeDynamo::new().with_table(TableSpec::user_table());
+        let repository = Repository::<UserTable>::new(fake.client(), &TableNames::default());
+        for (n, date) in dates.iter().enumerate() {
+            let mut order = UserTable::new(&UserTableId { user: format!("user{n}"), order: "order1".to_string() }, "p#prod1".to_string(), 1.0);
+            order.date_ordered = date.to_string();
+            repository.create(&order).await.unwrap();
+        }
+        (fake.client(), PageTokens::new("test-secret-0123456789abcdefghijklmn", 60))
+    }
+
+    #[tokio::test]
+    async fn date_range_pages_newest_first() {
+        let dates = ["2024-01-01", "2024-01-05", "2024-01-03", "2024-01-02", "2024-01-04", "2024-01-06"];
+        let (client, page_tokens) = orders(&dates).await;
+
+        let mut seen = vec![];
+        let mut token = None;
+        loop {
+            let page = query_by_date_range_serde_dynamo(
+                &client,
+                &page_tokens,
+                "UserTable",
+                Some(2),
+                token.as_ref(),
+                "2024-01-02".to_string(),
+                "2024-01-05".to_string(),
+            )
+            .await
+            .unwrap();
+            seen.extend(page.output.into_iter().map(|order| order.date_ordered));
+            token = page.key;
+            if token.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, ["2024-01-05", "2024-01-04", "2024-01-03", "2024-01-02"]);
+    }
+
+    #[tokio::test]
+    async fn page_tokens_only_resume_their_own_query() {
+        let (client, page_tokens) = orders(&["2024-01-01", "2024-01-02", "2024-01-03"]).await;
+
+        let first = query_by_sorted_dates_serde_dynamo(&client, &page_tokens, "UserTable", Some(2), None).await.unwrap();
+        let token = first.key.expect("a second page");
+        let second = query_by_sorted_dates_serde_dynamo(&client, &page_tokens, "UserTable", Some(2), Some(&token)).await.unwrap();
+        assert_eq!(second.output.len(), 1);
+        assert!(second.key.is_none());
+
+        let range = query_by_date_range_serde_dynamo(&client, &page_tokens, "UserTable", Some(2), Some(&token), "2024".to_string(), "2025".to_string()).await;
+        assert!(matches!(range, Err(QueryError::PageToken(_))));
     }
 }
@@ -1,75 +1,31 @@
-use std::collections::HashMap;
-use aws_config::BehaviorVersion;
-use aws_sdk_dynamodb::Client;
-use axum::extract::Query;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse};
+use axum::Json;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use crate::api_error::ApiError;
+use crate::app_state::AppState;
+use crate::authorization::{AuthClaims, RequireRole};
 use crate::dynamo::StatResp;
-use crate::dynamo_query_helpers::query_items_key_attribute_value_serde;
+use crate::dynamo_query_helpers::PaginatedOutput;
+use crate::etag::{etag, IfMatch};
+use crate::repository::{Condition, DynamoEntity, Update};
 use crate::user_table::*;
+use crate::validation::{parse_rfc3339, parse_rfc3339_end, Validate, ValidatedJson, ValidatedQuery, ValidationErrors};
 
+/// The order, with its version as `ETag` to send back in `If-Match`
 pub async fn query_items_by_key_account_user_rest(
+    State(state): State<AppState>,
     axum::extract::Path((user, order)): axum::extract::Path<(String, String)>,
     // axum::extract::Path(order): axum::extract::Path<String>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
-    // Create the unique key of the record in DynamoDB in a way rusoto understands
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("UserId"), serde_dynamo::to_attribute_value("u#".to_string() + &*user).unwrap()),
-            (String::from("OrderId"),  serde_dynamo::to_attribute_value("o#".to_string() + &*order).unwrap()),
-        ]);
-
-    match query_items_key_attribute_value_serde::<UserTable>
-        // (&client, &table, "UserId".to_string(), user.as_str()).await {
-        (&client, &table, key).await {
-        Ok(item) => match item {
-            Some(item_out) => {axum::Json(item_out).into_response()}
-            None => StatResp::new("failure", "no item found", StatusCode::OK).into_response()
-        }
-        Err(e) => {
-            // e.chain().skip(0).for_each(|cause| println!("because: {}", cause.to_string()));
-            dbg!("{:?}", &e);
-            StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
-    }
-}
-
-pub async fn query_items_by_key_account_user_dynamo_helper_rest(
-    axum::extract::Path((user, order)): axum::extract::Path<(String, String)>,
-    // axum::extract::Path(order): axum::extract::Path<String>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
-    // Create the unique key of the record in DynamoDB in a way rusoto understands
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("UserId"), serde_dynamo::to_attribute_value("u#".to_string() + &*user).unwrap()),
-            (String::from("OrderId"),  serde_dynamo::to_attribute_value("o#".to_string() + &*order).unwrap()),
-        ]);
-
-    match query_items_key_attribute_value_serde::<UserTable>
-        // (&client, &table, "UserId".to_string(), user.as_str()).await {
-        (&client, &table, key).await {
-        Ok(item) => match item {
-            Some(item_out) => {axum::Json(item_out).into_response()}
-            None => StatResp::new("failure", "no item found", StatusCode::OK).into_response()
-        }
-        Err(e) => {
-            // e.chain().skip(0).for_each(|cause| println!("because: {}", cause.to_string()));
-            dbg!("{:?}", &e);
-            StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    state.repository::<UserTable>()
+        .get(&UserTableId { user, order })
+        .await?
+        .map(|user_table| (etag(user_table.version), Json(user_table)))
+        .ok_or_else(|| ApiError::not_found("No item found"))
 }
 
 
@@ -110,123 +66,105 @@ impl Default for Paginator {
 //
 
 pub async fn query_accountusers_handler(
+    State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
-
-    let paginator_page_size_option: Option<i32> = match params.get("page_size") {
-        Some(x) => {
-            if let Ok(n) = x.parse::<i32>() {
-                Some(n)
-            } else {
-                return StatResp::new("failure", "Invalid page size", StatusCode::BAD_REQUEST).into_response()
-            }
-        }
-        None => {None}
-    };
+) -> Result<impl IntoResponse, ApiError> {
+    let client = &state.client;
+    let table = &state.config.tables.user_table;
+
+
+    let paginator_page_size_option = page_size(&params)?;
 
     // Get Option of Token &String. If it's empty (present but blank), set to None
     // It was already None if not present based on the HashMap get.
     let paginator_token_option: Option<&String> = params.get("token")
-        .map_or(None, |token| {if token == "" {None} else {Some(token)}});
+        .filter(|token| !token.is_empty());
 
-    match query_by_sorted_dates_serde_dynamo(
-        &client,
-        &table,
+    let output = query_by_sorted_dates_serde_dynamo(
+        client,
+        &state.page_tokens,
+        table,
         paginator_page_size_option,
         paginator_token_option
-    ).await {
-        Ok(output) =>     {
-            let item = output.output;
-            let mut response = axum::Json(item).into_response();
-            if let Some(token) = output.key {
-                response.headers_mut().append("app-token", token.parse().unwrap());
-            }
-            response
+    ).await?;
+
+    Ok(paginated_response(output))
+}
+
+/// `start_date` and `end_date` of `/dynamo_query_account_users_by_date_range`,
+/// RFC 3339 date-times or dates, e.g. `2024-10-02T14:05:00+02:00` or `2024-10-02`.
+/// Both are inclusive, an `end_date` of `2024-10-31` includes the whole day.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DateRange {
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+}
+
+impl DateRange {
+    pub fn range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let start = parse_rfc3339(self.start_date.as_deref()?)?;
+        let end = parse_rfc3339_end(self.end_date.as_deref()?)?;
+        Some((start, end))
+    }
+
+    /// The range in the `date_ordered` format, e.g. `2024-10-02T00:00:00.000Z`, so they compare as strings
+    pub fn bounds(&self) -> Option<(String, String)> {
+        let (start, end) = self.range()?;
+        Some((date_ordered(start), date_ordered(end)))
+    }
+}
+
+impl Validate for DateRange {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.rfc3339("start_date", self.start_date.as_deref());
+        errors.rfc3339("end_date", self.end_date.as_deref());
+        if self.range().is_some_and(|(start, end)| end < start) {
+            errors.add("end_date", "out_of_range", "must not be before start_date");
         }
-        Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
     }
 }
 
+// curl -H "Authorization: Bearer FIREBASE_ID_TOKEN" \
+// "http://localhost:{{port}}/dynamo_query_account_users_by_date_range?start_date=2024-10-02&end_date=2024-10-31T12:00:00Z&page_size=10"
 pub async fn query_account_users_by_date_range_handler(
-    Query(params): Query<HashMap<String, String>>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
-    let paginator_page_size_option: Option<i32> = match params.get("page_size") {
-        Some(page_size_input) => {
-            if let Ok(page_size) = page_size_input.parse::<i32>() {
-                Some(page_size)
-            } else {
-                return StatResp::new("failure", "Invalid page size", StatusCode::BAD_REQUEST).into_response()
-            }
-        }
-        None => {None}
-    };
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    ValidatedQuery(range): ValidatedQuery<DateRange>,
+) -> Result<impl IntoResponse, ApiError> {
+    let client = &state.client;
+    let table = &state.config.tables.user_table;
+
+    let paginator_page_size_option = page_size(&params)?;
 
     // Get Option of Token &String. If it's empty (present but blank), set to None
     // It was already None if not present based on the HashMap get.
     let paginator_token_option: Option<&String> = params.get("token")
-        .map_or(None, |token| {if token == "" {None} else {Some(token)}});
+        .filter(|token| !token.is_empty());
 
-    let start_date: String = match params.get("start_date") {
-        Some(start_date) => { start_date.to_string() }
-        None => {return StatResp::new("failure", "missing start date parameter", StatusCode::BAD_REQUEST).into_response()}
-    };
-
-    let end_date: String = match params.get("end_date") {
-        Some(start_date) => { start_date.to_string() }
-        None => {return StatResp::new("failure", "missing end date parameter", StatusCode::BAD_REQUEST).into_response()}
-    };
+    // Both were checked by ValidatedQuery
+    let (start_date, end_date) = range.bounds().unwrap_or_default();
 
-
-    match query_by_date_range_serde_dynamo(
-        &client,
-        &table,
+    let output = query_by_date_range_serde_dynamo(
+        client,
+        &state.page_tokens,
+        table,
         paginator_page_size_option,
         paginator_token_option,
         start_date,
         end_date,
-    ).await {
-        Ok(output) =>     {
-            let item = output.output;
-            let mut response = axum::Json(item).into_response();
-            if let Some(token) = output.key {
-                response.headers_mut().append("app-token", token.parse().unwrap());
-            }
-            response
-        }
-        // Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        Err(e) => {
-                e.chain().skip(0).for_each(|cause| println!("because: {}", cause.to_string()));
-                let e1 = e.to_string() + ": " + e.root_cause().to_string().as_str();
-                StatResp::new("failure", e1.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-            }
-    }
+    ).await?;
+
+    Ok(paginated_response(output))
 }
 
 pub async fn create_user_table_serde_rest_handler(
-    axum::extract::Json(payload): axum::extract::Json<UpdateUserTable>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<UpdateUserTable>
+) -> Result<impl IntoResponse, ApiError> {
     let update_user_table = payload;
 
-    let mut user_table = UserTable{
-        UserId: update_user_table.UserId,
-        OrderId: update_user_table.OrderId,
-        product: update_user_table.product,
-        price: update_user_table.price,
-        gsi_pk: 1,
-        date_ordered: Utc::now().to_rfc3339().to_string(),
-    };
+    let id = UserTableId { user: update_user_table.UserId, order: update_user_table.OrderId };
+    let user_table = UserTable::new(&id, update_user_table.product, update_user_table.price);
 
     // let ut = UserTable{
     //     UserId: "u#user7".to_string(),
@@ -236,91 +174,451 @@ pub async fn create_user_table_serde_rest_handler(
     //     gsi_pk: 1,
     //     date_ordered: "2024-09-08T02:37:08.733Z".to_string(),
     // };
-    match crate::dynamo_query_helpers::create_entity_serde(&client, user_table, &table).await {
-        Ok(item) =>
-            { StatResp::new("success", "created item", StatusCode::OK).into_response() }
-        Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-    }
+    // 409 if the order exists
+    state.repository::<UserTable>().create(&user_table).await?;
+    Ok((etag(1), StatResp::new("success", "created item", StatusCode::OK)))
 }
 
 pub async fn update_user_table_serde_rest_handler(
-    axum::extract::Json(payload): axum::extract::Json<UpdateUserTable>
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
-
+    State(state): State<AppState>,
+    IfMatch(expected): IfMatch,
+    ValidatedJson(payload): ValidatedJson<UpdateUserTable>
+) -> Result<impl IntoResponse, ApiError> {
     let update_user_table = payload;
 
-    // Create the unique key of the record in DynamoDB in a way rusoto understands
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("UserId"), serde_dynamo::to_attribute_value(&*update_user_table.UserId).unwrap()),
-            (String::from("OrderId"),  serde_dynamo::to_attribute_value(&*update_user_table.OrderId).unwrap()),
-        ]);
-
-    // Query to find the matching old UserTable
-    let mut user_table = match query_items_key_attribute_value_serde::<UserTable>
-        (&client, &table, key).await {
-        Ok(item) => match item {
-            Some(item_out) => item_out,
-            None => return StatResp::new("failure", "no item found", StatusCode::OK).into_response()
-        }
-        Err(e) => {
-            // e.chain().skip(0).for_each(|cause| println!("because: {}", cause.to_string()));
-            dbg!("{:?}", &e);
-            return StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        }
+    let key = UserTableId { user: update_user_table.UserId, order: update_user_table.OrderId };
+    let patch = UserTablePatch {
+        product: Some(update_user_table.product),
+        price: Some(update_user_table.price),
+        ..UserTablePatch::default()
     };
 
-    // Add modified values to user_table
-    user_table.product = update_user_table.product;
-    user_table.price = update_user_table.price;
-
-    // Using create_entity_serde because that uses PutItem, which is what we're doing here,
-    //  by completely replacing old item.
-    return match crate::dynamo_query_helpers::create_entity_serde(&client, user_table, &table).await {
-        Ok(_) => {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "success".to_string(),
-                    message: "updated item".to_string(),
-                    status_code: StatusCode::OK,
-                }                                           )
-        }
-        Err(e) =>  {
-            axum::response::IntoResponse::into_response(
-                StatResp {
-                    result: "failure".to_string(),
-                    message: e.to_string(),
-                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
-                }                                           )
+    // UpdateItem on the existing item, 404 if there is none, 412 if If-Match is outdated
+    let user_table = state.repository::<UserTable>().update(&key, patch.update()?, expected).await?;
+    Ok((etag(user_table.version), StatResp::new("success", "updated item", StatusCode::OK)))
+}
+
+// curl -X PATCH -H "Authorization: Bearer FIREBASE_ID_TOKEN" -H "Content-Type: application/json" \
+// -H 'If-Match: "3"' -d '{"price_change": -0.5}' "http://localhost:{{port}}/user_table/user7/order1"
+/// Writes only the fields given in the body and returns the updated order.
+/// With `If-Match`, only applies to that version.
+pub async fn patch_user_table_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(key): axum::extract::Path<UserTableId>,
+    IfMatch(expected): IfMatch,
+    ValidatedJson(patch): ValidatedJson<UserTablePatch>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_table = state.repository::<UserTable>().update(&key, patch_update(&patch)?, expected).await?;
+    Ok((etag(user_table.version), Json(user_table)))
+}
+
+/// The update of a `PATCH` body, 400 if it is empty or sets the price twice
+fn patch_update(patch: &UserTablePatch) -> Result<Update, ApiError> {
+    if patch.price.is_some() && patch.price_change.is_some() {
+        return Err(ApiError::bad_request("conflicting_fields", "Give either price or price_change, not both"));
+    }
+
+    let update = patch.update()?;
+    if update.is_empty() {
+        return Err(ApiError::bad_request("empty_patch", "Nothing to update"));
+    }
+    Ok(update)
+}
+
+/// One write of `/user_table/transaction`. `version` works like `If-Match`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderOperation {
+    Create { user: String, order: String, product: String, price: f64 },
+    Update { user: String, order: String, patch: UserTablePatch, version: Option<u64> },
+    Delete { user: String, order: String, version: Option<u64> },
+    /// Only commits if the order exists, at `version` if given
+    Check { user: String, order: String, version: Option<u64> },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OrderTransaction {
+    pub operations: Vec<OrderOperation>,
+}
+
+impl Validate for OrderOperation {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        let (user, order) = match self {
+            OrderOperation::Create { user, order, product, price } => {
+                errors.not_blank("product", product);
+                errors.non_negative("price", *price);
+                (user, order)
+            }
+            OrderOperation::Update { user, order, patch, .. } => {
+                errors.nested("patch", patch);
+                (user, order)
+            }
+            OrderOperation::Delete { user, order, .. } | OrderOperation::Check { user, order, .. } => (user, order),
+        };
+        errors.key_id("user", user, "u#");
+        errors.key_id("order", order, "o#");
+    }
+}
+
+impl Validate for OrderTransaction {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.nested("operations", &self.operations);
+    }
+}
+
+// curl -X POST -H "Authorization: Bearer FIREBASE_ID_TOKEN" -H "Content-Type: application/json" \
+// -H "Idempotency-Key: 3f2c9a56-8a1e-4c47-9b0e-1f6f0e2d7a10" \
+// -d '{"operations":[{"create":{"user":"user7","order":"order9","product":"p#prod1","price":1.5}},{"update":{"user":"user7","order":"order1","patch":{"price_change":-1.5},"version":3}}]}' \
+// "http://localhost:{{port}}/user_table/transaction"
+/// Writes all operations or none. A cancelled transaction is a 409 whose
+/// `reasons` say which operation failed.
+pub async fn order_transaction_handler(
+    State(state): State<AppState>,
+    claims: AuthClaims,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<OrderTransaction>,
+) -> Result<StatResp, ApiError> {
+    if payload.operations.is_empty() || payload.operations.len() > 100 {
+        return Err(ApiError::bad_request("invalid_transaction", "A transaction takes 1 to 100 operations"));
+    }
+    // Same rule as /delete_user_table_entity
+    let deletes = payload.operations.iter().any(|operation| matches!(operation, OrderOperation::Delete { .. }));
+    if deletes {
+        RequireRole("admin").check(&claims)?;
+    }
+
+    let mut transaction = state.transaction();
+    if let Some(key) = headers.get("idempotency-key") {
+        let key = key
+            .to_str()
+            .ok()
+            .filter(|key| (1..=36).contains(&key.len()))
+            .ok_or_else(|| ApiError::bad_request("invalid_idempotency_key", "Idempotency-Key must be 1 to 36 characters"))?;
+        transaction = transaction.idempotency_token(key);
+    }
+
+    let count = payload.operations.len();
+    for operation in payload.operations {
+        transaction = match operation {
+            OrderOperation::Create { user, order, product, price } => {
+                transaction.create(&UserTable::new(&UserTableId { user, order }, product, price))?
+            }
+            OrderOperation::Update { user, order, patch, version } => {
+                transaction.update::<UserTable>(&UserTableId { user, order }, patch_update(&patch)?, version)?
+            }
+            OrderOperation::Delete { user, order, version } => {
+                transaction.delete::<UserTable>(&UserTableId { user, order }, version)?
+            }
+            OrderOperation::Check { user, order, version } => {
+                let mut condition = Condition::exists::<UserTable>();
+                if let Some(version_condition) = version.and_then(Condition::version::<UserTable>) {
+                    condition = condition.and(version_condition);
+                }
+                transaction.condition_check::<UserTable>(&UserTableId { user, order }, condition)?
+            }
+        };
+    }
+
+    transaction.execute(&state.client).await?;
+    Ok(StatResp::new("success", format!("committed {count} operations").as_str(), StatusCode::OK))
+}
+
+/// Most keys or writes one batch request takes, in as many DynamoDB calls as needed
+const BATCH_LIMIT: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchGetUserTable {
+    pub keys: Vec<UserTableId>,
+}
+
+impl Validate for BatchGetUserTable {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.nested("keys", &self.keys);
+    }
+}
+
+// curl -X POST -H "Content-Type: application/json" \
+// -d '{"keys":[{"user":"user7","order":"order1"},{"user":"user8","order":"order2"}]}' \
+// "http://localhost:{{port}}/user_table/batch_get"
+/// The orders with the given keys, in no particular order. Missing orders are left out.
+pub async fn batch_get_user_table_handler(
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<BatchGetUserTable>,
+) -> Result<Json<Vec<UserTable>>, ApiError> {
+    if payload.keys.len() > BATCH_LIMIT {
+        return Err(ApiError::bad_request("invalid_batch", format!("A batch takes at most {BATCH_LIMIT} keys")));
+    }
+
+    let user_tables = state.repository::<UserTable>().batch_get(&payload.keys).await?;
+    Ok(Json(user_tables))
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BatchWriteUserTable {
+    /// Created, existing orders are refused
+    pub put: Vec<UpdateUserTable>,
+    pub delete: Vec<UserTableId>,
+}
+
+impl Validate for BatchWriteUserTable {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.nested("put", &self.put);
+        errors.nested("delete", &self.delete);
+
+        // DynamoDB rejects a batch that writes a key twice, and a put and a delete
+        // of the same order would have no defined order
+        let puts = self.put.iter().map(|put| UserTableId { user: put.UserId.clone(), order: put.OrderId.clone() });
+        let puts = puts.enumerate().map(|(n, key)| (format!("put[{n}]"), key));
+        let deletes = self.delete.iter().cloned().enumerate().map(|(n, key)| (format!("delete[{n}]"), key));
+        let mut seen = HashSet::new();
+        for (field, key) in puts.chain(deletes) {
+            if !seen.insert(UserTable::stored_key(&key)) {
+                errors.add(field, "duplicate_key", "names an order written earlier in the batch");
+            }
         }
-    };
+    }
+}
+
+// curl -X POST -H "Authorization: Bearer FIREBASE_ID_TOKEN" -H "Content-Type: application/json" \
+// -d '{"put":[{"UserId":"user7","OrderId":"order9","product":"p#prod1","price":1.5}],"delete":[{"user":"user7","order":"order2"}]}' \
+// "http://localhost:{{port}}/user_table/batch"
+/// Puts and deletes orders. A put replaces an existing order at its next version.
+/// Unlike `/user_table/transaction` the writes are not atomic: when some fail,
+/// the problem lists their keys in `failed` and the others stay done.
+pub async fn batch_write_user_table_handler(
+    State(state): State<AppState>,
+    claims: AuthClaims,
+    ValidatedJson(payload): ValidatedJson<BatchWriteUserTable>,
+) -> Result<StatResp, ApiError> {
+    let count = payload.put.len() + payload.delete.len();
+    if count > BATCH_LIMIT {
+        return Err(ApiError::bad_request("invalid_batch", format!("A batch takes at most {BATCH_LIMIT} writes")));
+    }
+    // Same rule as /delete_user_table_entity
+    if !payload.delete.is_empty() {
+        RequireRole("admin").check(&claims)?;
+    }
+
+    let puts: Vec<UserTable> = payload
+        .put
+        .into_iter()
+        .map(|put| UserTable::new(&UserTableId { user: put.UserId, order: put.OrderId }, put.product, put.price))
+        .collect();
+
+    let report = state.repository::<UserTable>().batch_write(&puts, &payload.delete).await?;
+    if let Some(error) = report.error {
+        return Err(ApiError::partial_batch(&report.failed, error));
+    }
+    Ok(StatResp::new("success", format!("wrote {count} items").as_str(), StatusCode::OK))
 }
 
 
 pub async fn delete_user_table_serde_rest_handler(
+    State(state): State<AppState>,
     // axum::extract::Path(user_id): axum::extract::Path<String>
     axum::extract::Path((user, order)): axum::extract::Path<(String, String)>,
+    IfMatch(expected): IfMatch,
+) -> Result<StatResp, ApiError> {
+    state.repository::<UserTable>()
+        .delete(&UserTableId { user, order }, expected)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No item found"))?;
+    Ok(StatResp::new("success", "deleted item", StatusCode::OK))
+}
+
+fn page_size(params: &HashMap<String, String>) -> Result<Option<i32>, ApiError> {
+    params.get("page_size")
+        .map(|page_size| page_size.parse::<i32>())
+        .transpose()
+        .map_err(|_| ApiError::bad_request("invalid_page_size", "Invalid page size"))
+}
+
+/// The items as JSON, with the token of the next page in the `app-token` header
+fn paginated_response(output: PaginatedOutput<Vec<UserTable>>) -> impl IntoResponse {
+    (AppendHeaders(output.key.map(|token| ("app-token", token))), Json(output.output))
+}
 
-) -> impl IntoResponse {
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&config);
-    let table = "UserTable".to_string();
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::dynamo_fake::FakeDynamo;
+    use crate::dynamo_replay::{DynamoReplay, Exchange, Recorder};
+    use crate::test_app::{TestApp, TestRequest};
+    use super::*;
+
+    fn order(user: &str, date: &str) -> Value {
+        json!({
+            "UserId": {"S": format!("u#{user}")},
+            "OrderId": {"S": "o#order1"},
+            "product": {"S": "p#prod1"},
+            "price": {"N": "1.5"},
+            "gsi_pk": {"N": "1"},
+            "date_ordered": {"S": date},
+            "version": {"N": "1"},
+        })
+    }
 
+    fn orders_by_date(exclusive_start_key: Option<Value>) -> Value {
+        let mut request = json!({
+            "TableName": "UserTable",
+            "IndexName": "gsi1",
+            "Limit": 2,
+            "ScanIndexForward": false,
+            "KeyConditionExpression": "#pk = :pk",
+            "ExpressionAttributeNames": {"#pk": "gsi_pk"},
+            "ExpressionAttributeValues": {":pk": {"N": "1"}},
+        });
+        if let Some(key) = exclusive_start_key {
+            request["ExclusiveStartKey"] = key;
+        }
+        request
+    }
+
+    fn user_ids(response: &Value) -> Vec<&str> {
+        response.as_array().unwrap().iter().map(|order| order["UserId"].as_str().unwrap()).collect()
+    }
 
-    let key =
-        HashMap::from([
-            // Map of [ key_field_name, key_field_value_as_attribute_value ]
-            (String::from("UserId"), serde_dynamo::to_attribute_value("u#".to_string() + &*user).unwrap()),
-            (String::from("OrderId"),  serde_dynamo::to_attribute_value("o#".to_string() + &*order).unwrap()),
+    #[tokio::test]
+    async fn order_pages_resume_from_the_last_evaluated_key() {
+        let last_key = json!({
+            "UserId": {"S": "u#user2"},
+            "OrderId": {"S": "o#order1"},
+            "gsi_pk": {"N": "1"},
+            "date_ordered": {"S": "2024-10-02T10:00:00+00:00"},
+        });
+        let replay = DynamoReplay::new(vec![
+            Exchange::new(
+                "Query",
+                orders_by_date(None),
+                json!({
+                    "Count": 2,
+                    "Items": [order("user1", "2024-10-03T10:00:00+00:00"), order("user2", "2024-10-02T10:00:00+00:00")],
+                    "LastEvaluatedKey": last_key,
+                }),
+            ),
+            Exchange::new(
+                "Query",
+                orders_by_date(Some(last_key)),
+                json!({"Count": 1, "Items": [order("user3", "2024-10-01T10:00:00+00:00")]}),
+            ),
         ]);
+        let app = TestApp::with_client(FakeDynamo::new(), replay.client());
+
+        let response = app.send(TestRequest::get("/dynamo_query_accountusers_handler?page_size=2")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(user_ids(&response.json()), ["u#user1", "u#user2"]);
+        let token = response.header("app-token").expect("a next page").to_string();
+
+        let response = app.send(TestRequest::get(&format!("/dynamo_query_accountusers_handler?page_size=2&token={token}"))).await;
+        assert_eq!(user_ids(&response.json()), ["u#user3"]);
+        assert_eq!(response.header("app-token"), None);
+
+        replay.assert_requests();
+    }
+
+    #[tokio::test]
+    async fn date_range_queries_replay_their_fixture() {
+        let replay = DynamoReplay::from_fixture("date_range_query");
+        let app = TestApp::with_client(FakeDynamo::new(), replay.client());
+        let uri = "/dynamo_query_account_users_by_date_range?page_size=2&start_date=2024-10-02&end_date=2024-10-31";
+
+        let response = app.send(TestRequest::get(uri)).await;
+        // user5 ordered in the evening of the end date
+        assert_eq!(user_ids(&response.json()), ["u#user5", "u#user4"]);
+        let token = response.header("app-token").expect("a next page").to_string();
+
+        let response = app.send(TestRequest::get(&format!("{uri}&token={token}"))).await;
+        assert_eq!(user_ids(&response.json()), ["u#user3"]);
+        assert_eq!(response.header("app-token"), None);
+
+        replay.assert_requests();
+    }
+
+    #[tokio::test]
+    async fn throttled_queries_are_too_many_requests() {
+        let replay = DynamoReplay::new(vec![Exchange::error(
+            "Query",
+            orders_by_date(None),
+            "ProvisionedThroughputExceededException",
+            "The level of configured provisioned throughput for the table was exceeded",
+        )]);
+        let app = TestApp::with_client(FakeDynamo::new(), replay.client());
+
+        let response = app.send(TestRequest::get("/dynamo_query_accountusers_handler?page_size=2")).await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.json()["code"], "throttled");
+
+        replay.assert_requests();
+    }
+
+    fn field_errors(response: &Value) -> Vec<(&str, &str)> {
+        let errors = response["errors"].as_array().unwrap();
+        errors.iter().map(|error| (error["field"].as_str().unwrap(), error["code"].as_str().unwrap())).collect()
+    }
+
+    #[tokio::test]
+    async fn invalid_orders_are_unprocessable_with_an_error_per_field() {
+        let app = TestApp::new();
+        let writer = app.firebase_token(json!({"permissions": ["orders:write"]}));
+
+        let order = json!({"UserId": "o#order1", "OrderId": "order1", "product": " ", "price": -1.5});
+        let response = app.send(TestRequest::post("/create_user_table_entity", order).bearer(&writer)).await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let problem = response.json();
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(field_errors(&problem), [("UserId", "invalid_id"), ("product", "required"), ("price", "out_of_range")]);
+
+        let order = json!({"UserId": "user7", "OrderId": "order1", "product": "p#prod1", "price": "cheap"});
+        let response = app.send(TestRequest::post("/create_user_table_entity", order).bearer(&writer)).await;
+        assert_eq!(field_errors(&response.json()), [("price", "invalid_value")]);
+
+        let batch = json!({"put": [{"UserId": "user7", "OrderId": "order1", "product": "p#prod1", "price": 1.0}, {"UserId": "user7", "OrderId": "order2", "product": "", "price": 1.0}]});
+        let response = app.send(TestRequest::post("/user_table/batch", batch).bearer(&writer)).await;
+        assert_eq!(field_errors(&response.json()), [("put[1].product", "required")]);
+        assert!(app.fake.items("UserTable").is_empty());
+
+        let admin = app.firebase_token(json!({"permissions": ["orders:write"], "roles": ["admin"]}));
+        let batch = json!({"put": [{"UserId": "user7", "OrderId": "order1", "product": "p#prod1", "price": 1.0}], "delete": [{"user": "u#user7", "order": "o#order1"}]});
+        let response = app.send(TestRequest::post("/user_table/batch", batch).bearer(&admin)).await;
+        assert_eq!(field_errors(&response.json()), [("delete[0]", "duplicate_key")]);
+    }
+
+    #[tokio::test]
+    async fn date_ranges_are_rfc3339() {
+        let app = TestApp::new();
+        let query = |range: &str| TestRequest::get(&format!("/dynamo_query_account_users_by_date_range?{range}"));
+
+        let response = app.send(query("start_date=yesterday")).await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(field_errors(&response.json()), [("start_date", "invalid_date"), ("end_date", "required")]);
+
+        let response = app.send(query("start_date=2024-10-31&end_date=2024-10-02")).await;
+        assert_eq!(field_errors(&response.json()), [("end_date", "out_of_range")]);
+
+        let response = app.send(query("start_date=2024-10-31&end_date=2024-10-31")).await;
+        assert_eq!(response.status, StatusCode::OK);
+
+        let range = DateRange { start_date: Some("2024-10-02T16:05:00+02:00".to_string()), end_date: Some("2024-10-31".to_string()) };
+        assert_eq!(range.bounds(), Some(("2024-10-02T14:05:00.000Z".to_string(), "2024-10-31T23:59:59.999Z".to_string())));
+    }
 
-    match crate::dynamo_query_helpers::delete_by_key_attribute_value_serde(&client, &table, key).await {
-        Ok(item) =>
-            { StatResp::new("success", "deleted item", StatusCode::OK).into_response() }
-        Err(e) => StatResp::new("failure", e.to_string().as_str(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    /// Records `fixtures/dynamo/date_range_query.json` from the account of the
+    /// environment, whose UserTable needs at least 3 orders in October 2024:
+    /// `cargo test record_date_range_fixture -- --ignored`
+    #[tokio::test]
+    #[ignore = "calls DynamoDB and rewrites fixtures/dynamo/date_range_query.json"]
+    async fn record_date_range_fixture() {
+        let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let recorder = Recorder::default();
+        let app = TestApp::with_client(FakeDynamo::new(), recorder.client(aws_sdk_dynamodb::Config::from(&aws_config)));
+        let uri = "/dynamo_query_account_users_by_date_range?page_size=2&start_date=2024-10-02&end_date=2024-10-31";
+
+        let response = app.send(TestRequest::get(uri)).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let token = response.header("app-token").expect("a next page").to_string();
+        app.send(TestRequest::get(&format!("{uri}&token={token}"))).await;
+
+        recorder.fixture().save("date_range_query");
     }
 }
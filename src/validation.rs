@@ -0,0 +1,327 @@
+//! Checks request payloads before they reach DynamoDB.
+//!
+//! Payloads implement `Validate` and are extracted with `ValidatedJson` or
+//! `ValidatedQuery`. Invalid payloads are a 422 `validation_failed` with an
+//! error per field:
+//! ```json
+//! {"type":"about:blank","title":"Unprocessable Entity","status":422,"detail":"price: must be a number of at least 0",
+//!  "code":"validation_failed","errors":[{"field":"price","code":"out_of_range","message":"must be a number of at least 0"}]}
+//! ```
+
+use std::fmt;
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::api_error::ApiError;
+
+/// A payload that can check its own fields
+pub trait Validate {
+    /// Adds an error to `errors` for each invalid field
+    fn validate(&self, errors: &mut ValidationErrors);
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        for (n, value) in self.iter().enumerate() {
+            errors.nested(&format!("[{n}]"), value);
+        }
+    }
+}
+
+/// Why a field is invalid, e.g. `{"field": "put[0].price", "code": "out_of_range", "message": "..."}`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The invalid fields of a payload, in the order they were checked
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ValidationErrors(Vec<FieldError>);
+
+impl ValidationErrors {
+    /// `Ok` when `value` has no invalid fields
+    pub fn check<T: Validate>(value: &T) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        value.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, code: &'static str, message: impl Into<String>) {
+        self.0.push(FieldError { field: field.into(), code, message: message.into() });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn errors(&self) -> &[FieldError] {
+        &self.0
+    }
+
+    /// Validates `value` as the field `path`, e.g. `put[0]` gives errors like `put[0].price`
+    pub fn nested<T: Validate>(&mut self, path: &str, value: &T) {
+        let mut errors = ValidationErrors::default();
+        value.validate(&mut errors);
+        for error in errors.0 {
+            let separator = if error.field.starts_with('[') { "" } else { "." };
+            self.add(format!("{path}{separator}{}", error.field), error.code, error.message);
+        }
+    }
+
+    /// `value` has something besides whitespace
+    pub fn not_blank(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.add(field, "required", "must not be empty");
+        }
+    }
+
+    /// `value` is an id with or without `prefix`, e.g. `user7` or `u#user7` but not `o#order1`
+    pub fn key_id(&mut self, field: &str, value: &str, prefix: &str) {
+        let id = value.strip_prefix(prefix).unwrap_or(value);
+        if id.trim().is_empty() || id.contains('#') {
+            self.add(field, "invalid_id", format!("must be an id, optionally prefixed with {prefix}"));
+        }
+    }
+
+    /// `value` is a finite number of at least 0
+    pub fn non_negative(&mut self, field: &str, value: f64) {
+        if !value.is_finite() || value < 0.0 {
+            self.add(field, "out_of_range", "must be a number of at least 0");
+        }
+    }
+
+    /// `value` is a whole number in `min..=max`
+    pub fn integer_in(&mut self, field: &str, value: &str, min: i64, max: i64) {
+        match value.trim().parse::<i64>() {
+            Ok(number) if (min..=max).contains(&number) => {}
+            _ => self.add(field, "out_of_range", format!("must be a whole number from {min} to {max}")),
+        }
+    }
+
+    /// The RFC 3339 date-time or date of a required field
+    pub fn rfc3339(&mut self, field: &str, value: Option<&str>) -> Option<DateTime<Utc>> {
+        let Some(value) = value else {
+            self.add(field, "required", "is required");
+            return None;
+        };
+        let date = parse_rfc3339(value);
+        if date.is_none() {
+            self.add(field, "invalid_date", "must be an RFC 3339 date-time like 2024-10-02T14:05:00Z, or a date like 2024-10-02");
+        }
+        date
+    }
+}
+
+/// An RFC 3339 `date-time` in UTC, or the start of a `full-date` in UTC
+pub fn parse_rfc3339(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some(date_time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)).map(|date| date.and_utc())
+}
+
+/// Like `parse_rfc3339`, but a `full-date` is the last millisecond of that day,
+/// so an end bound of `2024-10-31` includes all of October 31st
+pub fn parse_rfc3339_end(value: &str) -> Option<DateTime<Utc>> {
+    let date_time = parse_rfc3339(value)?;
+    if DateTime::parse_from_rfc3339(value).is_ok() {
+        Some(date_time)
+    } else {
+        Some(date_time + TimeDelta::days(1) - TimeDelta::milliseconds(1))
+    }
+}
+
+/// "price: must be a number of at least 0, product: must not be empty"
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|error| format!("{}: {}", error.field, error.message)).collect();
+        write!(f, "{}", errors.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// A JSON body that passed `Validate`
+#[derive(Debug)]
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !json_content_type(request.headers()) {
+            return Err(ApiError::UnsupportedMediaType("Expected request with `Content-Type: application/json`".to_string()));
+        }
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|rejection| ApiError::bad_request("invalid_body", rejection.body_text()))?;
+
+        let value = from_json(&body)?;
+        ValidationErrors::check(&value)?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+/// Query parameters that passed `Validate`
+#[derive(Debug)]
+pub struct ValidatedQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        let value: T = serde_path_to_error::deserialize(deserializer)
+            .map_err(|e| deserialize_error("query", e.path(), e.inner().to_string()))?;
+        ValidationErrors::check(&value)?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
+/// `application/json`, or a JSON type like `application/problem+json`
+fn json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+/// A 400 `invalid_json` for malformed JSON, and a 422 naming the field for
+/// JSON that doesn't fit `T`, e.g. a string `price`
+fn from_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let inner = e.inner();
+        match inner.classify() {
+            serde_json::error::Category::Data => {
+                // serde_json adds the position to its messages, the path says more
+                let position = format!(" at line {} column {}", inner.line(), inner.column());
+                let message = inner.to_string();
+                let message = message.strip_suffix(&position).unwrap_or(&message).to_string();
+                deserialize_error("body", e.path(), message).into()
+            }
+            _ => invalid_json(inner),
+        }
+    })?;
+    // Trailing characters after the value
+    deserializer.end().map_err(|e| invalid_json(&e))?;
+    Ok(value)
+}
+
+fn invalid_json(error: &serde_json::Error) -> ApiError {
+    ApiError::bad_request("invalid_json", format!("Invalid JSON: {error}"))
+}
+
+/// The value at `path` could not be deserialized, `whole` for the body or query as a whole,
+/// e.g. for a missing field
+fn deserialize_error(whole: &str, path: &serde_path_to_error::Path, message: String) -> ValidationErrors {
+    let field = match path.iter().next() {
+        Some(_) => path.to_string(),
+        None => whole.to_string(),
+    };
+    let mut errors = ValidationErrors::default();
+    errors.add(field, "invalid_value", message);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use super::*;
+
+    struct Order {
+        user: String,
+        price: f64,
+    }
+
+    impl Validate for Order {
+        fn validate(&self, errors: &mut ValidationErrors) {
+            errors.key_id("user", &self.user, "u#");
+            errors.non_negative("price", self.price);
+        }
+    }
+
+    #[test]
+    fn nested_errors_name_their_path() {
+        let orders = vec![Order { user: "u#user7".to_string(), price: 1.5 }, Order { user: "o#order1".to_string(), price: -1.0 }];
+        let mut errors = ValidationErrors::default();
+        errors.nested("put", &orders);
+
+        let fields: Vec<(&str, &str)> = errors.errors().iter().map(|error| (error.field.as_str(), error.code)).collect();
+        assert_eq!(fields, [("put[1].user", "invalid_id"), ("put[1].price", "out_of_range")]);
+    }
+
+    #[test]
+    fn dates_are_rfc3339_date_times_or_dates() {
+        let date = |value| parse_rfc3339(value).map(|date| date.to_rfc3339());
+        assert_eq!(date("2024-10-02T16:05:00+02:00").as_deref(), Some("2024-10-02T14:05:00+00:00"));
+        assert_eq!(date("2024-10-02").as_deref(), Some("2024-10-02T00:00:00+00:00"));
+        assert_eq!(date("2024-10-02 14:05"), None);
+        assert_eq!(date("yesterday"), None);
+
+        let end = |value| parse_rfc3339_end(value).map(|date| date.to_rfc3339());
+        assert_eq!(end("2024-10-31").as_deref(), Some("2024-10-31T23:59:59.999+00:00"));
+        assert_eq!(end("2024-10-31T12:00:00Z").as_deref(), Some("2024-10-31T12:00:00+00:00"));
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Batch {
+        #[allow(dead_code)]
+        put: Vec<Price>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Price {
+        #[allow(dead_code)]
+        price: f64,
+    }
+
+    fn json_error(body: &str) -> (StatusCode, Vec<(String, &'static str)>, String) {
+        let error = from_json::<Batch>(body.as_bytes()).unwrap_err();
+        let errors = match &error {
+            ApiError::Invalid(errors) => errors.errors().iter().map(|error| (error.field.clone(), error.code)).collect(),
+            _ => vec![],
+        };
+        (error.status_code(), errors, error.to_string())
+    }
+
+    #[test]
+    fn deserialize_errors_name_the_path() {
+        let (status, errors, detail) = json_error(r#"{"put": [{"price": 1}, {"price": "cheap"}]}"#);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(errors, [("put[1].price".to_string(), "invalid_value")]);
+        assert_eq!(detail, "put[1].price: invalid type: string \"cheap\", expected f64");
+
+        let (status, errors, _) = json_error(r#"{"put": [{}]}"#);
+        assert_eq!((status, errors), (StatusCode::UNPROCESSABLE_ENTITY, vec![("put[0]".to_string(), "invalid_value")]));
+
+        let (status, errors, _) = json_error(r#"{"put": []"#);
+        assert_eq!((status, errors), (StatusCode::BAD_REQUEST, vec![]));
+        let (status, _, _) = json_error(r#"{"put": []} []"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}